@@ -0,0 +1,1136 @@
+//! Uniswap v3-style pool, where liquidity positions are active only inside a tick range.
+//!
+//! Prices are stored as square roots in Q64.64 fixed point, `sqrt_price = sqrt(token1 / token0) * 2^64`.
+//! A tick `i` corresponds to the price `1.0001^i`. Initialized ticks are tracked in a bitmap of
+//! 128-bit words, so a swap can find the next tick to cross without walking every tick in between.
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::serde::Serialize;
+use near_sdk::{AccountId, Balance};
+
+use crate::error::{AmmError, AmmResult, OrPanic};
+use crate::fixed_point::U256;
+use crate::weighted_pool::{FEE_DIVISOR, MAX_SWAP_FEE};
+use crate::StorageKey;
+
+/// The tick range is half of Uniswap's, so every sqrt price fits into 96 bits.
+pub const MIN_TICK: i32 = -443_636;
+pub const MAX_TICK: i32 = 443_636;
+pub const MAX_TICK_SPACING: u32 = 16_384;
+
+/// `2^128 / sqrt(1.0001)^(2^i)` in Q128.128, used to compute a sqrt price from tick bits.
+const TICK_RATIOS: [u128; 19] = [
+    0xfff97272373d413259a46990580e213a,
+    0xfff2e50f5f656932ef12357cf3c7fdcc,
+    0xffe5caca7e10e4e61c3624eaa0941cd0,
+    0xffcb9843d60f6159c9db58835c926644,
+    0xff973b41fa98c081472e6896dfb254c0,
+    0xff2ea16466c96a3843ec78b326b52861,
+    0xfe5dee046a99a2a811c461f1969c3053,
+    0xfcbe86c7900a88aedcffc83b479aa3a4,
+    0xf987a7253ac413176f2b074cf7815e54,
+    0xf3392b0822b70005940c7a398e4b70f3,
+    0xe7159475a2c29b7443b29c7fa6e889d9,
+    0xd097f3bdfd2022b8845ad8f792aa5825,
+    0xa9f746462d870fdf8a65dc1f90e061e5,
+    0x70d869a156d2a1b890bb3df62baf32f7,
+    0x31be135f97d08fd981231505542fcfa6,
+    0x9aa508b5b7a84e1c677de54f3e99bc9,
+    0x5d6af8dedb81196699c329225ee604,
+    0x2216e584f5fa1ea926041bedfe98,
+    0x48a170391f7dc42444e8fa2,
+];
+const TICK_RATIO_BIT_0: u128 = 0xfffcb933bd6fad37aa2d162d1a594001;
+
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+pub struct TickInfo {
+    /// Total liquidity of the positions that use the tick as a bound.
+    pub liquidity_gross: u128,
+    /// Liquidity added to the active liquidity when the price crosses the tick going up.
+    pub liquidity_net: i128,
+    /// Fee growth per unit of liquidity on the other side of the tick, Q64.64.
+    pub fee_growth_outside: [u128; 2],
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Position {
+    pub owner_id: AccountId,
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+    pub liquidity: u128,
+    pub fee_growth_inside_last: [u128; 2],
+    pub tokens_owed: [Balance; 2],
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PositionView {
+    pub owner_id: AccountId,
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+    pub liquidity: U128,
+    /// Fees earned by the position and not yet collected.
+    pub fees: Vec<U128>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ConcentratedPoolView {
+    pub token_ids: Vec<AccountId>,
+    pub fee: u32,
+    pub tick_spacing: u32,
+    pub sqrt_price: U128,
+    pub tick: i32,
+    pub liquidity: U128,
+    pub balances: Vec<U128>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct ConcentratedPool {
+    pub token_ids: Vec<AccountId>,
+    /// Swap fee in basis points.
+    pub fee: u32,
+    pub tick_spacing: u32,
+    pub sqrt_price: u128,
+    pub tick: i32,
+    /// Liquidity of the positions whose range contains the current tick.
+    pub liquidity: u128,
+    pub fee_growth_global: [u128; 2],
+    pub balances: [Balance; 2],
+    pub ticks: LookupMap<i32, TickInfo>,
+    pub tick_bitmap: LookupMap<i32, u128>,
+    pub positions: LookupMap<u64, Position>,
+    pub next_position_id: u64,
+}
+
+/// Result of a swap computed against the current state.
+struct SwapState {
+    /// What's left of the input (or the output for an exact-output swap).
+    amount_remaining: Balance,
+    amount_in: Balance,
+    amount_out: Balance,
+    sqrt_price: u128,
+    tick: i32,
+    liquidity: u128,
+    fee_growth_global: u128,
+    /// Crossed ticks with the global fee growth of the input token at the moment of crossing.
+    crossed_ticks: Vec<(i32, u128)>,
+}
+
+impl ConcentratedPool {
+    pub fn new(
+        pool_id: u64,
+        token_ids: Vec<AccountId>,
+        fee: u32,
+        tick_spacing: u32,
+        initial_tick: i32,
+    ) -> Self {
+        assert_eq!(
+            token_ids.len(),
+            2,
+            "A concentrated liquidity pool must have 2 tokens"
+        );
+        assert_ne!(token_ids[0], token_ids[1], "The tokens must be different");
+        assert!(fee <= MAX_SWAP_FEE, "The swap fee is too high");
+        assert!(
+            tick_spacing > 0 && tick_spacing <= MAX_TICK_SPACING,
+            "Invalid tick spacing"
+        );
+        assert!(
+            (MIN_TICK..MAX_TICK).contains(&initial_tick),
+            "The initial tick is out of range"
+        );
+        Self {
+            token_ids,
+            fee,
+            tick_spacing,
+            sqrt_price: sqrt_price_at_tick(initial_tick),
+            tick: initial_tick,
+            liquidity: 0,
+            fee_growth_global: [0; 2],
+            balances: [0; 2],
+            ticks: LookupMap::new(StorageKey::ConcentratedTicks { pool_id }),
+            tick_bitmap: LookupMap::new(StorageKey::ConcentratedTickBitmap { pool_id }),
+            positions: LookupMap::new(StorageKey::ConcentratedPositions { pool_id }),
+            next_position_id: 0,
+        }
+    }
+
+    pub fn view(&self) -> ConcentratedPoolView {
+        ConcentratedPoolView {
+            token_ids: self.token_ids.clone(),
+            fee: self.fee,
+            tick_spacing: self.tick_spacing,
+            sqrt_price: U128(self.sqrt_price),
+            tick: self.tick,
+            liquidity: U128(self.liquidity),
+            balances: self.balances.iter().map(|b| U128(*b)).collect(),
+        }
+    }
+
+    pub fn token_index(&self, token_id: &AccountId) -> usize {
+        self.index(token_id).or_panic()
+    }
+
+    fn index(&self, token_id: &AccountId) -> AmmResult<usize> {
+        self.token_ids
+            .iter()
+            .position(|t| t == token_id)
+            .ok_or_else(|| AmmError::UnknownToken(token_id.clone()))
+    }
+
+    pub fn get_position(&self, position_id: u64) -> Position {
+        self.positions
+            .get(&position_id)
+            .unwrap_or_else(|| panic!("The position {} doesn't exist", position_id))
+    }
+
+    pub fn position_view(&self, position_id: u64) -> PositionView {
+        let position = self.get_position(position_id);
+        let inside = self.fee_growth_inside(position.tick_lower, position.tick_upper);
+        let fees = (0..2)
+            .map(|i| {
+                let earned = fees_earned(
+                    position.liquidity,
+                    inside[i],
+                    position.fee_growth_inside_last[i],
+                );
+                U128(position.tokens_owed[i] + earned)
+            })
+            .collect();
+        PositionView {
+            owner_id: position.owner_id,
+            tick_lower: position.tick_lower,
+            tick_upper: position.tick_upper,
+            liquidity: U128(position.liquidity),
+            fees,
+        }
+    }
+
+    /// Opens a position with the largest liquidity that the desired amounts allow.
+    /// Returns the position id and the amounts of both tokens it took.
+    pub fn open_position(
+        &mut self,
+        owner_id: &AccountId,
+        tick_lower: i32,
+        tick_upper: i32,
+        amounts_desired: [Balance; 2],
+    ) -> (u64, [Balance; 2]) {
+        self.assert_ticks(tick_lower, tick_upper);
+        let liquidity = liquidity_for_amounts(
+            self.sqrt_price,
+            sqrt_price_at_tick(tick_lower),
+            sqrt_price_at_tick(tick_upper),
+            amounts_desired,
+        );
+        assert!(
+            liquidity > 0,
+            "The amounts are too small to provide liquidity"
+        );
+
+        let position_id = self.next_position_id;
+        self.next_position_id += 1;
+        let position = Position {
+            owner_id: owner_id.clone(),
+            tick_lower,
+            tick_upper,
+            liquidity: 0,
+            fee_growth_inside_last: self.fee_growth_inside(tick_lower, tick_upper),
+            tokens_owed: [0; 2],
+        };
+        self.positions.insert(&position_id, &position);
+        let amounts = self.modify_position(position_id, liquidity as i128);
+        (position_id, amounts)
+    }
+
+    /// Removes `liquidity` from the position and returns the withdrawn amounts together with
+    /// the fees it earned. Zero liquidity only collects the fees. Fully closed positions are
+    /// deleted.
+    pub fn decrease_position(
+        &mut self,
+        account_id: &AccountId,
+        position_id: u64,
+        liquidity: u128,
+    ) -> [Balance; 2] {
+        let position = self.get_position(position_id);
+        assert_eq!(
+            &position.owner_id,
+            account_id,
+            "{}",
+            AmmError::Unauthorized("Only the owner of the position can change it".to_string())
+        );
+        assert!(
+            liquidity <= position.liquidity,
+            "The position doesn't have that much liquidity"
+        );
+        let amounts = self.modify_position(position_id, -(liquidity as i128));
+
+        let mut position = self.get_position(position_id);
+        let owed = position.tokens_owed;
+        position.tokens_owed = [0; 2];
+        if position.liquidity == 0 {
+            self.positions.remove(&position_id);
+        } else {
+            self.positions.insert(&position_id, &position);
+        }
+        self.balances[0] -= owed[0];
+        self.balances[1] -= owed[1];
+        // the principal is already subtracted from the balances by `modify_position`
+        [amounts[0] + owed[0], amounts[1] + owed[1]]
+    }
+
+    pub fn get_return(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+    ) -> AmmResult<Balance> {
+        let zero_for_one = self.swap_direction(token_in, token_out)?;
+        let state = self.compute_swap(zero_for_one, amount_in, true)?;
+        if state.amount_remaining > 0 {
+            return Err(AmmError::InsufficientLiquidity);
+        }
+        Ok(state.amount_out)
+    }
+
+    /// The input needed to get exactly `amount_out`.
+    pub fn get_amount_in(
+        &self,
+        token_in: &AccountId,
+        amount_out: Balance,
+        token_out: &AccountId,
+    ) -> AmmResult<Balance> {
+        let zero_for_one = self.swap_direction(token_in, token_out)?;
+        let state = self.compute_swap(zero_for_one, amount_out, false)?;
+        if state.amount_remaining > 0 {
+            return Err(AmmError::InsufficientLiquidity);
+        }
+        Ok(state.amount_in)
+    }
+
+    pub fn swap(
+        &mut self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> AmmResult<Balance> {
+        let zero_for_one = self.swap_direction(token_in, token_out)?;
+        let state = self.compute_swap(zero_for_one, amount_in, true)?;
+        if state.amount_remaining > 0 {
+            return Err(AmmError::InsufficientLiquidity);
+        }
+        if state.amount_out < min_amount_out {
+            return Err(AmmError::below_minimum(state.amount_out, min_amount_out));
+        }
+        Ok(self.apply_swap(zero_for_one, state))
+    }
+
+    /// Swaps for exactly `amount_out` and returns the input used, at most `max_amount_in`.
+    pub fn swap_exact_out(
+        &mut self,
+        token_in: &AccountId,
+        max_amount_in: Balance,
+        amount_out: Balance,
+        token_out: &AccountId,
+    ) -> AmmResult<Balance> {
+        let zero_for_one = self.swap_direction(token_in, token_out)?;
+        let state = self.compute_swap(zero_for_one, amount_out, false)?;
+        if state.amount_remaining > 0 {
+            return Err(AmmError::InsufficientLiquidity);
+        }
+        if state.amount_in > max_amount_in {
+            return Err(AmmError::above_maximum(state.amount_in, max_amount_in));
+        }
+        let amount_in = state.amount_in;
+        self.apply_swap(zero_for_one, state);
+        Ok(amount_in)
+    }
+
+    /// Moves the pool to the state computed by `compute_swap` and returns the output.
+    fn apply_swap(&mut self, zero_for_one: bool, state: SwapState) -> Balance {
+        let (idx_in, idx_out) = if zero_for_one { (0, 1) } else { (1, 0) };
+        for (tick, fee_growth_global_in) in state.crossed_ticks {
+            let mut info = self.ticks.get(&tick).unwrap();
+            info.fee_growth_outside[idx_in] =
+                fee_growth_global_in.wrapping_sub(info.fee_growth_outside[idx_in]);
+            info.fee_growth_outside[idx_out] =
+                self.fee_growth_global[idx_out].wrapping_sub(info.fee_growth_outside[idx_out]);
+            self.ticks.insert(&tick, &info);
+        }
+        self.sqrt_price = state.sqrt_price;
+        self.tick = state.tick;
+        self.liquidity = state.liquidity;
+        self.fee_growth_global[idx_in] = state.fee_growth_global;
+        self.balances[idx_in] += state.amount_in;
+        self.balances[idx_out] -= state.amount_out;
+        state.amount_out
+    }
+
+    fn swap_direction(&self, token_in: &AccountId, token_out: &AccountId) -> AmmResult<bool> {
+        let idx_in = self.index(token_in)?;
+        if idx_in == self.index(token_out)? {
+            return Err(AmmError::BadMsg(
+                "can't swap a token for itself".to_string(),
+            ));
+        }
+        Ok(idx_in == 0)
+    }
+
+    /// Walks the price from tick to tick until the whole `amount` is used: the input, or the
+    /// output if not `exact_input`. Only reads the state.
+    fn compute_swap(
+        &self,
+        zero_for_one: bool,
+        amount: Balance,
+        exact_input: bool,
+    ) -> AmmResult<SwapState> {
+        let idx_in = if zero_for_one { 0 } else { 1 };
+        let sqrt_price_limit = if zero_for_one {
+            sqrt_price_at_tick(MIN_TICK) + 1
+        } else {
+            sqrt_price_at_tick(MAX_TICK) - 1
+        };
+        let mut state = SwapState {
+            amount_remaining: amount,
+            amount_in: 0,
+            amount_out: 0,
+            sqrt_price: self.sqrt_price,
+            tick: self.tick,
+            liquidity: self.liquidity,
+            fee_growth_global: self.fee_growth_global[idx_in],
+            crossed_ticks: vec![],
+        };
+
+        while state.amount_remaining > 0 && state.sqrt_price != sqrt_price_limit {
+            let (tick_next, initialized) =
+                self.next_initialized_tick_within_one_word(state.tick, zero_for_one);
+            let tick_next = tick_next.clamp(MIN_TICK, MAX_TICK);
+            let sqrt_price_next = sqrt_price_at_tick(tick_next);
+            let sqrt_price_target = if zero_for_one {
+                sqrt_price_next.max(sqrt_price_limit)
+            } else {
+                sqrt_price_next.min(sqrt_price_limit)
+            };
+
+            let step = compute_swap_step(
+                state.sqrt_price,
+                sqrt_price_target,
+                state.liquidity,
+                state.amount_remaining,
+                exact_input,
+                self.fee,
+            )?;
+            state.sqrt_price = step.sqrt_price_next;
+            if exact_input {
+                state.amount_remaining -= step.amount_in + step.fee_amount;
+            } else {
+                state.amount_remaining -= step.amount_out;
+            }
+            state.amount_in += step.amount_in + step.fee_amount;
+            state.amount_out += step.amount_out;
+            if state.liquidity > 0 {
+                state.fee_growth_global = state
+                    .fee_growth_global
+                    .wrapping_add(fee_growth_delta(step.fee_amount, state.liquidity));
+            }
+
+            if state.sqrt_price == sqrt_price_next {
+                if initialized {
+                    state
+                        .crossed_ticks
+                        .push((tick_next, state.fee_growth_global));
+                    let mut liquidity_net = self.ticks.get(&tick_next).unwrap().liquidity_net;
+                    if zero_for_one {
+                        liquidity_net = -liquidity_net;
+                    }
+                    state.liquidity = add_delta(state.liquidity, liquidity_net);
+                }
+                state.tick = if zero_for_one {
+                    tick_next - 1
+                } else {
+                    tick_next
+                };
+            } else {
+                state.tick = tick_at_sqrt_price(state.sqrt_price);
+            }
+        }
+        Ok(state)
+    }
+
+    /// Adds or removes liquidity of a position, updating its ticks and fees.
+    /// Returns the token amounts added to (or removed from) the pool.
+    fn modify_position(&mut self, position_id: u64, liquidity_delta: i128) -> [Balance; 2] {
+        let mut position = self.get_position(position_id);
+        let (lower, upper) = (position.tick_lower, position.tick_upper);
+
+        let mut flipped = vec![];
+        if liquidity_delta != 0 {
+            if self.update_tick(lower, liquidity_delta, false) {
+                flipped.push(lower);
+            }
+            if self.update_tick(upper, liquidity_delta, true) {
+                flipped.push(upper);
+            }
+            for tick in &flipped {
+                self.flip_tick(*tick);
+            }
+        }
+
+        let inside = self.fee_growth_inside(lower, upper);
+        for (i, fee_growth_inside) in inside.iter().enumerate() {
+            position.tokens_owed[i] += fees_earned(
+                position.liquidity,
+                *fee_growth_inside,
+                position.fee_growth_inside_last[i],
+            );
+        }
+        position.fee_growth_inside_last = inside;
+        position.liquidity = add_delta(position.liquidity, liquidity_delta);
+        self.positions.insert(&position_id, &position);
+
+        if liquidity_delta < 0 {
+            // ticks nobody uses anymore are cleared
+            for tick in flipped {
+                self.ticks.remove(&tick);
+            }
+        }
+
+        let adding = liquidity_delta > 0;
+        let liquidity = liquidity_delta.unsigned_abs();
+        let sqrt_lower = sqrt_price_at_tick(lower);
+        let sqrt_upper = sqrt_price_at_tick(upper);
+        let amounts = if self.tick < lower {
+            [amount0_delta(sqrt_lower, sqrt_upper, liquidity, adding), 0]
+        } else if self.tick < upper {
+            self.liquidity = add_delta(self.liquidity, liquidity_delta);
+            [
+                amount0_delta(self.sqrt_price, sqrt_upper, liquidity, adding),
+                amount1_delta(sqrt_lower, self.sqrt_price, liquidity, adding),
+            ]
+        } else {
+            [0, amount1_delta(sqrt_lower, sqrt_upper, liquidity, adding)]
+        };
+        for (balance, amount) in self.balances.iter_mut().zip(amounts) {
+            if adding {
+                *balance += amount;
+            } else {
+                *balance -= amount;
+            }
+        }
+        amounts
+    }
+
+    /// Returns whether the tick got initialized or cleared.
+    fn update_tick(&mut self, tick: i32, liquidity_delta: i128, upper: bool) -> bool {
+        let mut info = self.ticks.get(&tick).unwrap_or_default();
+        let liquidity_gross_before = info.liquidity_gross;
+        info.liquidity_gross = add_delta(liquidity_gross_before, liquidity_delta);
+        if liquidity_gross_before == 0 && tick <= self.tick {
+            // by convention all the growth before the tick was initialized happened below it
+            info.fee_growth_outside = self.fee_growth_global;
+        }
+        info.liquidity_net = if upper {
+            info.liquidity_net - liquidity_delta
+        } else {
+            info.liquidity_net + liquidity_delta
+        };
+        self.ticks.insert(&tick, &info);
+        (info.liquidity_gross == 0) != (liquidity_gross_before == 0)
+    }
+
+    fn fee_growth_inside(&self, lower: i32, upper: i32) -> [u128; 2] {
+        let lower_info = self.ticks.get(&lower).unwrap_or_default();
+        let upper_info = self.ticks.get(&upper).unwrap_or_default();
+        let mut inside = [0; 2];
+        for (i, growth) in inside.iter_mut().enumerate() {
+            let global = self.fee_growth_global[i];
+            let below = if self.tick >= lower {
+                lower_info.fee_growth_outside[i]
+            } else {
+                global.wrapping_sub(lower_info.fee_growth_outside[i])
+            };
+            let above = if self.tick < upper {
+                upper_info.fee_growth_outside[i]
+            } else {
+                global.wrapping_sub(upper_info.fee_growth_outside[i])
+            };
+            *growth = global.wrapping_sub(below).wrapping_sub(above);
+        }
+        inside
+    }
+
+    fn assert_ticks(&self, tick_lower: i32, tick_upper: i32) {
+        assert!(
+            tick_lower < tick_upper,
+            "The lower tick must be below the upper"
+        );
+        assert!(
+            tick_lower >= MIN_TICK && tick_upper <= MAX_TICK,
+            "The ticks are out of range"
+        );
+        let spacing = self.tick_spacing as i32;
+        assert!(
+            tick_lower % spacing == 0 && tick_upper % spacing == 0,
+            "The ticks must be multiples of the tick spacing {}",
+            spacing
+        );
+    }
+
+    fn bitmap_position(compressed: i32) -> (i32, u32) {
+        (
+            compressed.div_euclid(128),
+            compressed.rem_euclid(128) as u32,
+        )
+    }
+
+    fn flip_tick(&mut self, tick: i32) {
+        let (word, bit) = Self::bitmap_position(tick / self.tick_spacing as i32);
+        let bits = self.tick_bitmap.get(&word).unwrap_or(0) ^ (1u128 << bit);
+        if bits == 0 {
+            self.tick_bitmap.remove(&word);
+        } else {
+            self.tick_bitmap.insert(&word, &bits);
+        }
+    }
+
+    /// The next initialized tick in the same bitmap word: at or below `tick` when `lte`,
+    /// above it otherwise. If there is none, returns the word boundary and `false`.
+    fn next_initialized_tick_within_one_word(&self, tick: i32, lte: bool) -> (i32, bool) {
+        let spacing = self.tick_spacing as i32;
+        let compressed = tick.div_euclid(spacing);
+        if lte {
+            let (word, bit) = Self::bitmap_position(compressed);
+            let mask = if bit == 127 {
+                u128::MAX
+            } else {
+                (1u128 << (bit + 1)) - 1
+            };
+            let masked = self.tick_bitmap.get(&word).unwrap_or(0) & mask;
+            if masked != 0 {
+                let msb = 127 - masked.leading_zeros() as i32;
+                ((compressed - (bit as i32 - msb)) * spacing, true)
+            } else {
+                ((compressed - bit as i32) * spacing, false)
+            }
+        } else {
+            let (word, bit) = Self::bitmap_position(compressed + 1);
+            let mask = !((1u128 << bit) - 1);
+            let masked = self.tick_bitmap.get(&word).unwrap_or(0) & mask;
+            if masked != 0 {
+                let lsb = masked.trailing_zeros() as i32;
+                ((compressed + 1 + (lsb - bit as i32)) * spacing, true)
+            } else {
+                ((compressed + 1 + (127 - bit as i32)) * spacing, false)
+            }
+        }
+    }
+}
+
+struct SwapStep {
+    sqrt_price_next: u128,
+    amount_in: Balance,
+    amount_out: Balance,
+    fee_amount: Balance,
+}
+
+/// Moves the price towards `sqrt_price_target` with as much of `amount_remaining` as needed.
+/// `amount_remaining` is the input (fee included), or the output if not `exact_input`.
+fn compute_swap_step(
+    sqrt_price: u128,
+    sqrt_price_target: u128,
+    liquidity: u128,
+    amount_remaining: Balance,
+    exact_input: bool,
+    fee: u32,
+) -> AmmResult<SwapStep> {
+    let zero_for_one = sqrt_price >= sqrt_price_target;
+    let sqrt_price_next = if exact_input {
+        let amount_remaining_less_fee = (U256::from(amount_remaining)
+            * U256::from(FEE_DIVISOR - fee)
+            / U256::from(FEE_DIVISOR))
+        .as_u128();
+        let amount_in_to_target = if zero_for_one {
+            amount0_delta(sqrt_price_target, sqrt_price, liquidity, true)
+        } else {
+            amount1_delta(sqrt_price, sqrt_price_target, liquidity, true)
+        };
+        if amount_remaining_less_fee >= amount_in_to_target {
+            sqrt_price_target
+        } else if zero_for_one {
+            next_sqrt_price_from_amount0_in(sqrt_price, liquidity, amount_remaining_less_fee)
+        } else {
+            next_sqrt_price_from_amount1_in(sqrt_price, liquidity, amount_remaining_less_fee)
+        }
+    } else {
+        let amount_out_to_target = if zero_for_one {
+            amount1_delta(sqrt_price_target, sqrt_price, liquidity, false)
+        } else {
+            amount0_delta(sqrt_price, sqrt_price_target, liquidity, false)
+        };
+        if amount_remaining >= amount_out_to_target {
+            sqrt_price_target
+        } else if zero_for_one {
+            next_sqrt_price_from_amount1_out(sqrt_price, liquidity, amount_remaining)?
+        } else {
+            next_sqrt_price_from_amount0_out(sqrt_price, liquidity, amount_remaining)?
+        }
+    };
+    let reached_target = sqrt_price_next == sqrt_price_target;
+
+    let (amount_in, mut amount_out) = if zero_for_one {
+        (
+            amount0_delta(sqrt_price_next, sqrt_price, liquidity, true),
+            amount1_delta(sqrt_price_next, sqrt_price, liquidity, false),
+        )
+    } else {
+        (
+            amount1_delta(sqrt_price, sqrt_price_next, liquidity, true),
+            amount0_delta(sqrt_price, sqrt_price_next, liquidity, false),
+        )
+    };
+    if !exact_input {
+        amount_out = amount_out.min(amount_remaining);
+    }
+
+    let fee_amount = if exact_input && !reached_target {
+        // the rest of the input stays in the pool as a fee
+        amount_remaining - amount_in
+    } else {
+        div_round_up(
+            U256::from(amount_in) * U256::from(fee),
+            U256::from(FEE_DIVISOR - fee),
+        )
+    };
+    Ok(SwapStep {
+        sqrt_price_next,
+        amount_in,
+        amount_out,
+        fee_amount,
+    })
+}
+
+/// `sqrt(1.0001^tick) * 2^64`, rounded up.
+pub fn sqrt_price_at_tick(tick: i32) -> u128 {
+    assert!(
+        (MIN_TICK..=MAX_TICK).contains(&tick),
+        "The tick is out of range"
+    );
+    let abs_tick = tick.unsigned_abs();
+    let mut ratio = if abs_tick & 1 != 0 {
+        U256::from(TICK_RATIO_BIT_0)
+    } else {
+        U256::one() << 128
+    };
+    for (i, tick_ratio) in TICK_RATIOS.iter().enumerate() {
+        if abs_tick & (1 << (i + 1)) != 0 {
+            ratio = (ratio * U256::from(*tick_ratio)) >> 128;
+        }
+    }
+    if tick > 0 {
+        ratio = U256::MAX / ratio;
+    }
+    // Q128.128 to Q64.64, rounding up
+    let rounding = if (ratio & U256::from(u64::MAX)).is_zero() {
+        0
+    } else {
+        1
+    };
+    (ratio >> 64).as_u128() + rounding
+}
+
+/// The greatest tick whose sqrt price is not above `sqrt_price`.
+pub fn tick_at_sqrt_price(sqrt_price: u128) -> i32 {
+    let (mut low, mut high) = (MIN_TICK, MAX_TICK);
+    while low < high {
+        let mid = low + (high - low + 1) / 2;
+        if sqrt_price_at_tick(mid) <= sqrt_price {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    low
+}
+
+/// Amount of token0 between two sqrt prices: `L * 2^64 / sqrt_a - L * 2^64 / sqrt_b`.
+pub fn amount0_delta(sqrt_a: u128, sqrt_b: u128, liquidity: u128, round_up: bool) -> Balance {
+    let (sqrt_a, sqrt_b) = (sqrt_a.min(sqrt_b), sqrt_a.max(sqrt_b));
+    let numerator = U256::from(liquidity) << 64;
+    if round_up {
+        div_round_up(numerator, U256::from(sqrt_a)) - (numerator / U256::from(sqrt_b)).as_u128()
+    } else {
+        (numerator / U256::from(sqrt_a))
+            .as_u128()
+            .saturating_sub(div_round_up(numerator, U256::from(sqrt_b)))
+    }
+}
+
+/// Amount of token1 between two sqrt prices: `L * (sqrt_b - sqrt_a) / 2^64`.
+pub fn amount1_delta(sqrt_a: u128, sqrt_b: u128, liquidity: u128, round_up: bool) -> Balance {
+    let (sqrt_a, sqrt_b) = (sqrt_a.min(sqrt_b), sqrt_a.max(sqrt_b));
+    let product = U256::from(liquidity) * U256::from(sqrt_b - sqrt_a);
+    if round_up {
+        div_round_up(product, U256::one() << 64)
+    } else {
+        (product >> 64).as_u128()
+    }
+}
+
+fn next_sqrt_price_from_amount0_in(sqrt_price: u128, liquidity: u128, amount: Balance) -> u128 {
+    if amount == 0 {
+        return sqrt_price;
+    }
+    // L * 2^64 / (L * 2^64 / sqrt_price + amount), rounded up
+    let numerator = U256::from(liquidity) << 64;
+    div_round_up(
+        numerator,
+        numerator / U256::from(sqrt_price) + U256::from(amount),
+    )
+}
+
+fn next_sqrt_price_from_amount1_in(sqrt_price: u128, liquidity: u128, amount: Balance) -> u128 {
+    // sqrt_price + amount * 2^64 / L, rounded down
+    let delta = (U256::from(amount) << 64) / U256::from(liquidity);
+    (U256::from(sqrt_price) + delta).as_u128()
+}
+
+fn next_sqrt_price_from_amount0_out(
+    sqrt_price: u128,
+    liquidity: u128,
+    amount: Balance,
+) -> AmmResult<u128> {
+    // L * 2^64 / (L * 2^64 / sqrt_price - amount), rounded up
+    let numerator = U256::from(liquidity) << 64;
+    let denominator = numerator / U256::from(sqrt_price);
+    if denominator <= U256::from(amount) {
+        return Err(AmmError::InsufficientLiquidity);
+    }
+    Ok(div_round_up(numerator, denominator - U256::from(amount)))
+}
+
+fn next_sqrt_price_from_amount1_out(
+    sqrt_price: u128,
+    liquidity: u128,
+    amount: Balance,
+) -> AmmResult<u128> {
+    // sqrt_price - amount * 2^64 / L, rounded down
+    let delta = div_round_up(U256::from(amount) << 64, U256::from(liquidity));
+    if sqrt_price <= delta {
+        return Err(AmmError::InsufficientLiquidity);
+    }
+    Ok(sqrt_price - delta)
+}
+
+/// The liquidity that `amounts` provide in the range, limited by the scarcer token.
+pub fn liquidity_for_amounts(
+    sqrt_price: u128,
+    sqrt_lower: u128,
+    sqrt_upper: u128,
+    amounts: [Balance; 2],
+) -> u128 {
+    let liquidity0 = |sqrt_a: u128, sqrt_b: u128| {
+        // amount0 * (sqrt_a * sqrt_b / 2^64) / (sqrt_b - sqrt_a)
+        let intermediate = (U256::from(sqrt_a) * U256::from(sqrt_b)) >> 64;
+        U256::from(amounts[0]) * intermediate / U256::from(sqrt_b - sqrt_a)
+    };
+    let liquidity1 =
+        |sqrt_a: u128, sqrt_b: u128| (U256::from(amounts[1]) << 64) / U256::from(sqrt_b - sqrt_a);
+    let liquidity = if sqrt_price <= sqrt_lower {
+        liquidity0(sqrt_lower, sqrt_upper)
+    } else if sqrt_price < sqrt_upper {
+        liquidity0(sqrt_price, sqrt_upper).min(liquidity1(sqrt_lower, sqrt_price))
+    } else {
+        liquidity1(sqrt_lower, sqrt_upper)
+    };
+    assert!(
+        liquidity <= U256::from(i128::MAX as u128),
+        "Liquidity overflow"
+    );
+    liquidity.as_u128()
+}
+
+fn fee_growth_delta(fee_amount: Balance, liquidity: u128) -> u128 {
+    ((U256::from(fee_amount) << 64) / U256::from(liquidity)).low_u128()
+}
+
+fn fees_earned(liquidity: u128, fee_growth_inside: u128, fee_growth_inside_last: u128) -> Balance {
+    let growth = fee_growth_inside.wrapping_sub(fee_growth_inside_last);
+    ((U256::from(liquidity) * U256::from(growth)) >> 64).as_u128()
+}
+
+fn add_delta(liquidity: u128, delta: i128) -> u128 {
+    if delta < 0 {
+        liquidity
+            .checked_sub(delta.unsigned_abs())
+            .expect("Liquidity underflow")
+    } else {
+        liquidity
+            .checked_add(delta as u128)
+            .expect("Liquidity overflow")
+    }
+}
+
+fn div_round_up(numerator: U256, denominator: U256) -> u128 {
+    let quotient = numerator / denominator;
+    if (numerator % denominator).is_zero() {
+        quotient.as_u128()
+    } else {
+        (quotient + 1).as_u128()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    const Q64: f64 = 18446744073709551616.0;
+
+    fn new_pool(tick_spacing: u32, initial_tick: i32) -> ConcentratedPool {
+        testing_env!(VMContextBuilder::new().build());
+        ConcentratedPool::new(
+            0,
+            vec![accounts(1), accounts(2)],
+            30,
+            tick_spacing,
+            initial_tick,
+        )
+    }
+
+    #[test]
+    fn test_sqrt_price_at_tick() {
+        assert_eq!(sqrt_price_at_tick(0), 1u128 << 64);
+        for tick in [-443_636, -100_000, -1, 1, 5_000, 200_000, 443_636] {
+            let expected = 1.0001f64.powf(tick as f64 / 2.0) * Q64;
+            let actual = sqrt_price_at_tick(tick) as f64;
+            assert!(
+                ((actual - expected) / expected).abs() < 1e-9,
+                "tick {}",
+                tick
+            );
+        }
+    }
+
+    #[test]
+    fn test_tick_at_sqrt_price() {
+        for tick in [MIN_TICK, -12_345, -1, 0, 1, 77_777, MAX_TICK - 1] {
+            let sqrt_price = sqrt_price_at_tick(tick);
+            assert_eq!(tick_at_sqrt_price(sqrt_price), tick);
+            assert_eq!(tick_at_sqrt_price(sqrt_price + 1), tick);
+            if tick > MIN_TICK {
+                assert_eq!(tick_at_sqrt_price(sqrt_price - 1), tick - 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_next_initialized_tick() {
+        let mut pool = new_pool(10, 0);
+        pool.flip_tick(-200);
+        pool.flip_tick(50);
+        pool.flip_tick(2_000);
+        // the search doesn't leave the word of the starting tick
+        assert_eq!(
+            pool.next_initialized_tick_within_one_word(0, true),
+            (0, false)
+        );
+        assert_eq!(
+            pool.next_initialized_tick_within_one_word(-1, true),
+            (-200, true)
+        );
+        assert_eq!(
+            pool.next_initialized_tick_within_one_word(-200, true),
+            (-200, true)
+        );
+        assert_eq!(
+            pool.next_initialized_tick_within_one_word(-201, true),
+            (-1280, false)
+        );
+        assert_eq!(
+            pool.next_initialized_tick_within_one_word(0, false),
+            (50, true)
+        );
+        assert_eq!(
+            pool.next_initialized_tick_within_one_word(50, false),
+            (1270, false)
+        );
+        assert_eq!(
+            pool.next_initialized_tick_within_one_word(1270, false),
+            (2_000, true)
+        );
+        pool.flip_tick(50);
+        assert_eq!(
+            pool.next_initialized_tick_within_one_word(0, false),
+            (1270, false)
+        );
+    }
+
+    #[test]
+    fn test_open_position_amounts() {
+        let mut pool = new_pool(10, 0);
+        // in range: takes both tokens, 1:1 at price 1
+        let (id, amounts) = pool.open_position(&accounts(3), -100, 100, [1_000_000, 1_000_000]);
+        assert_eq!(id, 0);
+        assert!(amounts[0] <= 1_000_000 && amounts[1] <= 1_000_000);
+        assert!(amounts[0] >= 999_990 || amounts[1] >= 999_990);
+        assert_eq!(pool.liquidity, pool.get_position(0).liquidity);
+        // above the price: only token0
+        let (_, amounts) = pool.open_position(&accounts(3), 100, 200, [1_000, 1_000]);
+        assert_eq!(amounts[1], 0);
+        // below the price: only token1
+        let (_, amounts) = pool.open_position(&accounts(3), -200, -100, [1_000, 1_000]);
+        assert_eq!(amounts[0], 0);
+        assert_eq!(pool.liquidity, pool.get_position(0).liquidity);
+    }
+
+    #[test]
+    fn test_swap_within_range() {
+        let mut pool = new_pool(10, 0);
+        pool.open_position(
+            &accounts(3),
+            -1_000,
+            1_000,
+            [10u128.pow(24), 10u128.pow(24)],
+        );
+        let quote = pool
+            .get_return(&accounts(1), 10u128.pow(21), &accounts(2))
+            .unwrap();
+        let out = pool
+            .swap(&accounts(1), 10u128.pow(21), &accounts(2), quote)
+            .unwrap();
+        assert_eq!(out, quote);
+        // ~0.3% fee plus a bit of price impact
+        assert!(out < 10u128.pow(21) * 997 / 1000);
+        assert!(out > 10u128.pow(21) * 99 / 100);
+        assert!(pool.tick < 0);
+    }
+
+    #[test]
+    fn test_swap_crosses_ticks_and_accrues_fees() {
+        let mut pool = new_pool(10, 0);
+        // a narrow position around the price and a wide one
+        let (narrow, _) =
+            pool.open_position(&accounts(3), -10, 10, [10u128.pow(20), 10u128.pow(20)]);
+        let (wide, _) = pool.open_position(
+            &accounts(4),
+            -1_000,
+            1_000,
+            [10u128.pow(20), 10u128.pow(20)],
+        );
+        let liquidity_before = pool.liquidity;
+
+        let amount_in = 15 * 10u128.pow(19);
+        let amount_out = pool.swap(&accounts(1), amount_in, &accounts(2), 0).unwrap();
+        assert!(pool.tick < -10, "the swap must cross the narrow range");
+        assert_eq!(pool.liquidity, pool.get_position(wide).liquidity);
+        assert!(pool.liquidity < liquidity_before);
+
+        // both positions earned token0 fees, none in token1
+        let narrow_fees = pool.position_view(narrow).fees;
+        let wide_fees = pool.position_view(wide).fees;
+        assert!(narrow_fees[0].0 > 0 && wide_fees[0].0 > 0);
+        assert_eq!(narrow_fees[1].0, 0);
+        // the whole fee is split between the positions, up to the fee growth precision
+        let total_fee = amount_in * 30 / 10_000;
+        let earned = narrow_fees[0].0 + wide_fees[0].0;
+        assert!(earned <= total_fee && earned + total_fee / 10u128.pow(12) >= total_fee);
+
+        // swapping the output back crosses into the narrow range again
+        pool.swap(&accounts(2), amount_out, &accounts(1), 0)
+            .unwrap();
+        assert!((-10..0).contains(&pool.tick));
+        assert_eq!(pool.liquidity, liquidity_before);
+    }
+
+    #[test]
+    fn test_exact_output_swap_crosses_ticks() {
+        let mut pool = new_pool(10, 0);
+        pool.open_position(&accounts(3), -10, 10, [10u128.pow(20), 10u128.pow(20)]);
+        pool.open_position(
+            &accounts(4),
+            -1_000,
+            1_000,
+            [10u128.pow(20), 10u128.pow(20)],
+        );
+        for (token_in, token_out) in [(accounts(1), accounts(2)), (accounts(2), accounts(1))] {
+            let amount_out = 15 * 10u128.pow(19);
+            let amount_in = pool
+                .get_amount_in(&token_in, amount_out, &token_out)
+                .unwrap();
+            // the same input swapped the other way gives at least the output, one unit less doesn't
+            assert!(pool.get_return(&token_in, amount_in, &token_out).unwrap() >= amount_out);
+            assert!(
+                pool.get_return(&token_in, amount_in - 1_000, &token_out)
+                    .unwrap()
+                    < amount_out
+            );
+
+            let balances = pool.balances;
+            let used = pool
+                .swap_exact_out(&token_in, amount_in, amount_out, &token_out)
+                .unwrap();
+            assert_eq!(used, amount_in);
+            let idx_in = pool.token_index(&token_in);
+            assert_eq!(pool.balances[idx_in], balances[idx_in] + amount_in);
+            assert_eq!(pool.balances[1 - idx_in], balances[1 - idx_in] - amount_out);
+        }
+    }
+
+    #[test]
+    fn test_exact_output_swap_slippage() {
+        let mut pool = new_pool(10, 0);
+        pool.open_position(&accounts(3), -100, 100, [10u128.pow(20), 10u128.pow(20)]);
+        let err = pool
+            .swap_exact_out(&accounts(1), 10u128.pow(18), 10u128.pow(18), &accounts(2))
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("E_SLIPPAGE: Slippage error: the input"));
+    }
+
+    #[test]
+    fn test_decrease_position_returns_liquidity_and_fees() {
+        let mut pool = new_pool(10, 0);
+        let (id, deposited) =
+            pool.open_position(&accounts(3), -100, 100, [10u128.pow(20), 10u128.pow(20)]);
+        pool.swap(&accounts(1), 10u128.pow(18), &accounts(2), 0)
+            .unwrap();
+        pool.swap(&accounts(2), 10u128.pow(18), &accounts(1), 0)
+            .unwrap();
+
+        let liquidity = pool.get_position(id).liquidity;
+        let withdrawn = pool.decrease_position(&accounts(3), id, liquidity);
+        // the round trip leaves fees in both tokens for the only LP
+        assert!(withdrawn[0] + withdrawn[1] > deposited[0] + deposited[1]);
+        assert!(pool.positions.get(&id).is_none());
+        assert_eq!(pool.liquidity, 0);
+        assert!(pool.ticks.get(&-100).is_none());
+        // only rounding dust of the fee growth stays in the pool
+        assert!(pool
+            .balances
+            .iter()
+            .all(|b| *b < 10u128.pow(20) / 10u128.pow(12)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner of the position can change it")]
+    fn test_decrease_foreign_position() {
+        let mut pool = new_pool(10, 0);
+        let (id, _) = pool.open_position(&accounts(3), -100, 100, [1_000_000, 1_000_000]);
+        pool.decrease_position(&accounts(4), id, 1);
+    }
+
+    #[test]
+    fn test_swap_without_liquidity() {
+        let mut pool = new_pool(1_000, 0);
+        pool.open_position(&accounts(3), -1_000, 1_000, [1_000, 1_000]);
+        assert_eq!(
+            pool.swap(&accounts(1), 10u128.pow(20), &accounts(2), 0),
+            Err(AmmError::InsufficientLiquidity)
+        );
+        assert_eq!(pool.balances, [1_000, 1_000]);
+    }
+}
@@ -0,0 +1,522 @@
+//! Dollar-cost averaging on the main pool.
+//!
+//! A `PlaceDcaOrder` transfer is a budget split into swaps of equal size, one every `interval`.
+//! Nothing runs on its own on NEAR, so anyone can call `execute_due_orders` to run the swaps
+//! that are due and earn the keeper reward from their output. A swap that would pay less than
+//! the order's `min_price`, e.g. because the pool price was pushed just before it, is skipped
+//! until the next interval.
+//!
+//! Orders are scheduled by the time of their next swap, so `execute_due_orders` only visits the
+//! due ones. An account can have [`MAX_DCA_ORDERS_PER_ACCOUNT`] orders, each swap selling at
+//! least 1/[`MIN_ORDER_DIVISOR`] of the pool's reserve of the token.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance};
+
+use crate::fixed_point::{self, U256};
+use crate::limit_orders::{keeper_reward, MIN_ORDER_DIVISOR};
+use crate::*;
+
+pub const MAX_DCA_ORDERS_PER_ACCOUNT: u32 = 10;
+const DEFAULT_LIMIT: u64 = 10;
+
+/// The `PlaceDcaOrder` msg.
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DcaParams {
+    pub token_out: AccountId,
+    pub swaps: u32,
+    /// Nanoseconds between two swaps.
+    pub interval: U64,
+    /// The least output per input of a swap, after the keeper reward, scaled by 10^18.
+    pub min_price: U128,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DcaOrder {
+    pub order_id: u64,
+    pub owner_id: AccountId,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    /// The budget that isn't swapped yet.
+    pub budget: U128,
+    pub swaps_left: u32,
+    /// Nanoseconds between two swaps.
+    pub interval: U64,
+    /// The least output per input of a swap, after the keeper reward, scaled by 10^18.
+    pub min_price: U128,
+    /// Block timestamp from which the next swap can run.
+    pub next_swap_at: U64,
+    /// Swaps done so far.
+    pub fills: u32,
+    pub amount_in: U128,
+    /// Output received so far, after the keeper rewards.
+    pub amount_out: U128,
+}
+
+impl DcaOrder {
+    /// The input of the next swap, an equal part of the budget left. The remainder of the split
+    /// goes into the later swaps, the last one swaps what is left.
+    fn next_amount(&self) -> Balance {
+        self.budget.0 / self.swaps_left as Balance
+    }
+
+    /// Whether `amount_out` for `amount_in` is at least the order's `min_price`.
+    fn accepts(&self, amount_in: Balance, amount_out: Balance) -> bool {
+        U256::from(amount_out) * U256::from(fixed_point::ONE)
+            >= U256::from(self.min_price.0) * U256::from(amount_in)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DcaOrderView {
+    #[serde(flatten)]
+    pub order: DcaOrder,
+    /// Output per input received so far, scaled by 10^18.
+    pub average_price: Option<U128>,
+}
+
+#[near_bindgen]
+impl AMM {
+    pub fn get_dca_orders(&self, account_id: AccountId) -> Vec<DcaOrderView> {
+        self.dca_orders
+            .values()
+            .filter(|order| order.owner_id == account_id)
+            .map(|order| {
+                let average_price = (order.amount_in.0 > 0).then(|| {
+                    U128(
+                        (U256::from(order.amount_out.0) * U256::from(fixed_point::ONE)
+                            / U256::from(order.amount_in.0))
+                        .as_u128(),
+                    )
+                });
+                DcaOrderView {
+                    order,
+                    average_price,
+                }
+            })
+            .collect()
+    }
+
+    /// Cancels a DCA order and refunds the rest of its budget.
+    pub fn cancel_dca_order(&mut self, order_id: u64) {
+        let order = self
+            .dca_orders
+            .get(&order_id)
+            .unwrap_or_else(|| panic!("No DCA order {}", order_id));
+        if order.owner_id != env::predecessor_account_id() {
+            panic!(
+                "{}",
+                AmmError::Unauthorized("Only the owner of the order can cancel it".to_string())
+            );
+        }
+        self.remove_dca_order(&order);
+        log!("DCA order {} is cancelled", order_id);
+        if order.budget.0 > 0 {
+            self.internal_refund(order.owner_id, order.token_in, order.budget.0);
+        }
+    }
+
+    /// Runs up to `limit` swaps of DCA orders that are due, by anyone. The keeper reward of each
+    /// swap is credited to the caller's deposits if it's registered. Returns the number of swaps.
+    pub fn execute_due_orders(&mut self, limit: Option<u64>) -> u64 {
+        self.assert_no_flash_loan();
+        let keeper_id = env::predecessor_account_id();
+        let now = env::block_timestamp();
+        let due: Vec<u64> = self
+            .dca_schedule
+            .iter()
+            .take_while(|((next_swap_at, _), _)| *next_swap_at <= now)
+            .take(limit.unwrap_or(DEFAULT_LIMIT) as usize)
+            .map(|((_, order_id), _)| order_id)
+            .collect();
+        let mut executed = 0;
+        for order_id in due {
+            let order = self.dca_orders.get(&order_id).unwrap();
+            if self.execute_dca_swap(&keeper_id, order, now) {
+                executed += 1;
+            }
+        }
+        executed
+    }
+}
+
+impl AMM {
+    pub(crate) fn place_dca_order(
+        &mut self,
+        owner_id: AccountId,
+        token_in: &AccountId,
+        budget: Balance,
+        params: DcaParams,
+    ) -> AmmResult<u64> {
+        let DcaParams {
+            token_out,
+            swaps,
+            interval,
+            min_price,
+        } = params;
+        let reserve = self.main_token(token_in)?.balance;
+        self.main_token(&token_out)?;
+        if token_in == &token_out {
+            return Err(AmmError::BadMsg(
+                "the order must sell one main pool token for the other".to_string(),
+            ));
+        }
+        let min_swap = math::mul_div_up(reserve, 1, MIN_ORDER_DIVISOR)?.max(1);
+        if swaps == 0 || budget / (swaps as Balance) < min_swap {
+            return Err(AmmError::BadMsg(format!(
+                "every swap must sell at least {} {}",
+                min_swap, token_in
+            )));
+        }
+        let count = self.dca_order_counts.get(&owner_id).unwrap_or(0);
+        if count >= MAX_DCA_ORDERS_PER_ACCOUNT {
+            return Err(AmmError::BadMsg(format!(
+                "an account can't have more than {} DCA orders",
+                MAX_DCA_ORDERS_PER_ACCOUNT
+            )));
+        }
+        let order_id = self.next_order_id;
+        self.next_order_id += 1;
+        let order = DcaOrder {
+            order_id,
+            owner_id,
+            token_in: token_in.clone(),
+            token_out,
+            budget: U128(budget),
+            swaps_left: swaps,
+            interval,
+            min_price,
+            next_swap_at: U64(env::block_timestamp()),
+            fills: 0,
+            amount_in: U128(0),
+            amount_out: U128(0),
+        };
+        self.dca_orders.insert(&order_id, &order);
+        self.dca_schedule
+            .insert(&(order.next_swap_at.0, order_id), &());
+        self.dca_order_counts.insert(&order.owner_id, &(count + 1));
+        log!("DCA order {} is placed", order_id);
+        Ok(order_id)
+    }
+
+    fn remove_dca_order(&mut self, order: &DcaOrder) {
+        self.dca_orders.remove(&order.order_id);
+        self.dca_schedule
+            .remove(&(order.next_swap_at.0, order.order_id));
+        let count = self.dca_order_counts.get(&order.owner_id).unwrap_or(1);
+        if count <= 1 {
+            self.dca_order_counts.remove(&order.owner_id);
+        } else {
+            self.dca_order_counts.insert(&order.owner_id, &(count - 1));
+        }
+    }
+
+    /// Moves the next swap of the order one interval after `now`.
+    fn reschedule(&mut self, order: &mut DcaOrder, now: u64) {
+        self.dca_schedule
+            .remove(&(order.next_swap_at.0, order.order_id));
+        order.next_swap_at = U64(now.saturating_add(order.interval.0));
+        self.dca_schedule
+            .insert(&(order.next_swap_at.0, order.order_id), &());
+        self.dca_orders.insert(&order.order_id, order);
+    }
+
+    /// Runs the next swap of the order. A swap the main pool can't do, or one below the order's
+    /// `min_price`, is retried after the interval.
+    fn execute_dca_swap(&mut self, keeper_id: &AccountId, mut order: DcaOrder, now: u64) -> bool {
+        // the quote and the swap see the same reserves
+        self.settle_virtual_orders();
+        let amount_in = order.next_amount();
+        let quote = self
+            .get_main_return(&order.token_in, amount_in, &order.token_out)
+            .and_then(|amount_out| Ok((amount_out, keeper_reward(amount_out)?)));
+        let (quoted_out, quoted_reward) = match quote {
+            Ok(quote) => quote,
+            Err(err) => {
+                log!("DCA order {} is not executed: {}", order.order_id, err);
+                self.reschedule(&mut order, now);
+                return false;
+            }
+        };
+        if !order.accepts(amount_in, quoted_out - quoted_reward) {
+            log!(
+                "DCA order {} is not executed: {}",
+                order.order_id,
+                AmmError::below_minimum(
+                    quoted_out - quoted_reward,
+                    math::mul_div_up(amount_in, order.min_price.0, fixed_point::ONE)
+                        .unwrap_or(Balance::MAX)
+                )
+            );
+            self.reschedule(&mut order, now);
+            return false;
+        }
+        let amount_out = match self.swap_main(&order.token_in, amount_in, &order.token_out) {
+            Ok(amount_out) => amount_out,
+            Err(err) => {
+                log!("DCA order {} is not executed: {}", order.order_id, err);
+                self.reschedule(&mut order, now);
+                return false;
+            }
+        };
+        let reward = self.keeper_reward_for(keeper_id, amount_out);
+        let amount_out = amount_out - reward;
+
+        order.budget = U128(order.budget.0 - amount_in);
+        order.swaps_left -= 1;
+        order.fills += 1;
+        order.amount_in = U128(order.amount_in.0 + amount_in);
+        order.amount_out = U128(order.amount_out.0.saturating_add(amount_out));
+        log!(
+            "DCA order {} swapped {} {} for {} {}",
+            order.order_id,
+            amount_in,
+            order.token_in,
+            amount_out,
+            order.token_out
+        );
+        if order.swaps_left == 0 {
+            self.remove_dca_order(&order);
+            log!("DCA order {} is completed", order.order_id);
+        } else {
+            self.reschedule(&mut order, now);
+        }
+
+        self.record_swap(
+            &order.owner_id,
+            None,
+            &order.token_in,
+            amount_in,
+            &order.token_out,
+            amount_out,
+        );
+        if reward > 0 {
+            self.internal_deposit(keeper_id, &order.token_out, reward);
+        }
+        self.internal_payout(
+            order.owner_id.clone(),
+            order.owner_id,
+            order.token_out.clone(),
+            amount_out,
+            None,
+        );
+        self.fill_limit_orders(keeper_id, &order.token_out);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{enable_swap_history, register, setup_main_pool, transfer};
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    const MINUTE: u64 = 60_000_000_000;
+
+    /// A `PlaceDcaOrder` msg for 3 swaps, one a minute, for at least 0.95 `accounts(2)` per
+    /// token.
+    fn place_msg() -> String {
+        format!(
+            r#"{{"PlaceDcaOrder": {{"token_out": "{}", "swaps": 3, "interval": "{}", "min_price": "{}"}}}}"#,
+            accounts(2),
+            MINUTE,
+            fixed_point::ONE * 95 / 100
+        )
+    }
+
+    /// A main pool of 1_000_000 of `accounts(2)` and 1_000_000 of `accounts(3)`, and a DCA order
+    /// of `accounts(4)` selling 10_001 of `accounts(3)` with `place_msg`. The keeper
+    /// `accounts(5)` is registered.
+    fn setup() -> (VMContextBuilder, AMM) {
+        let (mut context, mut contract) = setup_main_pool();
+        register(&mut context, &mut contract, accounts(5));
+        let unused = transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(4),
+            10_001,
+            place_msg(),
+        );
+        assert_eq!(unused, 0);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        (context, contract)
+    }
+
+    #[test]
+    fn test_due_swaps_are_executed() {
+        let (mut context, mut contract) = setup();
+        enable_swap_history(&mut context, &mut contract, accounts(4));
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        assert_eq!(contract.execute_due_orders(None), 1);
+        // the next one isn't due yet
+        assert_eq!(contract.execute_due_orders(None), 0);
+        let order = &contract.get_dca_orders(accounts(4))[0];
+        assert_eq!(order.order.budget.0, 6_668);
+        assert_eq!(order.order.fills, 1);
+        assert_eq!(order.order.amount_in.0, 3_333);
+        assert!(order.average_price.unwrap().0 < fixed_point::ONE);
+        assert!(contract.get_deposits(accounts(5))[&accounts(2)].0 > 0);
+
+        testing_env!(context.block_timestamp(MINUTE).build());
+        assert_eq!(contract.execute_due_orders(None), 1);
+        testing_env!(context.block_timestamp(2 * MINUTE).build());
+        assert_eq!(contract.execute_due_orders(None), 1);
+        assert!(contract.get_dca_orders(accounts(4)).is_empty());
+        let history = contract.get_swap_history(accounts(4), None, None);
+        assert_eq!(history.len(), 3);
+        // 10_001 is split into 3_333, 3_334 and 3_334
+        assert_eq!(history[0].amount_in.0, 3_334);
+    }
+
+    #[test]
+    fn test_swap_pays_the_pool_output_after_virtual_orders() {
+        let (mut context, mut contract) = setup();
+        let msg = format!(
+            r#"{{"PlaceLongTermOrder": {{"token_out": "{}", "blocks": "100"}}}}"#,
+            accounts(3)
+        );
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(5),
+            100_000,
+            msg,
+        );
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_index(100)
+            .build());
+        let reserve_before = contract.get_tokens()[0].balance.0;
+        assert_eq!(contract.execute_due_orders(None), 1);
+        let released = reserve_before - contract.main_pool_reserves()[0];
+        let paid = contract.get_dca_orders(accounts(4))[0].order.amount_out.0;
+        let reward = contract.get_deposits(accounts(5))[&accounts(2)].0;
+        assert_eq!(paid + reward, released);
+    }
+
+    #[test]
+    fn test_unregistered_keeper_earns_no_reward() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let reserve_before = contract.get_tokens()[0].balance.0;
+        assert_eq!(contract.execute_due_orders(None), 1);
+        let released = reserve_before - contract.main_pool_reserves()[0];
+        // the order's owner keeps the reward
+        assert_eq!(
+            contract.get_dca_orders(accounts(4))[0].order.amount_out.0,
+            released
+        );
+        assert!(contract.get_deposits(accounts(0)).is_empty());
+    }
+
+    #[test]
+    fn test_cancel_dca_order() {
+        let (mut context, mut contract) = setup();
+        contract.execute_due_orders(None);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.cancel_dca_order(0);
+        assert!(contract.get_dca_orders(accounts(4)).is_empty());
+        assert_eq!(contract.dca_schedule.len(), 0);
+        testing_env!(context.block_timestamp(MINUTE).build());
+        assert_eq!(contract.execute_due_orders(None), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "E_UNAUTHORIZED: Only the owner of the order can cancel it")]
+    fn test_cancel_dca_order_not_owner() {
+        let (_context, mut contract) = setup();
+        contract.cancel_dca_order(0);
+    }
+
+    #[test]
+    fn test_swap_below_min_price_is_skipped() {
+        let (mut context, mut contract) = setup();
+        // a large sale of the same token right before the DCA swap
+        let msg = format!(r#"{{"Swap": {{"token_out": "{}"}}}}"#, accounts(2));
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(5),
+            100_000,
+            msg,
+        );
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        assert_eq!(contract.execute_due_orders(None), 0);
+        assert!(get_logs()[0].starts_with("DCA order 0 is not executed: E_SLIPPAGE"));
+        let order = &contract.get_dca_orders(accounts(4))[0].order;
+        assert_eq!(order.budget.0, 10_001);
+        assert_eq!(order.next_swap_at.0, MINUTE);
+        // it isn't due again before the interval
+        testing_env!(context.build());
+        assert_eq!(contract.execute_due_orders(None), 0);
+        assert!(get_logs().is_empty());
+        // the price recovers, and the order is executed after the interval
+        let msg = format!(r#"{{"Swap": {{"token_out": "{}"}}}}"#, accounts(3));
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(5),
+            90_000,
+            msg,
+        );
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .block_timestamp(MINUTE)
+            .build());
+        assert_eq!(contract.execute_due_orders(None), 1);
+    }
+
+    #[test]
+    fn test_order_limits() {
+        let (mut context, mut contract) = setup();
+        // 3 swaps of 99 are below 0.01% of the reserve
+        let unused = transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(5),
+            299,
+            place_msg(),
+        );
+        assert_eq!(unused, 299);
+        assert_eq!(
+            get_logs(),
+            vec!["299 danny are refunded: E_BAD_MSG: Invalid msg: every swap must sell at least 100 danny"]
+        );
+
+        for _ in 1..MAX_DCA_ORDERS_PER_ACCOUNT {
+            let unused = transfer(
+                &mut context,
+                &mut contract,
+                accounts(3),
+                accounts(4),
+                300,
+                place_msg(),
+            );
+            assert_eq!(unused, 0);
+        }
+        let unused = transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(4),
+            300,
+            place_msg(),
+        );
+        assert_eq!(unused, 300);
+        assert_eq!(
+            contract.get_dca_orders(accounts(4)).len(),
+            MAX_DCA_ORDERS_PER_ACCOUNT as usize
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use near_sdk::json_types::U128;
+use near_sdk::{env, is_promise_success, log, near_bindgen, AccountId, Balance, Promise};
+
+use crate::*;
+
+#[near_bindgen]
+impl AMM {
+    /// Tokens credited to `account_id` inside the AMM, e.g. with a `Deposit` msg or from closed
+    /// positions.
+    pub fn get_deposits(&self, account_id: AccountId) -> HashMap<AccountId, U128> {
+        self.deposits
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(token_id, amount)| (token_id, U128(amount)))
+            .collect()
+    }
+
+    /// Transfers deposited tokens back to the caller.
+    pub fn withdraw(&mut self, token_id: AccountId, amount: U128) -> Promise {
+        let account_id = env::predecessor_account_id();
+        self.internal_withdraw(&account_id, &token_id, amount.0);
+        self.track_sent(&token_id, amount.0);
+        ext_ft::ext(token_id.clone())
+            .with_attached_deposit(1)
+            .ft_transfer(account_id.clone(), amount, None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .withdraw_callback(account_id, token_id, amount),
+            )
+    }
+
+    /// Credits the tokens back if the transfer failed, e.g. the account isn't registered.
+    #[private]
+    pub fn withdraw_callback(&mut self, account_id: AccountId, token_id: AccountId, amount: U128) {
+        if !is_promise_success() {
+            log!(
+                "Failed to withdraw {} {} to @{}, the tokens are credited back",
+                amount.0,
+                token_id,
+                account_id
+            );
+            self.track_received(&token_id, amount.0);
+            self.internal_deposit(&account_id, &token_id, amount.0);
+        }
+    }
+}
+
+impl AMM {
+    pub(crate) fn internal_deposit(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) {
+        let mut deposits = self.deposits.get(account_id).unwrap_or_default();
+        *deposits.entry(token_id.clone()).or_default() += amount;
+        self.deposits.insert(account_id, &deposits);
+    }
+
+    pub(crate) fn internal_withdraw(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) {
+        let mut deposits = self.deposits.get(account_id).unwrap_or_default();
+        let balance = deposits.get(token_id).copied().unwrap_or(0);
+        assert!(
+            balance >= amount,
+            "Not enough {} deposited: {} < {}",
+            token_id,
+            balance,
+            amount
+        );
+        if balance == amount {
+            deposits.remove(token_id);
+        } else {
+            deposits.insert(token_id.clone(), balance - amount);
+        }
+        if deposits.is_empty() {
+            self.deposits.remove(account_id);
+        } else {
+            self.deposits.insert(account_id, &deposits);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Failures of the AMM.
+//!
+//! Every [`AmmError`] has a stable code that starts its message, e.g.
+//! `E_SLIPPAGE: Slippage error: 90 is less than the minimum 100`, so clients can match the
+//! failures of a transaction programmatically. Transfers that fail are refunded by
+//! `ft_on_transfer` with the error logged as the reason, everything else panics with it.
+
+use std::fmt;
+
+use near_sdk::{AccountId, Balance};
+
+use crate::math::MathError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmmError {
+    /// The token isn't accepted by the AMM or isn't in the pool.
+    UnknownToken(AccountId),
+    /// The `ft_transfer_call` msg can't be handled.
+    BadMsg(String),
+    /// The output or the shares are below the minimum, or the input above the maximum.
+    Slippage(String),
+    /// The main pool is locked by a flash loan.
+    Paused,
+    InsufficientLiquidity,
+    /// The caller isn't allowed to do this.
+    Unauthorized(String),
+    /// The account didn't pay for its storage with `storage_deposit`.
+    NotRegistered(AccountId),
+    MathOverflow,
+}
+
+impl AmmError {
+    /// The stable code of the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AmmError::UnknownToken(_) => "E_UNKNOWN_TOKEN",
+            AmmError::BadMsg(_) => "E_BAD_MSG",
+            AmmError::Slippage(_) => "E_SLIPPAGE",
+            AmmError::Paused => "E_PAUSED",
+            AmmError::InsufficientLiquidity => "E_INSUFFICIENT_LIQUIDITY",
+            AmmError::Unauthorized(_) => "E_UNAUTHORIZED",
+            AmmError::NotRegistered(_) => "E_NOT_REGISTERED",
+            AmmError::MathOverflow => "E_MATH_OVERFLOW",
+        }
+    }
+
+    pub fn below_minimum(amount: Balance, min_amount: Balance) -> Self {
+        AmmError::Slippage(format!(
+            "{} is less than the minimum {}",
+            amount, min_amount
+        ))
+    }
+
+    pub fn shares_below_minimum(shares: Balance, min_shares: Balance) -> Self {
+        AmmError::Slippage(format!(
+            "{} shares is less than the minimum {}",
+            shares, min_shares
+        ))
+    }
+
+    pub fn above_maximum(amount: Balance, max_amount: Balance) -> Self {
+        AmmError::Slippage(format!(
+            "the input {} is more than the maximum {}",
+            amount, max_amount
+        ))
+    }
+}
+
+impl fmt::Display for AmmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.code())?;
+        match self {
+            AmmError::UnknownToken(token_id) => {
+                write!(f, "The token {} is not supported", token_id)
+            }
+            AmmError::BadMsg(msg) => write!(f, "Invalid msg: {}", msg),
+            AmmError::Slippage(details) => write!(f, "Slippage error: {}", details),
+            AmmError::Paused => write!(f, "The main pool is locked by a flash loan"),
+            AmmError::InsufficientLiquidity => write!(f, "Not enough liquidity in the pool"),
+            AmmError::Unauthorized(reason) => write!(f, "{}", reason),
+            AmmError::NotRegistered(account_id) => write!(
+                f,
+                "@{} is not registered, pay for its storage with storage_deposit",
+                account_id
+            ),
+            AmmError::MathOverflow => write!(f, "Math overflow"),
+        }
+    }
+}
+
+impl From<MathError> for AmmError {
+    fn from(err: MathError) -> Self {
+        match err {
+            MathError::Overflow => AmmError::MathOverflow,
+            // only an empty reserve divides by zero
+            MathError::DivisionByZero | MathError::InsufficientLiquidity => {
+                AmmError::InsufficientLiquidity
+            }
+        }
+    }
+}
+
+pub type AmmResult<T> = Result<T, AmmError>;
+
+/// Unwraps results where there is nothing to refund, panicking with the error message.
+pub(crate) trait OrPanic<T> {
+    fn or_panic(self) -> T;
+}
+
+impl<T, E: fmt::Display> OrPanic<T> for Result<T, E> {
+    fn or_panic(self) -> T {
+        self.unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_starts_with_code() {
+        let err = AmmError::below_minimum(90, 100);
+        assert_eq!(
+            err.to_string(),
+            "E_SLIPPAGE: Slippage error: 90 is less than the minimum 100"
+        );
+        assert_eq!(
+            AmmError::from(MathError::Overflow).code(),
+            "E_MATH_OVERFLOW"
+        );
+        assert_eq!(
+            AmmError::from(MathError::DivisionByZero),
+            AmmError::InsufficientLiquidity
+        );
+    }
+}
@@ -0,0 +1,196 @@
+//! 18-decimal fixed-point arithmetic used by the weighted pools.
+//!
+//! Every value is a `u128` scaled by [`ONE`]. Products and quotients go through a 256-bit
+//! intermediate, so balances with 24 decimals don't overflow. `pow` is computed as
+//! `exp(y * ln(x))` with series expansions; it's accurate to about 1e-17 for the arguments the
+//! weighted pools feed it, and `pow_up`/`pow_down` widen the result by [`MAX_POW_RELATIVE_ERROR`]
+//! so the error is always rounded in the pool's favour.
+
+#[allow(clippy::all)]
+mod uint_types {
+    uint::construct_uint! {
+        pub struct U256(4);
+    }
+}
+pub use uint_types::U256;
+
+pub const ONE: u128 = 1_000_000_000_000_000_000;
+const ONE_I: i128 = ONE as i128;
+/// ln(2) scaled by `ONE`.
+const LN_2: i128 = 693_147_180_559_945_309;
+/// Relative error margin added to (or subtracted from) every `pow` result.
+pub const MAX_POW_RELATIVE_ERROR: u128 = 10_000;
+
+pub fn mul_down(a: u128, b: u128) -> u128 {
+    (U256::from(a) * U256::from(b) / U256::from(ONE)).as_u128()
+}
+
+pub fn mul_up(a: u128, b: u128) -> u128 {
+    let product = U256::from(a) * U256::from(b);
+    if product.is_zero() {
+        0
+    } else {
+        ((product - 1) / U256::from(ONE) + 1).as_u128()
+    }
+}
+
+pub fn div_down(a: u128, b: u128) -> u128 {
+    assert!(b != 0, "Division by zero");
+    (U256::from(a) * U256::from(ONE) / U256::from(b)).as_u128()
+}
+
+pub fn div_up(a: u128, b: u128) -> u128 {
+    assert!(b != 0, "Division by zero");
+    if a == 0 {
+        0
+    } else {
+        ((U256::from(a) * U256::from(ONE) - 1) / U256::from(b) + 1).as_u128()
+    }
+}
+
+/// `1 - x`, clamped at zero.
+pub fn complement(x: u128) -> u128 {
+    ONE.saturating_sub(x)
+}
+
+/// Natural logarithm of a positive fixed-point number.
+pub fn ln(x: u128) -> i128 {
+    assert!(x > 0, "Logarithm of zero");
+    // Bring x into [1, 2) and remember how many times it was halved or doubled.
+    let mut m = x;
+    let mut k: i128 = 0;
+    while m >= 2 * ONE {
+        m /= 2;
+        k += 1;
+    }
+    while m < ONE {
+        m *= 2;
+        k -= 1;
+    }
+    // ln(m) = 2 * atanh(z) = 2 * (z + z^3/3 + z^5/5 + ...), where z = (m - 1) / (m + 1) < 1/3
+    let z = ((m - ONE) * ONE / (m + ONE)) as i128;
+    let z_squared = z * z / ONE_I;
+    let mut term = z;
+    let mut sum = 0;
+    let mut n = 1;
+    while term != 0 {
+        sum += term / n;
+        term = term * z_squared / ONE_I;
+        n += 2;
+    }
+    2 * sum + k * LN_2
+}
+
+/// `e^x` for a fixed-point exponent.
+pub fn exp(x: i128) -> u128 {
+    // x = k * ln(2) + r, where 0 <= r < ln(2)
+    let k = x.div_euclid(LN_2);
+    let r = x.rem_euclid(LN_2);
+    let mut term = ONE_I;
+    let mut sum = ONE_I;
+    let mut n = 1;
+    while term != 0 {
+        term = term * r / ONE_I / n;
+        sum += term;
+        n += 1;
+    }
+    // sum < 2 * ONE < 2^61
+    let sum = sum as u128;
+    if k >= 0 {
+        assert!(k < 67, "Exponent is too large");
+        sum << k
+    } else if k > -128 {
+        sum >> -k
+    } else {
+        0
+    }
+}
+
+/// `x^y`, both fixed-point.
+pub fn pow(x: u128, y: u128) -> u128 {
+    if y == 0 {
+        return ONE;
+    }
+    if x == 0 {
+        return 0;
+    }
+    if y == ONE {
+        return x;
+    }
+    let log = ln(x);
+    let y = y as i128;
+    // Split the product so it stays within i128.
+    exp((log / ONE_I) * y + (log % ONE_I) * y / ONE_I)
+}
+
+/// `x^y` rounded up by the maximum relative error.
+pub fn pow_up(x: u128, y: u128) -> u128 {
+    let raw = pow(x, y);
+    raw + mul_up(raw, MAX_POW_RELATIVE_ERROR) + 1
+}
+
+/// `x^y` rounded down by the maximum relative error.
+pub fn pow_down(x: u128, y: u128) -> u128 {
+    let raw = pow(x, y);
+    raw.saturating_sub(mul_up(raw, MAX_POW_RELATIVE_ERROR) + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_f64(x: u128) -> f64 {
+        x as f64 / ONE as f64
+    }
+
+    fn assert_close(actual: u128, expected: f64) {
+        let actual = to_f64(actual);
+        assert!(
+            (actual - expected).abs() <= expected.abs() * 1e-12 + 1e-15,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_mul_div_rounding() {
+        assert_eq!(mul_down(1, ONE / 2), 0);
+        assert_eq!(mul_up(1, ONE / 2), 1);
+        assert_eq!(div_down(1, 3 * ONE), 0);
+        assert_eq!(div_up(1, 3 * ONE), 1);
+        // 24-decimal balances don't overflow
+        let balance = 10u128.pow(30);
+        assert_eq!(mul_down(balance, 2 * ONE), 2 * balance);
+        assert_eq!(div_down(balance, balance), ONE);
+    }
+
+    #[test]
+    fn test_ln_exp() {
+        assert_eq!(ln(ONE), 0);
+        assert!((ln(2 * ONE) - LN_2).abs() <= 2);
+        assert!((ln(ONE / 2) + LN_2).abs() <= 2);
+        assert_close(exp(0), 1.0);
+        assert_close(exp(ONE_I), std::f64::consts::E);
+        assert_close(exp(-3 * ONE_I), (-3.0f64).exp());
+        assert_close(exp(ln(123 * ONE)), 123.0);
+    }
+
+    #[test]
+    fn test_pow() {
+        assert_close(pow(2 * ONE, ONE / 2), 2f64.sqrt());
+        assert_close(pow(ONE / 2, 2 * ONE), 0.25);
+        assert_close(pow(ONE * 9 / 10, 4 * ONE), 0.9f64.powi(4));
+        assert_close(pow(ONE * 13 / 10, ONE / 4), 1.3f64.powf(0.25));
+        assert_eq!(pow(0, ONE / 3), 0);
+        assert_eq!(pow(ONE * 7, 0), ONE);
+    }
+
+    #[test]
+    fn test_pow_bounds() {
+        let x = ONE * 8 / 10;
+        let y = 3 * ONE / 2;
+        assert!(pow_down(x, y) < pow(x, y));
+        assert!(pow_up(x, y) > pow(x, y));
+    }
+}
@@ -0,0 +1,499 @@
+//! Flash loans out of the main pool's reserves.
+//!
+//! The loan is a chain of receipts: the tokens are transferred to the receiver, its
+//! `on_flash_loan` is called, and `resolve_flash_loan` checks that `amount + fee` came back
+//! through `ft_transfer_call` with the `FlashLoanRepayment` msg. The main pool is locked until
+//! the loan is resolved, so nothing but the repayment changes its reserves in between.
+//!
+//! Receipts on NEAR aren't atomic: an earlier transfer can't be undone by a later panic. That's
+//! why only receivers approved by the owner can borrow, only for themselves, and why `amount + fee`
+//! is set aside from the receiver's deposits as collateral while the loan runs. What isn't repaid
+//! is taken from the collateral, so the reserves and `k` never end up lower than before the loan.
+//! It's a collateralized loan: the receiver must already hold what it borrows.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::{
+    env, ext_contract, is_promise_success, log, near_bindgen, AccountId, Balance, Promise,
+    PromiseOrValue,
+};
+
+use crate::error::OrPanic;
+use crate::fixed_point::U256;
+use crate::math;
+use crate::*;
+
+/// Flash loan fee in basis points of the borrowed amount.
+pub const FLASH_LOAN_FEE: u128 = 9;
+const FEE_DIVISOR: u128 = 10_000;
+/// Blocks after which the owner can clear a loan that was never resolved.
+pub const FLASH_LOAN_TIMEOUT: u64 = 100;
+
+/// The loan that is being executed.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct FlashLoan {
+    pub receiver_id: AccountId,
+    pub token_id: AccountId,
+    pub amount: Balance,
+    pub fee: Balance,
+    /// Repaid so far with `FlashLoanRepayment` transfers.
+    pub repaid: Balance,
+    /// Taken from the receiver's deposits, the part that repays nothing is credited back.
+    pub collateral: Balance,
+    /// Reserves of the main pool when the loan was taken, `k` can't be lower once it's repaid.
+    pub reserves: Vec<Balance>,
+    /// Block height when the loan was taken.
+    pub started_at: u64,
+}
+
+/// Interface of the contracts that take flash loans.
+#[ext_contract(ext_flash_loan_receiver)]
+pub trait FlashLoanReceiver {
+    /// Called once `amount` of `token_id` is transferred to the receiver. Before the returned
+    /// promise resolves, the receiver must send `amount + fee` back with `ft_transfer_call` and
+    /// the `"FlashLoanRepayment"` msg.
+    fn on_flash_loan(
+        &mut self,
+        initiator_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        fee: U128,
+        msg: String,
+    ) -> PromiseOrValue<()>;
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Lends `amount` of `token_id` from the main pool to `receiver_id` and passes `msg` to its
+    /// `on_flash_loan`. The fee is [`FLASH_LOAN_FEE`] basis points, rounded up. Only the receiver
+    /// can call it, and it must have `amount + fee` of `token_id` deposited as collateral.
+    pub fn flash_loan(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        receiver_id: AccountId,
+        msg: String,
+    ) -> Promise {
+        if env::predecessor_account_id() != receiver_id {
+            panic!(
+                "{}",
+                AmmError::Unauthorized(format!("only @{} can take its flash loans", receiver_id))
+            );
+        }
+        self.assert_no_flash_loan();
+        self.settle_virtual_orders();
+        if !self.flash_loan_receivers.contains(&receiver_id) {
+            panic!(
+                "{}",
+                AmmError::Unauthorized(format!(
+                    "@{} is not approved to take flash loans",
+                    receiver_id
+                ))
+            );
+        }
+        let amount = amount.0;
+        let mut token_info = self.main_token(&token_id).or_panic();
+        assert!(amount > 0, "Nothing to lend");
+        if amount > token_info.balance {
+            panic!("{}", AmmError::InsufficientLiquidity);
+        }
+        let fee = math::mul_div_up(amount, FLASH_LOAN_FEE, FEE_DIVISOR).or_panic();
+        let collateral = math::checked_add(amount, fee).or_panic();
+        self.internal_withdraw(&receiver_id, &token_id, collateral);
+
+        let reserves = self.main_pool_reserves();
+        token_info.balance -= amount;
+        self.tokens.insert(&token_id, &token_info);
+        self.flash_loan = Some(FlashLoan {
+            receiver_id: receiver_id.clone(),
+            token_id: token_id.clone(),
+            amount,
+            fee,
+            repaid: 0,
+            collateral,
+            reserves,
+            started_at: env::block_height(),
+        });
+
+        self.track_sent(&token_id, amount);
+        ext_ft::ext(token_id)
+            .with_attached_deposit(1)
+            .ft_transfer(receiver_id, U128(amount), None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .flash_loan_transfer_callback(env::predecessor_account_id(), msg),
+            )
+    }
+
+    /// Calls the receiver once the tokens are transferred. If the transfer failed, nothing was
+    /// lent and the loan is cancelled.
+    #[private]
+    pub fn flash_loan_transfer_callback(
+        &mut self,
+        initiator_id: AccountId,
+        msg: String,
+    ) -> PromiseOrValue<()> {
+        let loan = self.flash_loan.as_ref().expect("No flash loan in progress");
+        if !is_promise_success() {
+            let loan = self.flash_loan.take().unwrap();
+            log!(
+                "Failed to lend {} {} to @{}, the loan is cancelled",
+                loan.amount,
+                loan.token_id,
+                loan.receiver_id
+            );
+            let mut token_info = self.tokens.get(&loan.token_id).unwrap();
+            token_info.balance += loan.amount;
+            self.tokens.insert(&loan.token_id, &token_info);
+            self.track_received(&loan.token_id, loan.amount);
+            self.internal_deposit(&loan.receiver_id, &loan.token_id, loan.collateral);
+            return PromiseOrValue::Value(());
+        }
+        ext_flash_loan_receiver::ext(loan.receiver_id.clone())
+            .on_flash_loan(
+                initiator_id,
+                loan.token_id.clone(),
+                U128(loan.amount),
+                U128(loan.fee),
+                msg,
+            )
+            .then(Self::ext(env::current_account_id()).resolve_flash_loan())
+            .into()
+    }
+
+    /// Returns the repayment to the reserves and unlocks the main pool. Returns `true` if the
+    /// loan was repaid with the fee.
+    #[private]
+    pub fn resolve_flash_loan(&mut self) -> bool {
+        let loan = self.flash_loan.take().expect("No flash loan in progress");
+        self.close_flash_loan(loan)
+    }
+
+    /// Resolves a loan that is still in progress [`FLASH_LOAN_TIMEOUT`] blocks after it was
+    /// taken, e.g. because its callback failed, with what was repaid so far.
+    pub fn clear_flash_loan(&mut self) -> bool {
+        self.assert_owner();
+        let loan = self.flash_loan.take().expect("No flash loan in progress");
+        assert!(
+            env::block_height() >= loan.started_at + FLASH_LOAN_TIMEOUT,
+            "The flash loan can be cleared from block {}",
+            loan.started_at + FLASH_LOAN_TIMEOUT
+        );
+        log!("The flash loan to @{} is cleared", loan.receiver_id);
+        self.close_flash_loan(loan)
+    }
+
+    pub fn approve_flash_loan_receiver(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.flash_loan_receivers.insert(&account_id);
+    }
+
+    pub fn revoke_flash_loan_receiver(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.flash_loan_receivers.remove(&account_id);
+    }
+
+    pub fn get_flash_loan_receivers(&self) -> Vec<AccountId> {
+        self.flash_loan_receivers.to_vec()
+    }
+}
+
+impl AMM {
+    /// The main pool can't be used while a flash loan is in progress.
+    pub(crate) fn assert_no_flash_loan(&self) {
+        self.check_no_flash_loan().or_panic();
+    }
+
+    pub(crate) fn check_no_flash_loan(&self) -> AmmResult<()> {
+        match self.flash_loan {
+            Some(_) => Err(AmmError::Paused),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds the repayment, and the collateral for what is missing, to the reserves. The rest of
+    /// the collateral is credited back to the receiver. Nothing here panics, the main pool must
+    /// be unlocked whatever happens.
+    fn close_flash_loan(&mut self, loan: FlashLoan) -> bool {
+        let owed = loan.amount + loan.fee;
+        let from_collateral = owed.saturating_sub(loan.repaid);
+        let mut token_info = self.tokens.get(&loan.token_id).unwrap();
+        token_info.balance = token_info
+            .balance
+            .saturating_add(loan.repaid)
+            .saturating_add(from_collateral);
+        self.tokens.insert(&loan.token_id, &token_info);
+        self.k = self.main_pool_k();
+        if loan.collateral > from_collateral {
+            self.internal_deposit(
+                &loan.receiver_id,
+                &loan.token_id,
+                loan.collateral - from_collateral,
+            );
+        }
+        if math::invariant(&self.main_pool_reserves()) < math::invariant(&loan.reserves) {
+            log!("The flash loan decreased k");
+        }
+
+        if loan.repaid >= owed {
+            log!(
+                "Flash loan of {} {} to @{} is repaid with the fee {}",
+                loan.amount,
+                loan.token_id,
+                loan.receiver_id,
+                loan.repaid - loan.amount
+            );
+            true
+        } else {
+            // The receiver can't borrow again.
+            self.flash_loan_receivers.remove(&loan.receiver_id);
+            log!(
+                "Flash loan of {} {} to @{} is not repaid: {} < {}, {} are taken from the collateral",
+                loan.amount,
+                loan.token_id,
+                loan.receiver_id,
+                loan.repaid,
+                owed,
+                from_collateral
+            );
+            false
+        }
+    }
+
+    /// Credits a `FlashLoanRepayment` transfer to the loan in progress.
+    pub(crate) fn repay_flash_loan(
+        &mut self,
+        sender_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) -> AmmResult<()> {
+        let loan = self
+            .flash_loan
+            .as_mut()
+            .ok_or_else(|| AmmError::BadMsg("no flash loan in progress".to_string()))?;
+        if &loan.receiver_id != sender_id || &loan.token_id != token_id {
+            return Err(AmmError::BadMsg(
+                "the transfer doesn't repay the flash loan in progress".to_string(),
+            ));
+        }
+        loan.repaid = math::checked_add(loan.repaid, amount)?;
+        Ok(())
+    }
+
+    /// `k` of the main pool, capped at `u128::MAX` for reserves whose product doesn't fit.
+    /// Comparisons of `k` go through [`math::invariant`] instead.
+    pub(crate) fn main_pool_k(&self) -> u128 {
+        math::invariant(&self.main_pool_reserves())
+            .min(U256::from(u128::MAX))
+            .as_u128()
+    }
+
+    /// Reserves of the main pool, in the order of `get_main_pool_tokens`.
+    pub(crate) fn main_pool_reserves(&self) -> Vec<Balance> {
+        self.tokens
+            .values()
+            .map(|token_info| token_info.balance)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    fn context(predecessor: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor);
+        builder
+    }
+
+    /// The main pool holds 20_000 of `accounts(2)` and 5_000 of `accounts(3)`, `accounts(4)` is
+    /// an approved receiver with 20_000 of `accounts(2)` deposited.
+    fn setup() -> AMM {
+        testing_env!(context(accounts(1)).build());
+        let mut contract = AMM::new(accounts(1), accounts(2), accounts(3));
+        test_utils::register(&mut context(accounts(1)), &mut contract, accounts(1));
+        for (sell, buy, amount) in [
+            (accounts(2), accounts(3), 20_000),
+            (accounts(3), accounts(2), 5_000),
+        ] {
+            testing_env!(context(sell.clone()).build());
+            contract.ft_on_transfer(accounts(1), U128(amount), format!("{}:{}", sell, buy));
+        }
+        testing_env!(context(accounts(1)).build());
+        contract.add_liquidity(vec![U128(20_000), U128(5_000)], U128(0));
+        test_utils::register(&mut context(accounts(4)), &mut contract, accounts(4));
+        testing_env!(context(accounts(2)).build());
+        contract.ft_on_transfer(accounts(4), U128(20_000), "\"Deposit\"".to_string());
+        testing_env!(context(accounts(1)).build());
+        contract.approve_flash_loan_receiver(accounts(4));
+        contract
+    }
+
+    fn borrow(contract: &mut AMM, amount: Balance) {
+        testing_env!(context(accounts(4)).build());
+        contract.flash_loan(accounts(2), U128(amount), accounts(4), String::new());
+        testing_env!(
+            context(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        contract.flash_loan_transfer_callback(accounts(4), String::new());
+    }
+
+    fn repay(contract: &mut AMM, amount: Balance) {
+        testing_env!(context(accounts(2)).build());
+        contract.ft_on_transfer(
+            accounts(4),
+            U128(amount),
+            "\"FlashLoanRepayment\"".to_string(),
+        );
+    }
+
+    fn balance(contract: &AMM, token: &AccountId) -> Balance {
+        contract.tokens.get(token).unwrap().balance
+    }
+
+    /// `accounts(2)` deposited by the receiver.
+    fn deposit(contract: &AMM) -> Balance {
+        contract
+            .get_deposits(accounts(4))
+            .get(&accounts(2))
+            .map_or(0, |amount| amount.0)
+    }
+
+    #[test]
+    fn test_repaid_flash_loan() {
+        let mut contract = setup();
+        let k = contract.k;
+        borrow(&mut contract, 10_000);
+        assert_eq!(balance(&contract, &accounts(2)), 10_000);
+
+        repay(&mut contract, 10_009);
+        testing_env!(context(accounts(0)).build());
+        assert!(contract.resolve_flash_loan());
+        assert_eq!(balance(&contract, &accounts(2)), 20_009);
+        assert!(contract.k > k);
+        assert!(contract.flash_loan.is_none());
+        assert_eq!(deposit(&contract), 20_000);
+    }
+
+    #[test]
+    fn test_unpaid_flash_loan() {
+        let mut contract = setup();
+        borrow(&mut contract, 10_000);
+        repay(&mut contract, 10_000);
+        testing_env!(context(accounts(0)).build());
+        assert!(!contract.resolve_flash_loan());
+        // the missing 9 are taken from the collateral
+        assert_eq!(balance(&contract, &accounts(2)), 20_009);
+        assert_eq!(deposit(&contract), 19_991);
+        assert!(contract.get_flash_loan_receivers().is_empty());
+    }
+
+    #[test]
+    fn test_repayment_without_flash_loan() {
+        let mut contract = setup();
+        repay(&mut contract, 100);
+        assert_eq!(
+            get_logs(),
+            vec!["100 charlie are refunded: E_BAD_MSG: Invalid msg: no flash loan in progress"]
+        );
+        assert_eq!(balance(&contract, &accounts(2)), 20_000);
+    }
+
+    #[test]
+    fn test_clear_flash_loan() {
+        let mut contract = setup();
+        borrow(&mut contract, 10_000);
+        testing_env!(context(accounts(1)).block_index(FLASH_LOAN_TIMEOUT).build());
+        assert!(!contract.clear_flash_loan());
+        assert_eq!(balance(&contract, &accounts(2)), 20_009);
+        assert!(contract.flash_loan.is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "The flash loan can be cleared from block 100")]
+    fn test_clear_flash_loan_too_early() {
+        let mut contract = setup();
+        borrow(&mut contract, 10_000);
+        testing_env!(context(accounts(1)).build());
+        contract.clear_flash_loan();
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough charlie deposited: 20000 < 20018")]
+    fn test_flash_loan_without_collateral() {
+        let mut contract = setup();
+        testing_env!(context(accounts(4)).build());
+        contract.flash_loan(accounts(2), U128(20_000), accounts(4), String::new());
+    }
+
+    #[test]
+    fn test_failed_loan_transfer() {
+        let mut contract = setup();
+        testing_env!(context(accounts(4)).build());
+        contract.flash_loan(accounts(2), U128(10_000), accounts(4), String::new());
+        testing_env!(
+            context(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.flash_loan_transfer_callback(accounts(4), String::new());
+        assert_eq!(balance(&contract, &accounts(2)), 20_000);
+        assert!(contract.flash_loan.is_none());
+        assert_eq!(deposit(&contract), 20_000);
+    }
+
+    #[test]
+    fn test_swap_during_flash_loan() {
+        let mut contract = setup();
+        borrow(&mut contract, 10_000);
+        testing_env!(context(accounts(3)).build());
+        let result = contract.ft_on_transfer(
+            accounts(5),
+            U128(100),
+            format!("{}:{}", accounts(3), accounts(2)),
+        );
+        assert!(matches!(result, PromiseOrValue::Value(U128(100))));
+        assert_eq!(
+            get_logs(),
+            vec!["100 danny are refunded: E_PAUSED: The main pool is locked by a flash loan"]
+        );
+        assert_eq!(balance(&contract, &accounts(3)), 5_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "E_PAUSED")]
+    fn test_add_liquidity_during_flash_loan() {
+        let mut contract = setup();
+        borrow(&mut contract, 10_000);
+        testing_env!(context(accounts(1)).build());
+        contract.add_liquidity(vec![U128(1_000), U128(1_000)], U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "is not approved to take flash loans")]
+    fn test_unapproved_receiver() {
+        let mut contract = setup();
+        testing_env!(context(accounts(5)).build());
+        contract.flash_loan(accounts(2), U128(10_000), accounts(5), String::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "E_UNAUTHORIZED: only @eugene can take its flash loans")]
+    fn test_flash_loan_for_another_receiver() {
+        let mut contract = setup();
+        testing_env!(context(accounts(5)).build());
+        contract.flash_loan(accounts(2), U128(10_000), accounts(4), String::new());
+    }
+}
@@ -1,53 +1,204 @@
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::vec;
 
 use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
-use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::UnorderedMap;
-use near_sdk::json_types::U128;
+use near_sdk::collections::{LookupMap, TreeMap, UnorderedMap, UnorderedSet, Vector};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::Serialize;
 use near_sdk::{
-    env, ext_contract, log, near_bindgen, AccountId, Balance, Gas, PanicOnDefault, Promise,
-    PromiseOrValue,
+    env, ext_contract, log, near_bindgen, AccountId, Balance, BorshStorageKey, Gas, PanicOnDefault,
+    Promise,
 };
 
+pub use crate::dca::{DcaOrder, DcaOrderView, DcaParams};
+use crate::error::OrPanic;
+pub use crate::error::{AmmError, AmmResult};
+use crate::fixed_point::U256;
+pub use crate::flash_loan::{FlashLoan, FlashLoanReceiver};
+pub use crate::limit_orders::LimitOrder;
+pub use crate::math::{MathError, MathResult};
+pub use crate::pool::{Pool, PoolView};
+pub use crate::swap_history::{SwapRecord, VolumeView, SWAP_HISTORY_LEN};
+pub use crate::token_receiver::TokenReceiverMessage;
+pub use crate::token_registry::TokenBond;
+use crate::twamm::Settlement;
+pub use crate::twamm::{LongTermOrder, LongTermOrderView, ProceedsPerRate, VirtualOrderPoolView};
+
+pub mod concentrated_pool;
+mod dca;
+mod deposits;
+pub mod error;
+pub mod fixed_point;
+mod flash_loan;
+mod limit_orders;
+mod liquidity;
+pub mod math;
+mod migration;
+mod payout;
+mod pool;
+mod reconcile;
+mod storage;
+mod swap_history;
+#[cfg(test)]
+mod test_utils;
+mod token_receiver;
+mod token_registry;
+mod twamm;
+mod user_registration;
+pub mod weighted_pool;
+
 pub const GAS: Gas = Gas(300_000_000_000_000);
 const MIN_STORAGE: Balance = 1_000_000_000_000_000_000_000_000;
 
+#[derive(BorshStorageKey, BorshSerialize)]
+pub(crate) enum StorageKey {
+    Pools,
+    PoolShares { pool_id: u64 },
+    ConcentratedTicks { pool_id: u64 },
+    ConcentratedTickBitmap { pool_id: u64 },
+    ConcentratedPositions { pool_id: u64 },
+    Deposits,
+    FlashLoanReceivers,
+    WhitelistedTokens,
+    RegisteredTokens,
+    PendingLiquidity,
+    MainPoolShares,
+    TrackedBalances,
+    TransferFees,
+    Shortfalls,
+    SwapHistory,
+    PoolVolumes,
+    LimitOrders,
+    LimitOrderBook,
+    LimitOrderCounts,
+    DcaOrders,
+    DcaSchedule,
+    DcaOrderCounts,
+    TwammExpirations,
+    TwammProceeds,
+    TwammOrders,
+    TwammOrderCounts,
+    StorageDeposits,
+}
+
 #[near_bindgen]
 #[derive(PanicOnDefault, BorshDeserialize, BorshSerialize)]
 pub struct AMM {
     pub owner_id: AccountId,
     pub tokens: UnorderedMap<AccountId, TokenInfo>,
     pub k: u128,
+    pub pools: Vector<Pool>,
+    /// Tokens that accounts keep inside the AMM, by account and token.
+    pub deposits: LookupMap<AccountId, HashMap<AccountId, Balance>>,
+    /// The flash loan in progress, the main pool is locked until it's resolved.
+    pub flash_loan: Option<FlashLoan>,
+    pub flash_loan_receivers: UnorderedSet<AccountId>,
+    pub whitelisted_tokens: UnorderedSet<AccountId>,
+    /// Tokens listed with a storage bond, with the accounts that paid it.
+    pub registered_tokens: UnorderedMap<AccountId, TokenBond>,
+    /// Tokens sent with an `AddLiquidity` msg, by account and token.
+    pub pending_liquidity: LookupMap<AccountId, HashMap<AccountId, Balance>>,
+    pub main_shares: LookupMap<AccountId, Balance>,
+    pub main_shares_total_supply: Balance,
+    /// The balance of each token the AMM should hold, see `reconcile_balance`.
+    pub tracked_balances: LookupMap<AccountId, Balance>,
+    /// Taxes in basis points that token contracts take from the AMM's incoming transfers.
+    pub transfer_fees: LookupMap<AccountId, u16>,
+    /// The part of the tracked balances that reconciliations found missing and couldn't take
+    /// from the main pool.
+    pub shortfalls: LookupMap<AccountId, Balance>,
+    swap_history: LookupMap<AccountId, swap_history::SwapHistory>,
+    /// Volume by pool id, `None` for the main pool.
+    pool_volumes: LookupMap<Option<u64>, swap_history::PoolVolume>,
+    /// Open limit orders on the main pool by id.
+    pub limit_orders: UnorderedMap<u64, LimitOrder>,
+    /// Open limit orders by the token they sell and their price, from the lowest.
+    limit_order_book: TreeMap<limit_orders::OrderBookKey, ()>,
+    /// Open limit orders of each account.
+    limit_order_counts: LookupMap<AccountId, u32>,
+    /// Id of the next limit or DCA order.
+    pub next_order_id: u64,
+    pub dca_orders: UnorderedMap<u64, DcaOrder>,
+    /// DCA orders by the time of their next swap and their id.
+    dca_schedule: TreeMap<(u64, u64), ()>,
+    /// DCA orders of each account.
+    dca_order_counts: LookupMap<AccountId, u32>,
+    /// Long-term orders on the main pool.
+    twamm: twamm::Twamm,
+    /// What each registered account paid for its storage, see `storage_deposit`.
+    storage_deposits: LookupMap<AccountId, Balance>,
 }
 
 #[derive(Default, BorshSerialize, BorshDeserialize)]
 pub struct TokenInfo {
-    name: String,
-    decimals: u8,
+    /// The NEP-148 metadata, `None` until the first `ft_metadata` call returns.
+    metadata: Option<FungibleTokenMetadata>,
+    /// Block timestamp of the last metadata fetch, in nanoseconds.
+    metadata_fetched_at: u64,
+    /// Starts at 1 and grows every time a fetch returns changed metadata.
+    metadata_version: u32,
     balance: u128,
     ticker: TickerInfo,
 }
 
+impl TokenInfo {
+    fn name(&self) -> &str {
+        self.metadata.as_ref().map_or("", |metadata| &metadata.name)
+    }
+
+    fn decimals(&self) -> u8 {
+        self.metadata
+            .as_ref()
+            .map_or(0, |metadata| metadata.decimals)
+    }
+
+    /// Stores fetched metadata, bumping the version if it changed.
+    fn update_metadata(&mut self, metadata: FungibleTokenMetadata) {
+        // the standard's metadata isn't `PartialEq`, so it's compared serialized
+        let changed = self.metadata.as_ref().map(|old| old.try_to_vec().unwrap())
+            != Some(metadata.try_to_vec().unwrap());
+        if changed {
+            self.metadata_version += 1;
+        }
+        self.metadata = Some(metadata);
+        self.metadata_fetched_at = env::block_timestamp();
+    }
+
+    /// The view with the reserve and the sale rate of the virtual orders as of `settlement`.
+    fn view(&self, token_id: AccountId, settlement: &Settlement, side: usize) -> TokenView {
+        TokenView {
+            token_id,
+            metadata: self.metadata.clone(),
+            metadata_fetched_at: U64(self.metadata_fetched_at),
+            metadata_version: self.metadata_version,
+            balance: U128(settlement.reserves[side]),
+            virtual_sale_rate: U128(settlement.sale_rates[side]),
+        }
+    }
+}
+
+/// A main pool token with its cached metadata, so clients don't need to call `ft_metadata`.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenView {
+    pub token_id: AccountId,
+    pub metadata: Option<FungibleTokenMetadata>,
+    pub metadata_fetched_at: U64,
+    pub metadata_version: u32,
+    /// The main pool reserve, with the virtual orders settled up to the current block.
+    pub balance: U128,
+    /// Tokens the long-term orders sell to the main pool per block.
+    pub virtual_sale_rate: U128,
+}
+
 #[derive(Default, BorshSerialize, BorshDeserialize)]
 pub struct TickerInfo {
     pub change_direction: TokenRate,
     pub percentage: f64,
     pub change: f64,
 }
-impl TickerInfo {
-    fn update(&mut self, change: f64) {
-        if change > 1.0 {
-            self.change_direction = TokenRate::Decreased;
-            self.percentage = change / self.change;
-        } else {
-            self.change_direction = TokenRate::Increased;
-            self.percentage = self.change / change;
-        }
-        self.change = change;
-    }
-}
 impl Display for TickerInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let direction_symbol = match self.change_direction {
@@ -67,12 +218,22 @@ pub enum TokenRate {
     Decreased,
 }
 
+/// Methods of the token contracts that the AMM calls.
 #[ext_contract(ext_ft)]
-trait Contract {
+pub trait FtContract {
     fn ft_metadata(&self) -> Promise;
     fn ft_transfer(&self, receiver_id: AccountId, amount: U128, memo: Option<String>) -> Promise;
+    fn ft_transfer_call(
+        &self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> Promise;
     fn ft_balance_of(&self, account_id: AccountId) -> Promise;
     fn storage_deposit(&self, account_id: AccountId, registration_only: bool) -> Promise;
+    fn storage_balance_of(&self, account_id: AccountId) -> Promise;
+    fn storage_unregister(&self, force: Option<bool>) -> Promise;
 }
 
 #[near_bindgen]
@@ -83,26 +244,20 @@ impl AMM {
         account_id: &AccountId,
         #[callback_unwrap] balance: U128,
     ) {
-        let mut token_info = self
-            .tokens
-            .get(account_id)
-            .unwrap_or_else(|| panic!("The token {}, is not supported", account_id));
+        let mut token_info = self.main_token(account_id).or_panic();
         token_info.balance = u128::from(balance);
         self.tokens.insert(account_id, &token_info);
     }
 
+    /// Caches the metadata of a main pool token, its balance is kept.
     #[private]
     pub fn ft_metadata_callback(
         &mut self,
         account_id: &AccountId,
         #[callback_unwrap] meta: FungibleTokenMetadata,
     ) {
-        let token_info = TokenInfo {
-            name: meta.name,
-            decimals: meta.decimals,
-            balance: 0_u128,
-            ticker: TickerInfo::default(),
-        };
+        let mut token_info = self.tokens.get(account_id).unwrap_or_default();
+        token_info.update_metadata(meta);
         self.tokens.insert(account_id, &token_info);
     }
 }
@@ -124,11 +279,8 @@ impl AMM {
         tokens.insert(&a_contract, &TokenInfo::default());
         tokens.insert(&b_contract, &TokenInfo::default());
 
-        let mut this = Self {
-            owner_id,
-            tokens,
-            k: 0,
-        };
+        let mut this = Self::with_tokens(owner_id, tokens);
+        migration::write_state_version();
         this.get_metadata();
         this
     }
@@ -142,92 +294,217 @@ impl AMM {
         }
     }
 
+    /// Fetches the metadata of the main pool tokens again, e.g. after a token changed it.
+    pub fn refresh_metadata(&mut self) {
+        self.get_metadata();
+    }
+
+    pub fn get_token(&self, token_id: AccountId) -> Option<TokenView> {
+        let side = self.tokens.keys().position(|id| id == token_id)?;
+        let token_info = self.tokens.get(&token_id).unwrap();
+        Some(token_info.view(token_id, &self.settled_virtual_orders(), side))
+    }
+
+    /// The main pool tokens with their metadata.
+    pub fn get_tokens(&self) -> Vec<TokenView> {
+        let settlement = self.settled_virtual_orders();
+        self.tokens
+            .iter()
+            .enumerate()
+            .map(|(side, (token_id, token_info))| token_info.view(token_id, &settlement, side))
+            .collect()
+    }
+
     pub fn info(&self) -> String {
+        let settlement = self.settled_virtual_orders();
         let mut res = "".to_string();
-        for (token_addr, token_info) in &self.tokens {
+        for (side, (token_addr, token_info)) in self.tokens.iter().enumerate() {
             res.push_str(
                 format!(
-                    "Token address: {}. Token name: {}. Decimals: {}. Ticker: {}. Balance: {:?}; ",
+                    "Token address: {}. Token name: {}. Decimals: {}. Ticker: {}. Balance: {:?}. Virtual orders sell: {} per block; ",
                     token_addr,
-                    token_info.name,
-                    token_info.decimals,
+                    token_info.name(),
+                    token_info.decimals(),
                     token_info.ticker,
-                    token_info.balance
+                    settlement.reserves[side],
+                    settlement.sale_rates[side]
                 )
                 .as_str(),
             );
         }
-        res.push_str(format!("Tokens ratio: {}", self.k).as_str());
+        let k = math::invariant(&settlement.reserves)
+            .min(U256::from(u128::MAX))
+            .as_u128();
+        res.push_str(format!("Tokens ratio: {}", k).as_str());
         res
     }
 }
 
-#[near_bindgen]
-impl FungibleTokenReceiver for AMM {
-    fn ft_on_transfer(
-        &mut self,
-        sender_id: AccountId,
-        amount: U128,
-        msg: String,
-    ) -> PromiseOrValue<U128> {
-        let amount = u128::from(amount);
+impl AMM {
+    /// A contract with the main pool `tokens` and no other state.
+    fn with_tokens(owner_id: AccountId, tokens: UnorderedMap<AccountId, TokenInfo>) -> Self {
+        let mut this = Self {
+            owner_id,
+            tokens,
+            k: 0,
+            pools: Vector::new(StorageKey::Pools),
+            deposits: LookupMap::new(StorageKey::Deposits),
+            flash_loan: None,
+            flash_loan_receivers: UnorderedSet::new(StorageKey::FlashLoanReceivers),
+            whitelisted_tokens: UnorderedSet::new(StorageKey::WhitelistedTokens),
+            registered_tokens: UnorderedMap::new(StorageKey::RegisteredTokens),
+            pending_liquidity: LookupMap::new(StorageKey::PendingLiquidity),
+            main_shares: LookupMap::new(StorageKey::MainPoolShares),
+            main_shares_total_supply: 0,
+            tracked_balances: LookupMap::new(StorageKey::TrackedBalances),
+            transfer_fees: LookupMap::new(StorageKey::TransferFees),
+            shortfalls: LookupMap::new(StorageKey::Shortfalls),
+            swap_history: LookupMap::new(StorageKey::SwapHistory),
+            pool_volumes: LookupMap::new(StorageKey::PoolVolumes),
+            limit_orders: UnorderedMap::new(StorageKey::LimitOrders),
+            limit_order_book: TreeMap::new(StorageKey::LimitOrderBook),
+            limit_order_counts: LookupMap::new(StorageKey::LimitOrderCounts),
+            next_order_id: 0,
+            dca_orders: UnorderedMap::new(StorageKey::DcaOrders),
+            dca_schedule: TreeMap::new(StorageKey::DcaSchedule),
+            dca_order_counts: LookupMap::new(StorageKey::DcaOrderCounts),
+            twamm: twamm::Twamm::new(),
+            storage_deposits: LookupMap::new(StorageKey::StorageDeposits),
+        };
+        this.k = this.main_pool_k();
+        this
+    }
 
-        // Get tokens' accounts.
-        let accounts = msg
-            .split(':')
-            .map(|x| AccountId::new_unchecked(x.to_string()))
-            .collect::<Vec<AccountId>>();
-        let sell_token = &accounts[0];
-        let buy_token = &accounts[1];
+    pub(crate) fn assert_owner(&self) {
+        self.check_owner().or_panic();
+    }
 
-        let mut sell_token_info = self.tokens.get(sell_token).unwrap();
-        let mut buy_token_info = self.tokens.get(buy_token).unwrap();
+    pub(crate) fn check_owner(&self) -> AmmResult<()> {
+        if env::predecessor_account_id() == self.owner_id {
+            Ok(())
+        } else {
+            Err(AmmError::Unauthorized(
+                "Only the owner can call this method".to_string(),
+            ))
+        }
+    }
 
-        if sender_id == self.owner_id {
-            sell_token_info.balance += amount;
-            self.k = buy_token_info.balance * sell_token_info.balance;
+    /// A token of the main pool.
+    pub(crate) fn main_token(&self, token_id: &AccountId) -> AmmResult<TokenInfo> {
+        self.tokens
+            .get(token_id)
+            .ok_or_else(|| AmmError::UnknownToken(token_id.clone()))
+    }
 
-            self.tokens.insert(sell_token, &sell_token_info);
-        } else {
-            // (x + a)(y - b) = xy
-            // x = sell_token_balance, y = buy_token_balance, a = amount, b = unknown var
-            // b = ya / (x + a)
-            // b = buy_token * amount / (x + amount)
+    /// The main pool reserves of `sell_token` and `buy_token`, with the virtual orders settled
+    /// up to the current block. A pool without both tokens can't swap.
+    fn main_reserves(
+        &self,
+        sell_token: &AccountId,
+        buy_token: &AccountId,
+    ) -> AmmResult<(Balance, Balance)> {
+        let token_ids = self.get_main_pool_tokens();
+        let side = |token_id: &AccountId| {
+            token_ids
+                .iter()
+                .position(|id| id == token_id)
+                .ok_or_else(|| AmmError::UnknownToken(token_id.clone()))
+        };
+        let (sell_side, buy_side) = (side(sell_token)?, side(buy_token)?);
+        let reserves = self.settled_virtual_orders().reserves;
+        if reserves[sell_side] == 0 || reserves[buy_side] == 0 {
+            return Err(AmmError::InsufficientLiquidity);
+        }
+        Ok((reserves[sell_side], reserves[buy_side]))
+    }
 
-            // Thus,
-            // buy_token_balance -= b
-            // sell_token_balance += amount
-            // k aka xy remains the same
+    /// Constant-product quote for the main pool, rounded down.
+    pub(crate) fn get_main_return(
+        &self,
+        sell_token: &AccountId,
+        amount: Balance,
+        buy_token: &AccountId,
+    ) -> AmmResult<Balance> {
+        let (x, y) = self.main_reserves(sell_token, buy_token)?;
+        Ok(math::get_amount_out(amount, x, y)?)
+    }
 
-            let x = sell_token_info.balance;
-            let y = buy_token_info.balance;
-            let b = (amount * y) / (amount + x);
+    /// Input the main pool needs to pay out exactly `amount_out`, rounded up.
+    pub(crate) fn get_main_amount_in(
+        &self,
+        sell_token: &AccountId,
+        amount_out: Balance,
+        buy_token: &AccountId,
+    ) -> AmmResult<Balance> {
+        let (x, y) = self.main_reserves(sell_token, buy_token)?;
+        Ok(math::get_amount_in(amount_out, x, y)?)
+    }
 
-            log!("x: {}, y: {}, amount: {}, b: {}", x, y, amount, b);
+    /// Swaps in the main pool and returns the amount of `buy_token` to transfer.
+    pub(crate) fn swap_main(
+        &mut self,
+        sell_token: &AccountId,
+        amount: Balance,
+        buy_token: &AccountId,
+    ) -> AmmResult<Balance> {
+        self.check_no_flash_loan()?;
+        self.settle_virtual_orders();
+        let b = self.get_main_return(sell_token, amount, buy_token)?;
+        let mut sell_token_info = self.main_token(sell_token)?;
+        let mut buy_token_info = self.main_token(buy_token)?;
 
-            // update balances
-            sell_token_info.balance += amount;
-            buy_token_info.balance -= b;
+        log!(
+            "x: {}, y: {}, amount: {}, b: {}",
+            sell_token_info.balance,
+            buy_token_info.balance,
+            amount,
+            b
+        );
 
-            self.tokens.insert(sell_token, &sell_token_info);
-            self.tokens.insert(buy_token, &buy_token_info);
+        // Thus,
+        // buy_token_balance -= b
+        // sell_token_balance += amount
+        // k aka xy doesn't decrease, b is rounded down
+        sell_token_info.balance = math::checked_add(sell_token_info.balance, amount)?;
+        buy_token_info.balance -= b;
 
-            log!("amount to transfer: {}", b);
+        self.tokens.insert(sell_token, &sell_token_info);
+        self.tokens.insert(buy_token, &buy_token_info);
+        Ok(b)
+    }
 
-            // transfer buy_token to initializer of swap operation
-            ext_ft::ext(buy_token.clone())
-                .with_attached_deposit(1)
-                .ft_transfer(sender_id, U128::from(b), None);
+    /// Swaps in the main pool for exactly `amount_out` and returns the input used. The rounding
+    /// of the input stays in the pool.
+    pub(crate) fn swap_main_exact_out(
+        &mut self,
+        sell_token: &AccountId,
+        max_amount: Balance,
+        amount_out: Balance,
+        buy_token: &AccountId,
+    ) -> AmmResult<Balance> {
+        self.check_no_flash_loan()?;
+        self.settle_virtual_orders();
+        let amount = self.get_main_amount_in(sell_token, amount_out, buy_token)?;
+        if amount > max_amount {
+            return Err(AmmError::above_maximum(amount, max_amount));
         }
-        PromiseOrValue::Value(U128::from(0_u128))
+        let mut sell_token_info = self.main_token(sell_token)?;
+        let mut buy_token_info = self.main_token(buy_token)?;
+        sell_token_info.balance = math::checked_add(sell_token_info.balance, amount)?;
+        buy_token_info.balance -= amount_out;
+        self.tokens.insert(sell_token, &sell_token_info);
+        self.tokens.insert(buy_token, &buy_token_info);
+        Ok(amount)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
-    use near_sdk::{testing_env, AccountId, Balance};
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{accounts, get_created_receipts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, AccountId, Balance, PromiseOrValue};
 
     fn get_context(predecessor: AccountId) -> VMContextBuilder {
         let mut builder = VMContextBuilder::new();
@@ -237,4 +514,495 @@ mod tests {
             .predecessor_account_id(predecessor);
         builder
     }
+
+    /// The owner is `accounts(1)`, tokens A and B are `accounts(2)` and `accounts(3)`.
+    fn setup() -> (VMContextBuilder, AMM) {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = AMM::new(accounts(1), accounts(2), accounts(3));
+        contract.ft_metadata_callback(&accounts(2), metadata());
+        contract.ft_metadata_callback(&accounts(3), metadata());
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        (context, contract)
+    }
+
+    fn metadata() -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: "ft-1.0.0".to_string(),
+            name: "Example NEAR fungible token".to_string(),
+            symbol: "EXAMPLE".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 24,
+        }
+    }
+
+    fn transfer(
+        context: &mut VMContextBuilder,
+        contract: &mut AMM,
+        token: AccountId,
+        sender: AccountId,
+        amount: Balance,
+        msg: String,
+    ) {
+        testing_env!(context.predecessor_account_id(token).build());
+        contract.ft_on_transfer(sender, U128(amount), msg);
+    }
+
+    fn balance(contract: &AMM, token: &AccountId) -> Balance {
+        contract.tokens.get(token).unwrap().balance
+    }
+
+    #[test]
+    fn test_legacy_deposit_and_swap() {
+        let (mut context, mut contract) = setup();
+        test_utils::enable_swap_history(&mut context, &mut contract, accounts(4));
+        test_utils::seed_main_pool(&mut context, &mut contract, [20_000, 5_000]);
+        assert_eq!(contract.k, 100_000_000);
+
+        let msg = format!("{}:{}", accounts(3), accounts(2));
+
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(4),
+            800,
+            msg,
+        );
+        assert_eq!(balance(&contract, &accounts(2)), 17_242);
+        assert_eq!(balance(&contract, &accounts(3)), 5_800);
+
+        // the owner's deposits aren't swaps
+        assert!(contract
+            .get_swap_history(accounts(1), None, None)
+            .is_empty());
+        let history = contract.get_swap_history(accounts(4), None, None);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].amount_out.0, 2_758);
+        assert_eq!(contract.get_pool_volume(None).total[&accounts(3)].0, 800);
+    }
+
+    #[test]
+    fn test_refresh_metadata_keeps_balances() {
+        let (mut context, mut contract) = setup();
+        test_utils::seed_main_pool(&mut context, &mut contract, [20_000, 5_000]);
+
+        contract.refresh_metadata();
+        let calls: Vec<_> = get_created_receipts()
+            .into_iter()
+            .filter(|receipt| {
+                receipt.actions.iter().any(|action| {
+                    matches!(action, VmAction::FunctionCall { function_name, .. } if function_name == "ft_metadata")
+                })
+            })
+            .map(|receipt| receipt.receiver_id)
+            .collect();
+        assert_eq!(calls, vec![accounts(2), accounts(3)]);
+
+        let mut meta = metadata();
+        meta.name = "Renamed token".to_string();
+        contract.ft_metadata_callback(&accounts(2), meta);
+        let token_info = contract.tokens.get(&accounts(2)).unwrap();
+        assert_eq!(token_info.name(), "Renamed token");
+        assert_eq!(token_info.balance, 20_000);
+    }
+
+    #[test]
+    fn test_token_views() {
+        let (mut context, mut contract) = setup();
+        let token = contract.get_token(accounts(2)).unwrap();
+        assert_eq!(token.metadata.unwrap().symbol, "EXAMPLE");
+        assert_eq!(token.metadata_version, 1);
+        assert_eq!(contract.get_tokens().len(), 2);
+        assert!(contract.get_token(accounts(4)).is_none());
+
+        // the same metadata fetched again only updates the timestamp
+        testing_env!(context.block_timestamp(1_000).build());
+        contract.ft_metadata_callback(&accounts(2), metadata());
+        let token = contract.get_token(accounts(2)).unwrap();
+        assert_eq!(token.metadata_version, 1);
+        assert_eq!(token.metadata_fetched_at.0, 1_000);
+
+        let mut meta = metadata();
+        meta.icon = Some("data:image/svg+xml,".to_string());
+        contract.ft_metadata_callback(&accounts(2), meta);
+        let token = contract.get_token(accounts(2)).unwrap();
+        assert_eq!(token.metadata_version, 2);
+        assert_eq!(
+            token.metadata.unwrap().icon.as_deref(),
+            Some("data:image/svg+xml,")
+        );
+    }
+
+    #[test]
+    fn test_legacy_transfer_from_unknown_contract() {
+        let (mut context, mut contract) = setup();
+        // a contract calls ft_on_transfer directly, pretending to send token B
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        let unused = match contract.ft_on_transfer(
+            accounts(4),
+            U128(1_000_000),
+            format!("{}:{}", accounts(3), accounts(2)),
+        ) {
+            PromiseOrValue::Value(unused) => unused,
+            PromiseOrValue::Promise(_) => unreachable!(),
+        };
+        assert_eq!(unused, U128(1_000_000));
+        assert_eq!(
+            get_logs(),
+            vec!["1000000 fargo are refunded: E_UNKNOWN_TOKEN: The token fargo is not supported"]
+        );
+        assert_eq!(balance(&contract, &accounts(2)), 0);
+    }
+
+    #[test]
+    fn test_legacy_swap_with_buy_token_only() {
+        let (mut context, mut contract) = setup();
+        test_utils::seed_main_pool(&mut context, &mut contract, [20_000, 5_000]);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(4),
+            800,
+            accounts(2).to_string(),
+        );
+        assert_eq!(balance(&contract, &accounts(2)), 17_242);
+        assert_eq!(balance(&contract, &accounts(3)), 5_800);
+    }
+
+    #[test]
+    fn test_json_swap_in_main_pool() {
+        let (mut context, mut contract) = setup();
+        test_utils::seed_main_pool(&mut context, &mut contract, [20_000, 5_000]);
+
+        assert_eq!(
+            contract.get_return(None, accounts(3), U128(800), accounts(2)),
+            U128(2_758)
+        );
+        let msg = format!(
+            r#"{{"Swap": {{"pool_id": null, "token_out": "{}", "min_amount_out": "2758"}}}}"#,
+            accounts(2)
+        );
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(4),
+            800,
+            msg,
+        );
+        assert_eq!(balance(&contract, &accounts(2)), 17_242);
+    }
+
+    #[test]
+    fn test_swap_slippage_is_refunded() {
+        let (mut context, mut contract) = setup();
+        test_utils::seed_main_pool(&mut context, &mut contract, [20_000, 5_000]);
+
+        let msg = format!(
+            r#"{{"Swap": {{"pool_id": null, "token_out": "{}", "min_amount_out": "2759"}}}}"#,
+            accounts(2)
+        );
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let unused = match contract.ft_on_transfer(accounts(4), U128(800), msg) {
+            PromiseOrValue::Value(unused) => unused,
+            PromiseOrValue::Promise(_) => unreachable!(),
+        };
+        assert_eq!(unused, U128(800));
+        assert_eq!(
+            get_logs(),
+            vec!["800 danny are refunded: E_SLIPPAGE: Slippage error: 2758 is less than the minimum 2759"]
+        );
+        assert_eq!(balance(&contract, &accounts(2)), 20_000);
+    }
+
+    #[test]
+    fn test_expired_swap() {
+        let (mut context, mut contract) = setup();
+        test_utils::seed_main_pool(&mut context, &mut contract, [20_000, 5_000]);
+
+        let msg = format!(
+            r#"{{"Swap": {{"pool_id": null, "token_out": "{}", "deadline": "1000"}}}}"#,
+            accounts(2)
+        );
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .block_timestamp(1_001)
+            .build());
+        let unused = match contract.ft_on_transfer(accounts(4), U128(800), msg.clone()) {
+            PromiseOrValue::Value(unused) => unused,
+            PromiseOrValue::Promise(_) => unreachable!(),
+        };
+        assert_eq!(unused, U128(800));
+        assert_eq!(balance(&contract, &accounts(3)), 5_000);
+
+        testing_env!(context.block_timestamp(1_000).build());
+        contract.ft_on_transfer(accounts(4), U128(800), msg);
+        assert_eq!(balance(&contract, &accounts(3)), 5_800);
+    }
+
+    #[test]
+    fn test_swap_with_large_balances() {
+        let (mut context, mut contract) = setup();
+        // 24 decimals, (amount * y) doesn't fit a u128
+        let e24 = 10u128.pow(24);
+        test_utils::seed_main_pool(
+            &mut context,
+            &mut contract,
+            [1_000_000 * e24, 3_000_000 * e24],
+        );
+
+        let msg = format!(
+            r#"{{"Swap": {{"pool_id": null, "token_out": "{}"}}}}"#,
+            accounts(3)
+        );
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(accounts(4), U128(1_000 * e24), msg.clone());
+        assert_eq!(
+            balance(&contract, &accounts(3)),
+            3_000_000 * e24 - 2_997_002_997_002_997_002_997_002_997
+        );
+
+        // the reserve would overflow, the tokens are refunded
+        let unused = match contract.ft_on_transfer(accounts(4), U128(u128::MAX - e24), msg) {
+            PromiseOrValue::Value(unused) => unused,
+            PromiseOrValue::Promise(_) => unreachable!(),
+        };
+        assert_eq!(unused, U128(u128::MAX - e24));
+        assert_eq!(balance(&contract, &accounts(2)), 1_001_000 * e24);
+    }
+
+    #[test]
+    fn test_exact_output_swap_in_main_pool() {
+        let (mut context, mut contract) = setup();
+        test_utils::seed_main_pool(&mut context, &mut contract, [20_000, 5_000]);
+
+        // 5_000 * 2_000 / (20_000 - 2_000), rounded up
+        assert_eq!(
+            contract.get_amount_in(None, accounts(3), U128(2_000), accounts(2)),
+            U128(556)
+        );
+        let msg = format!(
+            r#"{{"Swap": {{"pool_id": null, "token_out": "{}", "amount_out": "2000"}}}}"#,
+            accounts(2)
+        );
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let unused = match contract.ft_on_transfer(accounts(4), U128(1_000), msg) {
+            PromiseOrValue::Value(unused) => unused,
+            PromiseOrValue::Promise(_) => unreachable!(),
+        };
+        assert_eq!(unused, U128(444));
+        assert_eq!(balance(&contract, &accounts(2)), 18_000);
+        assert_eq!(balance(&contract, &accounts(3)), 5_556);
+    }
+
+    #[test]
+    fn test_weighted_pool() {
+        let (mut context, mut contract) = setup();
+        let pool_id =
+            contract.create_weighted_pool(vec![accounts(2), accounts(3)], vec![80, 20], 30);
+        assert_eq!(contract.get_number_of_pools(), 1);
+
+        let msg = format!(
+            r#"{{"JoinPool": {{"pool_id": {}, "min_shares": "0"}}}}"#,
+            pool_id
+        );
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(1),
+            400_000,
+            msg.clone(),
+        );
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(1),
+            100_000,
+            msg.clone(),
+        );
+        let owner_shares = contract.get_pool_shares(pool_id, accounts(1));
+        assert_eq!(owner_shares.0, weighted_pool::INIT_SHARES_SUPPLY);
+        assert_eq!(
+            contract.get_spot_price(pool_id, accounts(2), accounts(3)),
+            U128(fixed_point::ONE)
+        );
+
+        // a single-token join by a user
+        let quote = contract.get_join_shares(pool_id, accounts(2), U128(10_000));
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            10_000,
+            msg,
+        );
+        assert_eq!(contract.get_pool_shares(pool_id, accounts(4)), quote);
+
+        // a swap through the weighted pool
+        let quote = contract.get_return(Some(pool_id), accounts(3), U128(1_000), accounts(2));
+        let msg = format!(
+            r#"{{"Swap": {{"pool_id": {}, "token_out": "{}", "min_amount_out": null}}}}"#,
+            pool_id,
+            accounts(2)
+        );
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(5),
+            1_000,
+            msg.clone(),
+        );
+        // more than 30% of the balance is refunded
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(5),
+            50_000,
+            msg,
+        );
+        assert_eq!(
+            get_logs(),
+            vec!["50000 danny are refunded: E_INSUFFICIENT_LIQUIDITY: Not enough liquidity in the pool"]
+        );
+        let view = match contract.get_pool(pool_id) {
+            PoolView::Weighted(view) => view,
+            _ => unreachable!(),
+        };
+        assert_eq!(view.balances[0].0, 410_000 - quote.0);
+        assert_eq!(view.balances[1].0, 101_000);
+
+        // the user exits into the other token
+        let shares = contract.get_pool_shares(pool_id, accounts(4));
+        let quote = contract.get_exit_amount(pool_id, shares, accounts(3));
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        let amount_out = contract.exit_pool(pool_id, shares, accounts(3), quote);
+        assert_eq!(amount_out, quote);
+        assert_eq!(contract.get_pool_shares(pool_id, accounts(4)), U128(0));
+        // a failed transfer is credited to the deposits
+        assert!(get_created_receipts().iter().any(|receipt| {
+            receipt.actions.iter().any(|action| {
+                matches!(action, VmAction::FunctionCall { function_name, .. } if function_name == "withdraw_callback")
+            })
+        }));
+    }
+
+    #[test]
+    fn test_join_before_seeding() {
+        let (mut context, mut contract) = setup();
+        let pool_id =
+            contract.create_weighted_pool(vec![accounts(2), accounts(3)], vec![50, 50], 30);
+        let msg = format!(
+            r#"{{"JoinPool": {{"pool_id": {}, "min_shares": "0"}}}}"#,
+            pool_id
+        );
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            1_000,
+            msg,
+        );
+        assert_eq!(
+            get_logs(),
+            vec!["1000 charlie are refunded: E_UNAUTHORIZED: The pool is not seeded yet"]
+        );
+        let mut pool = contract.get_pool_or_panic(pool_id);
+        assert_eq!(pool.as_weighted().balances, vec![0, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_create_pool_not_owner() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.create_weighted_pool(vec![accounts(2), accounts(3)], vec![50, 50], 30);
+    }
+
+    #[test]
+    fn test_concentrated_pool() {
+        let (mut context, mut contract) = setup();
+        let pool_id = contract.create_concentrated_pool(vec![accounts(2), accounts(3)], 30, 10, 0);
+
+        test_utils::register(&mut context, &mut contract, accounts(4));
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            1_000_000,
+            r#""Deposit""#.to_string(),
+        );
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(4),
+            1_000_000,
+            r#""Deposit""#.to_string(),
+        );
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        let position_id = contract.open_position(
+            pool_id,
+            -100,
+            100,
+            vec![U128(1_000_000), U128(1_000_000)],
+            vec![U128(0), U128(0)],
+        );
+        // the price is 1, so one of the tokens is fully used and the other almost
+        let deposits = contract.get_deposits(accounts(4));
+        assert!(deposits.values().all(|amount| amount.0 < 100));
+
+        let quote = contract.get_return(Some(pool_id), accounts(2), U128(10_000), accounts(3));
+        let msg = format!(
+            r#"{{"Swap": {{"pool_id": {}, "token_out": "{}", "min_amount_out": "{}"}}}}"#,
+            pool_id,
+            accounts(3),
+            quote.0
+        );
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(5),
+            10_000,
+            msg,
+        );
+        let position = contract.get_position(pool_id, position_id);
+        assert!(position.fees[0].0 > 0);
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        let amounts = contract.decrease_position(pool_id, position_id, position.liquidity);
+        let deposits = contract.get_deposits(accounts(4));
+        assert!(deposits[&accounts(2)].0 >= amounts[0].0);
+        assert!(amounts[0].0 + amounts[1].0 > 2_000_000 - 200);
+        contract.withdraw(accounts(2), deposits[&accounts(2)]);
+        assert!(!contract
+            .get_deposits(accounts(4))
+            .contains_key(&accounts(2)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough")]
+    fn test_open_position_without_deposit() {
+        let (mut context, mut contract) = setup();
+        let pool_id = contract.create_concentrated_pool(vec![accounts(2), accounts(3)], 30, 10, 0);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.open_position(
+            pool_id,
+            -100,
+            100,
+            vec![U128(1_000), U128(1_000)],
+            vec![U128(0), U128(0)],
+        );
+    }
 }
@@ -0,0 +1,479 @@
+//! Limit orders on the main pool.
+//!
+//! A `PlaceLimitOrder` transfer escrows the tokens with the least output the owner accepts for
+//! them. A swap on the main pool that buys a token makes it more expensive, so it's followed by
+//! a check of the orders that sell it: an order whose whole amount now swaps for at least its
+//! minimum, after the keeper reward, is filled right away. The reward goes to the account whose
+//! swap crossed the price, credited to its deposits if it's registered; otherwise the order's
+//! owner keeps it.
+//!
+//! Orders are kept in a book sorted by the token they sell and their price, so a swap only
+//! visits the orders whose price it crossed. An account can have [`MAX_ORDERS_PER_ACCOUNT`]
+//! open orders, each of at least 1/[`MIN_ORDER_DIVISOR`] of the pool's reserve of the token.
+
+use std::ops::Bound;
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::Serialize;
+use near_sdk::{env, log, near_bindgen, AccountId, Balance};
+
+use crate::fixed_point::{ONE, U256};
+use crate::math;
+use crate::*;
+
+/// Keeper reward in basis points of the output of a limit or DCA order.
+pub const KEEPER_REWARD: u128 = 10;
+const REWARD_DIVISOR: u128 = 10_000;
+pub const MAX_ORDERS_PER_ACCOUNT: u32 = 10;
+/// An order sells at least this part of the pool's reserve of the token, 0.01%.
+pub const MIN_ORDER_DIVISOR: u128 = 10_000;
+/// Orders filled after one swap at most, the rest waits for the next swap.
+const MAX_FILLS_PER_SWAP: usize = 5;
+/// Crossed orders checked after one swap at most, filled or not.
+const MAX_CHECKS_PER_SWAP: usize = 20;
+
+/// The token an order sells, its price and its id.
+pub(crate) type OrderBookKey = (AccountId, u128, u64);
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LimitOrder {
+    pub order_id: u64,
+    pub owner_id: AccountId,
+    pub token_in: AccountId,
+    pub amount_in: U128,
+    pub token_out: AccountId,
+    /// The target price: the least output for the whole `amount_in`, after the keeper reward.
+    pub min_amount_out: U128,
+}
+
+impl LimitOrder {
+    /// The order's place in the book. The price is the output per input scaled by `ONE`,
+    /// rounded up and capped at `u128::MAX`.
+    fn book_key(&self) -> OrderBookKey {
+        let price = (U256::from(self.min_amount_out.0) * U256::from(ONE)
+            + U256::from(self.amount_in.0 - 1))
+            / U256::from(self.amount_in.0);
+        let price = price.min(U256::from(u128::MAX)).as_u128();
+        (self.token_in.clone(), price, self.order_id)
+    }
+}
+
+#[near_bindgen]
+impl AMM {
+    pub fn get_limit_order(&self, order_id: u64) -> Option<LimitOrder> {
+        self.limit_orders.get(&order_id)
+    }
+
+    pub fn get_limit_orders(&self, account_id: AccountId) -> Vec<LimitOrder> {
+        self.limit_orders
+            .values()
+            .filter(|order| order.owner_id == account_id)
+            .collect()
+    }
+
+    /// Cancels an open order and refunds its tokens.
+    pub fn cancel_order(&mut self, order_id: u64) {
+        let order = self
+            .limit_orders
+            .get(&order_id)
+            .unwrap_or_else(|| panic!("No limit order {}", order_id));
+        if order.owner_id != env::predecessor_account_id() {
+            panic!(
+                "{}",
+                AmmError::Unauthorized("Only the owner of the order can cancel it".to_string())
+            );
+        }
+        self.remove_limit_order(&order);
+        log!("Limit order {} is cancelled", order_id);
+        self.internal_refund(order.owner_id, order.token_in, order.amount_in.0);
+    }
+}
+
+impl AMM {
+    pub(crate) fn place_limit_order(
+        &mut self,
+        owner_id: AccountId,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: AccountId,
+        min_amount_out: Balance,
+    ) -> AmmResult<u64> {
+        let reserve = self.main_token(token_in)?.balance;
+        self.main_token(&token_out)?;
+        if token_in == &token_out {
+            return Err(AmmError::BadMsg(
+                "the order must sell one main pool token for the other".to_string(),
+            ));
+        }
+        if min_amount_out == 0 {
+            return Err(AmmError::BadMsg(
+                "the order must have a min_amount_out".to_string(),
+            ));
+        }
+        if amount_in == 0
+            || U256::from(amount_in) * U256::from(MIN_ORDER_DIVISOR) < U256::from(reserve)
+        {
+            return Err(AmmError::BadMsg(format!(
+                "the order must sell at least {} {}",
+                math::mul_div_up(reserve, 1, MIN_ORDER_DIVISOR)?,
+                token_in
+            )));
+        }
+        let count = self.limit_order_counts.get(&owner_id).unwrap_or(0);
+        if count >= MAX_ORDERS_PER_ACCOUNT {
+            return Err(AmmError::BadMsg(format!(
+                "an account can't have more than {} open orders",
+                MAX_ORDERS_PER_ACCOUNT
+            )));
+        }
+        let order_id = self.next_order_id;
+        self.next_order_id += 1;
+        let order = LimitOrder {
+            order_id,
+            owner_id,
+            token_in: token_in.clone(),
+            amount_in: U128(amount_in),
+            token_out,
+            min_amount_out: U128(min_amount_out),
+        };
+        self.limit_orders.insert(&order_id, &order);
+        self.limit_order_book.insert(&order.book_key(), &());
+        self.limit_order_counts
+            .insert(&order.owner_id, &(count + 1));
+        log!("Limit order {} is placed", order_id);
+        Ok(order_id)
+    }
+
+    /// Fills the orders selling `token_id` that a swap which bought it made fillable, from the
+    /// lowest target price. Only orders priced at most the pool's price are visited, the others
+    /// can't be filled. `keeper_id` gets the rewards.
+    pub(crate) fn fill_limit_orders(&mut self, keeper_id: &AccountId, token_id: &AccountId) {
+        let price = match self.main_pool_price(token_id) {
+            Some(price) => price,
+            None => return,
+        };
+        let crossed: Vec<u64> = self
+            .limit_order_book
+            .range((
+                Bound::Included((token_id.clone(), 0, 0)),
+                Bound::Included((token_id.clone(), price, u64::MAX)),
+            ))
+            .take(MAX_CHECKS_PER_SWAP)
+            .map(|((_, _, order_id), _)| order_id)
+            .collect();
+
+        let mut filled = 0;
+        for order_id in crossed {
+            if filled == MAX_FILLS_PER_SWAP {
+                break;
+            }
+            let order = self.limit_orders.get(&order_id).unwrap();
+            if self.try_fill(keeper_id, &order) {
+                filled += 1;
+            }
+        }
+    }
+
+    /// The main pool's output per `token_id` sold, scaled by `ONE`, before the swap fee.
+    fn main_pool_price(&self, token_id: &AccountId) -> Option<u128> {
+        let token_ids = self.get_main_pool_tokens();
+        let idx = token_ids.iter().position(|id| id == token_id)?;
+        let reserve_in = self.tokens.get(token_id)?.balance;
+        let reserve_out = self.tokens.get(&token_ids[1 - idx])?.balance;
+        if reserve_in == 0 {
+            return None;
+        }
+        let price = U256::from(reserve_out) * U256::from(ONE) / U256::from(reserve_in);
+        Some(price.min(U256::from(u128::MAX)).as_u128())
+    }
+
+    fn remove_limit_order(&mut self, order: &LimitOrder) {
+        self.limit_orders.remove(&order.order_id);
+        self.limit_order_book.remove(&order.book_key());
+        let count = self.limit_order_counts.get(&order.owner_id).unwrap_or(1);
+        if count <= 1 {
+            self.limit_order_counts.remove(&order.owner_id);
+        } else {
+            self.limit_order_counts
+                .insert(&order.owner_id, &(count - 1));
+        }
+    }
+
+    fn try_fill(&mut self, keeper_id: &AccountId, order: &LimitOrder) -> bool {
+        // the quote and the swap see the same reserves
+        self.settle_virtual_orders();
+        let quoted_out =
+            match self.get_main_return(&order.token_in, order.amount_in.0, &order.token_out) {
+                Ok(amount_out) => amount_out,
+                Err(_) => return false,
+            };
+        let quoted_reward = match keeper_reward(quoted_out) {
+            Ok(reward) => reward,
+            Err(_) => return false,
+        };
+        if quoted_out - quoted_reward < order.min_amount_out.0 {
+            return false;
+        }
+        let amount_out = match self.swap_main(&order.token_in, order.amount_in.0, &order.token_out)
+        {
+            Ok(amount_out) => amount_out,
+            Err(_) => return false,
+        };
+        let reward = self.keeper_reward_for(keeper_id, amount_out);
+        self.remove_limit_order(order);
+        log!(
+            "Limit order {} is filled: {} {} for {} {}",
+            order.order_id,
+            order.amount_in.0,
+            order.token_in,
+            amount_out - reward,
+            order.token_out
+        );
+        self.record_swap(
+            &order.owner_id,
+            None,
+            &order.token_in,
+            order.amount_in.0,
+            &order.token_out,
+            amount_out - reward,
+        );
+        if reward > 0 {
+            self.internal_deposit(keeper_id, &order.token_out, reward);
+        }
+        self.internal_payout(
+            order.owner_id.clone(),
+            order.owner_id.clone(),
+            order.token_out.clone(),
+            amount_out - reward,
+            None,
+        );
+        true
+    }
+
+    /// The reward of `keeper_id` from the output of an order. Only registered accounts can be
+    /// credited, the order's owner keeps the reward of the others.
+    pub(crate) fn keeper_reward_for(&self, keeper_id: &AccountId, amount_out: Balance) -> Balance {
+        if self.is_registered(keeper_id) {
+            keeper_reward(amount_out).or_panic()
+        } else {
+            0
+        }
+    }
+}
+
+/// The keeper's part of a swap output, rounded down.
+pub(crate) fn keeper_reward(amount_out: Balance) -> MathResult<Balance> {
+    math::mul_div_down(amount_out, KEEPER_REWARD, REWARD_DIVISOR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{enable_swap_history, register, setup_main_pool as setup, transfer};
+    use near_sdk::test_utils::{accounts, get_logs};
+    use near_sdk::testing_env;
+
+    fn place_msg(token_out: AccountId, min_amount_out: Balance) -> String {
+        format!(
+            r#"{{"PlaceLimitOrder": {{"token_out": "{}", "min_amount_out": "{}"}}}}"#,
+            token_out, min_amount_out
+        )
+    }
+
+    fn swap_msg(token_out: AccountId) -> String {
+        format!(r#"{{"Swap": {{"token_out": "{}"}}}}"#, token_out)
+    }
+
+    #[test]
+    fn test_order_is_filled_when_the_price_crosses() {
+        let (mut context, mut contract) = setup();
+        enable_swap_history(&mut context, &mut contract, accounts(4));
+        register(&mut context, &mut contract, accounts(5));
+        // sells 10_000 B for at least 11_000 A, the pool pays about 9_900 now
+        let msg = place_msg(accounts(2), 11_000);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(4),
+            10_000,
+            msg,
+        );
+        assert_eq!(contract.get_limit_orders(accounts(4)).len(), 1);
+
+        // buying A makes it more expensive and B cheaper
+        let msg = swap_msg(accounts(2));
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(5),
+            10_000,
+            msg,
+        );
+        assert!(contract.get_limit_order(0).is_some());
+
+        // buying B makes it more expensive, enough to fill the order
+        let msg = swap_msg(accounts(3));
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(5),
+            200_000,
+            msg,
+        );
+        assert!(contract.get_limit_order(0).is_none());
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.starts_with("Limit order 0 is filled: 10000 danny for ")));
+        assert_eq!(contract.get_swap_history(accounts(4), None, None).len(), 1);
+        // the keeper reward
+        assert!(contract.get_deposits(accounts(5))[&accounts(2)].0 > 0);
+    }
+
+    #[test]
+    fn test_cancel_order() {
+        let (mut context, mut contract) = setup();
+        let msg = place_msg(accounts(2), 1_000);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(4),
+            100,
+            msg,
+        );
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.cancel_order(0);
+        assert!(contract.get_limit_orders(accounts(4)).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "E_UNAUTHORIZED: Only the owner of the order can cancel it")]
+    fn test_cancel_order_not_owner() {
+        let (mut context, mut contract) = setup();
+        let msg = place_msg(accounts(2), 1_000);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(4),
+            100,
+            msg,
+        );
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.cancel_order(0);
+    }
+
+    #[test]
+    fn test_order_for_the_same_token_is_refunded() {
+        let (mut context, mut contract) = setup();
+        let msg = place_msg(accounts(3), 1_000);
+        let unused = transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(4),
+            100,
+            msg,
+        );
+        assert_eq!(unused, 100);
+        assert!(contract.get_limit_orders(accounts(4)).is_empty());
+    }
+
+    #[test]
+    fn test_only_crossed_orders_are_visited() {
+        let (mut context, mut contract) = setup();
+        // asks for 1.1 and 0.5 A per B, the pool pays 1
+        for min_amount_out in [11_000, 5_000] {
+            let msg = place_msg(accounts(2), min_amount_out);
+            transfer(
+                &mut context,
+                &mut contract,
+                accounts(3),
+                accounts(4),
+                10_000,
+                msg,
+            );
+        }
+        let prices: Vec<u128> = contract
+            .limit_order_book
+            .iter()
+            .map(|((_, price, _), _)| price)
+            .collect();
+        assert_eq!(prices, vec![ONE / 2, ONE * 11 / 10]);
+
+        let msg = swap_msg(accounts(3));
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(5),
+            100,
+            msg,
+        );
+        assert!(contract.get_limit_order(1).is_none());
+        assert!(contract.get_limit_order(0).is_some());
+        assert_eq!(contract.limit_order_book.len(), 1);
+        assert_eq!(contract.limit_order_counts.get(&accounts(4)), Some(1));
+    }
+
+    #[test]
+    fn test_order_limits() {
+        let (mut context, mut contract) = setup();
+        let unused = transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(4),
+            99,
+            place_msg(accounts(2), 1_000),
+        );
+        assert_eq!(unused, 99);
+        assert_eq!(
+            get_logs(),
+            vec!["99 danny are refunded: E_BAD_MSG: Invalid msg: the order must sell at least 100 danny"]
+        );
+
+        for _ in 0..MAX_ORDERS_PER_ACCOUNT {
+            let unused = transfer(
+                &mut context,
+                &mut contract,
+                accounts(3),
+                accounts(4),
+                100,
+                place_msg(accounts(2), 1_000),
+            );
+            assert_eq!(unused, 0);
+        }
+        let unused = transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(4),
+            100,
+            place_msg(accounts(2), 1_000),
+        );
+        assert_eq!(unused, 100);
+        // another account still can
+        let unused = transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(5),
+            100,
+            place_msg(accounts(2), 1_000),
+        );
+        assert_eq!(unused, 0);
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.cancel_order(0);
+        assert_eq!(
+            contract.limit_order_counts.get(&accounts(4)),
+            Some(MAX_ORDERS_PER_ACCOUNT - 1)
+        );
+    }
+}
@@ -0,0 +1,789 @@
+use std::collections::HashMap;
+
+use near_sdk::json_types::U128;
+use near_sdk::{env, log, near_bindgen, AccountId, Balance};
+
+use crate::error::OrPanic;
+use crate::fixed_point::U256;
+use crate::math;
+use crate::*;
+
+/// Fee of the swap inside `AddLiquiditySingle`, in basis points. It stays in the pool.
+pub const ZAP_SWAP_FEE: u128 = 30;
+const FEE_DIVISOR: u128 = 10_000;
+/// Shares of the first mint that are locked forever with the contract account, so that the
+/// price of a share can't be inflated by whoever mints the first few of them.
+pub const MINIMUM_LIQUIDITY: Balance = 1_000;
+
+#[near_bindgen]
+impl AMM {
+    /// Tokens sent with an `AddLiquidity` msg and not yet added to the main pool.
+    pub fn get_pending_liquidity(&self, account_id: AccountId) -> HashMap<AccountId, U128> {
+        self.pending_liquidity
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(token_id, amount)| (token_id, U128(amount)))
+            .collect()
+    }
+
+    /// Adds up to `amounts` of the caller's pending tokens to the main pool, in the order of
+    /// `get_main_pool_tokens`. As much is used as the pool's current ratio allows, the rest of
+    /// the pending tokens is refunded.
+    pub fn add_liquidity(&mut self, amounts: Vec<U128>, min_shares: U128) -> U128 {
+        self.assert_no_flash_loan();
+        self.settle_virtual_orders();
+        self.mint_owner_shares();
+        let account_id = env::predecessor_account_id();
+        let token_ids = self.get_main_pool_tokens();
+        assert_eq!(
+            amounts.len(),
+            token_ids.len(),
+            "Expected amounts of 2 tokens"
+        );
+        let mut pending = self.pending_liquidity.get(&account_id).unwrap_or_default();
+        for (token_id, amount) in token_ids.iter().zip(&amounts) {
+            let available = pending.get(token_id).copied().unwrap_or(0);
+            assert!(
+                amount.0 <= available,
+                "Not enough {} pending: {} < {}",
+                token_id,
+                available,
+                amount.0
+            );
+        }
+
+        let mut reserves: Vec<Balance> = token_ids
+            .iter()
+            .map(|token_id| self.tokens.get(token_id).unwrap().balance)
+            .collect();
+
+        let (shares, used) = if self.main_shares_total_supply == 0 {
+            let amounts: Vec<Balance> = amounts.iter().map(|amount| amount.0).collect();
+            let shares = self.lock_minimum_liquidity(initial_shares(&amounts));
+            (shares, amounts)
+        } else {
+            // shares round down and the amounts they take round up, both in the pool's favour
+            let total_supply = self.main_shares_total_supply;
+            let shares = amounts
+                .iter()
+                .zip(&reserves)
+                .map(|(amount, reserve)| {
+                    math::mul_div_down(amount.0, total_supply, *reserve).or_panic()
+                })
+                .min()
+                .unwrap();
+            let used = reserves
+                .iter()
+                .map(|reserve| math::mul_div_up(shares, *reserve, total_supply).or_panic())
+                .collect();
+            (shares, used)
+        };
+        assert!(shares > 0, "The deposit is too small to mint any shares");
+        if shares < min_shares.0 {
+            panic!("{}", AmmError::shares_below_minimum(shares, min_shares.0));
+        }
+
+        for ((token_id, reserve), amount) in token_ids.iter().zip(&mut reserves).zip(&used) {
+            *reserve += amount;
+            let mut token_info = self.tokens.get(token_id).unwrap();
+            token_info.balance = *reserve;
+            self.tokens.insert(token_id, &token_info);
+            if let Some(pending) = pending.get_mut(token_id) {
+                *pending -= amount;
+            }
+        }
+        self.k = self.main_pool_k();
+        self.mint_main_shares(&account_id, shares);
+        self.pending_liquidity.remove(&account_id);
+        for (token_id, amount) in pending {
+            if amount > 0 {
+                self.internal_refund(account_id.clone(), token_id, amount);
+            }
+        }
+        log!(
+            "@{} added {:?} to the main pool for {} shares",
+            account_id,
+            used,
+            shares
+        );
+        U128(shares)
+    }
+
+    /// Burns `shares` of the main pool and withdraws both tokens in proportion.
+    pub fn remove_liquidity(&mut self, shares: U128, min_amounts: Vec<U128>) -> Vec<U128> {
+        let account_id = env::predecessor_account_id();
+        let token_ids = self.get_main_pool_tokens();
+        assert_eq!(
+            min_amounts.len(),
+            token_ids.len(),
+            "Expected amounts of 2 tokens"
+        );
+        let amounts = self.burn_main_shares(&account_id, shares.0);
+        for (amount, min_amount) in amounts.iter().zip(min_amounts) {
+            if *amount < min_amount.0 {
+                panic!("{}", AmmError::below_minimum(*amount, min_amount.0));
+            }
+        }
+
+        for (token_id, amount) in token_ids.into_iter().zip(&amounts) {
+            if *amount > 0 {
+                self.internal_payout(
+                    account_id.clone(),
+                    account_id.clone(),
+                    token_id,
+                    *amount,
+                    None,
+                );
+            }
+        }
+        amounts.into_iter().map(U128).collect()
+    }
+
+    /// Burns `shares` of the main pool and withdraws their value in `token_out` only: the other
+    /// token is swapped through the pool.
+    pub fn remove_liquidity_one_token(
+        &mut self,
+        shares: U128,
+        token_out: AccountId,
+        min_amount: U128,
+    ) -> U128 {
+        let account_id = env::predecessor_account_id();
+        let token_ids = self.get_main_pool_tokens();
+        let idx_out = token_ids
+            .iter()
+            .position(|token_id| token_id == &token_out)
+            .unwrap_or_else(|| panic!("{}", AmmError::UnknownToken(token_out.clone())));
+        let amounts = self.burn_main_shares(&account_id, shares.0);
+
+        let other_token = &token_ids[1 - idx_out];
+        let amount_in = amounts[1 - idx_out];
+        let swapped = self
+            .swap_main(other_token, amount_in, &token_out)
+            .or_panic();
+        let amount = amounts[idx_out] + swapped;
+        if amount < min_amount.0 {
+            panic!("{}", AmmError::below_minimum(amount, min_amount.0));
+        }
+        if amount_in > 0 {
+            self.record_swap(
+                &account_id,
+                None,
+                other_token,
+                amount_in,
+                &token_out,
+                swapped,
+            );
+            self.fill_limit_orders(&account_id, &token_out);
+        }
+        log!(
+            "@{} removed {} shares from the main pool for {} {}, {} {} swapped for {}",
+            account_id,
+            shares.0,
+            amount,
+            token_out,
+            amounts[1 - idx_out],
+            other_token,
+            swapped
+        );
+
+        self.internal_payout(account_id.clone(), account_id, token_out, amount, None);
+        U128(amount)
+    }
+
+    /// Transfers back all the caller's pending tokens.
+    pub fn withdraw_pending_liquidity(&mut self) {
+        let account_id = env::predecessor_account_id();
+        let pending = self
+            .pending_liquidity
+            .remove(&account_id)
+            .unwrap_or_default();
+        for (token_id, amount) in pending {
+            self.internal_refund(account_id.clone(), token_id, amount);
+        }
+    }
+
+    /// Tokens of the main pool, in the order `add_liquidity` and `remove_liquidity` take amounts.
+    pub fn get_main_pool_tokens(&self) -> Vec<AccountId> {
+        self.tokens.keys().collect()
+    }
+
+    pub fn get_main_pool_shares(&self, account_id: AccountId) -> U128 {
+        U128(self.main_shares.get(&account_id).unwrap_or(0))
+    }
+
+    pub fn get_main_pool_total_shares(&self) -> U128 {
+        U128(self.main_shares_total_supply)
+    }
+}
+
+impl AMM {
+    /// Adds liquidity with one token: swaps the part of it that leaves the rest in the pool's
+    /// ratio and mints shares for both. Returns the shares.
+    pub(crate) fn add_liquidity_single(
+        &mut self,
+        account_id: &AccountId,
+        token_in: &AccountId,
+        amount: Balance,
+        min_shares: Balance,
+    ) -> AmmResult<Balance> {
+        self.check_no_flash_loan()?;
+        let token_ids = self.get_main_pool_tokens();
+        let idx_in = token_ids
+            .iter()
+            .position(|token_id| token_id == token_in)
+            .ok_or_else(|| AmmError::UnknownToken(token_in.clone()))?;
+        self.settle_virtual_orders();
+        self.mint_owner_shares();
+        let token_out = &token_ids[1 - idx_in];
+        let mut info_in = self.tokens.get(token_in).unwrap();
+        let (x, y) = (info_in.balance, self.tokens.get(token_out).unwrap().balance);
+        if x == 0 || y == 0 {
+            return Err(AmmError::InsufficientLiquidity);
+        }
+        let swapped = zap_swap_amount(x, amount);
+        let amount_out =
+            (U256::from(swapped) * U256::from(FEE_DIVISOR - ZAP_SWAP_FEE) * U256::from(y)
+                / (U256::from(x) * U256::from(FEE_DIVISOR)
+                    + U256::from(swapped) * U256::from(FEE_DIVISOR - ZAP_SWAP_FEE)))
+            .as_u128();
+        let fee = swapped * ZAP_SWAP_FEE / FEE_DIVISOR;
+
+        // The output goes back into the pool together with the rest of the input, so only the
+        // input token's reserve changes.
+        let total_supply = U256::from(self.main_shares_total_supply);
+        let shares = (U256::from(amount - swapped) * total_supply / U256::from(x + swapped))
+            .min(U256::from(amount_out) * total_supply / U256::from(y - amount_out))
+            .as_u128();
+        if shares == 0 {
+            return Err(AmmError::Slippage(
+                "the deposit is too small to mint any shares".to_string(),
+            ));
+        }
+        if shares < min_shares {
+            return Err(AmmError::shares_below_minimum(shares, min_shares));
+        }
+
+        info_in.balance = math::checked_add(info_in.balance, amount)?;
+        self.tokens.insert(token_in, &info_in);
+        self.k = self.main_pool_k();
+        self.mint_main_shares(account_id, shares);
+        log!(
+            "@{} added {} {} to the main pool for {} shares, {} of it swapped for {} {} with the fee {}",
+            account_id,
+            amount,
+            token_in,
+            shares,
+            swapped,
+            amount_out,
+            token_out,
+            fee
+        );
+        Ok(shares)
+    }
+
+    /// Reserves funded by the owner's legacy transfers belong to the owner: they are accounted
+    /// with shares before the first deposit mints any. A seed that can't mint more than
+    /// `MINIMUM_LIQUIDITY` shares, e.g. with only one of the tokens, goes to the owner's pending
+    /// liquidity instead and the first deposit starts the pool.
+    fn mint_owner_shares(&mut self) {
+        let reserves = self.main_pool_reserves();
+        if self.main_shares_total_supply > 0 || reserves.iter().all(|reserve| *reserve == 0) {
+            return;
+        }
+        let owner_id = self.owner_id.clone();
+        let shares = initial_shares(&reserves);
+        if shares > MINIMUM_LIQUIDITY {
+            let shares = self.lock_minimum_liquidity(shares);
+            self.mint_main_shares(&owner_id, shares);
+            return;
+        }
+        for (token_id, reserve) in self.get_main_pool_tokens().iter().zip(reserves) {
+            if reserve > 0 {
+                self.add_pending_liquidity(&owner_id, token_id, reserve)
+                    .or_panic();
+                let mut token_info = self.tokens.get(token_id).unwrap();
+                token_info.balance = 0;
+                self.tokens.insert(token_id, &token_info);
+                log!(
+                    "The seed of {} {} can't start the main pool, it's pending for @{}",
+                    reserve,
+                    token_id,
+                    owner_id
+                );
+            }
+        }
+        self.k = self.main_pool_k();
+    }
+
+    /// Locks `MINIMUM_LIQUIDITY` of the first shares minted and returns the rest of them.
+    fn lock_minimum_liquidity(&mut self, shares: Balance) -> Balance {
+        assert!(
+            shares > MINIMUM_LIQUIDITY,
+            "The first deposit must mint more than {} shares, got {}",
+            MINIMUM_LIQUIDITY,
+            shares
+        );
+        self.mint_main_shares(&env::current_account_id(), MINIMUM_LIQUIDITY);
+        shares - MINIMUM_LIQUIDITY
+    }
+
+    /// Holds tokens sent with an `AddLiquidity` msg until `add_liquidity` is called.
+    pub(crate) fn add_pending_liquidity(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) -> AmmResult<()> {
+        self.main_token(token_id)?;
+        let mut pending = self.pending_liquidity.get(account_id).unwrap_or_default();
+        *pending.entry(token_id.clone()).or_default() += amount;
+        self.pending_liquidity.insert(account_id, &pending);
+        Ok(())
+    }
+
+    /// Burns `shares` and takes their part of both reserves out of the pool. Returns the
+    /// amounts, in the order of `get_main_pool_tokens`.
+    fn burn_main_shares(&mut self, account_id: &AccountId, shares: Balance) -> Vec<Balance> {
+        self.assert_no_flash_loan();
+        self.settle_virtual_orders();
+        let balance = self.main_shares.get(account_id).unwrap_or(0);
+        assert!(
+            shares > 0 && shares <= balance,
+            "Not enough shares: {} < {}",
+            balance,
+            shares
+        );
+        let mut amounts = vec![];
+        for token_id in self.get_main_pool_tokens() {
+            let mut token_info = self.tokens.get(&token_id).unwrap();
+            let amount =
+                math::mul_div_down(token_info.balance, shares, self.main_shares_total_supply)
+                    .or_panic();
+            token_info.balance -= amount;
+            self.tokens.insert(&token_id, &token_info);
+            amounts.push(amount);
+        }
+        self.main_shares.insert(account_id, &(balance - shares));
+        self.main_shares_total_supply -= shares;
+        self.k = self.main_pool_k();
+        amounts
+    }
+
+    fn mint_main_shares(&mut self, account_id: &AccountId, shares: Balance) {
+        let balance = self.main_shares.get(account_id).unwrap_or(0);
+        self.main_shares.insert(account_id, &(balance + shares));
+        self.main_shares_total_supply += shares;
+    }
+}
+
+/// The part of `amount` to swap so that the rest and the output are in the ratio of the pool
+/// after the swap:
+/// `(sqrt(((2 - f) * x)^2 + 4 * (1 - f) * x * amount) - (2 - f) * x) / (2 * (1 - f))`
+fn zap_swap_amount(reserve: Balance, amount: Balance) -> Balance {
+    let fee_complement = U256::from(FEE_DIVISOR - ZAP_SWAP_FEE);
+    let b = U256::from(2 * FEE_DIVISOR - ZAP_SWAP_FEE) * U256::from(reserve);
+    let discriminant = b * b
+        + U256::from(4)
+            * fee_complement
+            * U256::from(FEE_DIVISOR)
+            * U256::from(reserve)
+            * U256::from(amount);
+    ((discriminant.integer_sqrt() - b) / (U256::from(2) * fee_complement)).as_u128()
+}
+
+/// Shares of the first deposit: the geometric mean of the amounts.
+fn initial_shares(amounts: &[Balance]) -> Balance {
+    (U256::from(amounts[0]) * U256::from(amounts[1]))
+        .integer_sqrt()
+        .as_u128()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseOrValue};
+
+    fn context(predecessor: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor);
+        builder
+    }
+
+    /// The owner `accounts(1)`, `accounts(4)` and `accounts(5)` are registered.
+    fn setup() -> AMM {
+        testing_env!(context(accounts(1)).build());
+        let mut contract = AMM::new(accounts(1), accounts(2), accounts(3));
+        for account in [accounts(1), accounts(4), accounts(5)] {
+            test_utils::register(&mut context(account.clone()), &mut contract, account);
+        }
+        contract
+    }
+
+    fn send(contract: &mut AMM, account: AccountId, token: AccountId, amount: Balance) {
+        testing_env!(context(token).build());
+        contract.ft_on_transfer(account, U128(amount), "\"AddLiquidity\"".to_string());
+    }
+
+    fn balance(contract: &AMM, token: &AccountId) -> Balance {
+        contract.tokens.get(token).unwrap().balance
+    }
+
+    #[test]
+    fn test_pending_until_add_liquidity() {
+        let mut contract = setup();
+        assert_eq!(
+            contract.get_main_pool_tokens(),
+            vec![accounts(2), accounts(3)]
+        );
+        send(&mut contract, accounts(1), accounts(2), 20_000);
+        assert_eq!(balance(&contract, &accounts(2)), 0);
+        send(&mut contract, accounts(1), accounts(3), 5_000);
+        assert_eq!(
+            contract.get_pending_liquidity(accounts(1))[&accounts(3)],
+            U128(5_000)
+        );
+
+        testing_env!(context(accounts(1)).build());
+        let shares = contract.add_liquidity(vec![U128(20_000), U128(5_000)], U128(9_000));
+        assert_eq!(shares, U128(10_000 - MINIMUM_LIQUIDITY));
+        assert_eq!(
+            contract.get_main_pool_shares(accounts(0)),
+            U128(MINIMUM_LIQUIDITY)
+        );
+        assert_eq!(contract.get_main_pool_total_shares(), U128(10_000));
+        assert_eq!(balance(&contract, &accounts(2)), 20_000);
+        assert_eq!(balance(&contract, &accounts(3)), 5_000);
+        assert_eq!(contract.k, 100_000_000);
+        assert!(contract.get_pending_liquidity(accounts(1)).is_empty());
+    }
+
+    #[test]
+    fn test_add_liquidity_at_current_ratio() {
+        let mut contract = setup();
+        send(&mut contract, accounts(1), accounts(2), 20_000);
+        send(&mut contract, accounts(1), accounts(3), 5_000);
+        testing_env!(context(accounts(1)).build());
+        contract.add_liquidity(vec![U128(20_000), U128(5_000)], U128(0));
+
+        // 1_000 of token B only needs 4_000 of token A, the other 2_000 are refunded
+        send(&mut contract, accounts(4), accounts(2), 6_000);
+        send(&mut contract, accounts(4), accounts(3), 1_000);
+        testing_env!(context(accounts(4)).build());
+        let shares = contract.add_liquidity(vec![U128(6_000), U128(1_000)], U128(0));
+        assert_eq!(shares, U128(2_000));
+        assert_eq!(balance(&contract, &accounts(2)), 24_000);
+        assert_eq!(balance(&contract, &accounts(3)), 6_000);
+        assert!(contract.get_pending_liquidity(accounts(4)).is_empty());
+
+        let amounts = contract.remove_liquidity(shares, vec![U128(4_000), U128(1_000)]);
+        assert_eq!(amounts, vec![U128(4_000), U128(1_000)]);
+        assert_eq!(contract.get_main_pool_total_shares(), U128(10_000));
+        assert_eq!(contract.k, 100_000_000);
+    }
+
+    #[test]
+    fn test_remove_liquidity_one_token() {
+        let mut contract = setup();
+        send(&mut contract, accounts(1), accounts(2), 20_000);
+        send(&mut contract, accounts(1), accounts(3), 5_000);
+        testing_env!(context(accounts(1)).build());
+        contract.add_liquidity(vec![U128(20_000), U128(5_000)], U128(0));
+
+        // 1_000 shares are 2_000 A and 500 B, B is swapped for 500 * 18_000 / (4_500 + 500) A
+        let amount = contract.remove_liquidity_one_token(U128(1_000), accounts(2), U128(3_800));
+        assert_eq!(amount, U128(2_000 + 1_800));
+        assert_eq!(balance(&contract, &accounts(2)), 20_000 - 3_800);
+        assert_eq!(balance(&contract, &accounts(3)), 5_000);
+        assert_eq!(contract.get_main_pool_shares(accounts(1)), U128(8_000));
+    }
+
+    #[test]
+    fn test_remove_liquidity_one_token_is_a_swap() {
+        let mut contract = setup();
+        send(&mut contract, accounts(1), accounts(2), 20_000);
+        send(&mut contract, accounts(1), accounts(3), 5_000);
+        testing_env!(context(accounts(1)).build());
+        contract.add_liquidity(vec![U128(20_000), U128(5_000)], U128(0));
+        let mut builder = context(accounts(1));
+        crate::test_utils::enable_swap_history(&mut builder, &mut contract, accounts(1));
+        // 100 A for at least 28 B, more than the 24 they buy now
+        let msg = format!(
+            r#"{{"PlaceLimitOrder": {{"token_out": "{}", "min_amount_out": "28"}}}}"#,
+            accounts(3)
+        );
+        crate::test_utils::transfer(
+            &mut builder,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            100,
+            msg,
+        );
+        assert_eq!(contract.limit_orders.len(), 1);
+
+        testing_env!(builder.predecessor_account_id(accounts(1)).build());
+        contract.remove_liquidity_one_token(U128(1_000), accounts(2), U128(3_800));
+        // the swap leaves k at the product after the burn
+        assert_eq!(contract.k, 18_000 * 4_500);
+        let history = contract.get_swap_history(accounts(1), None, None);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].amount_in.0, 500);
+        assert_eq!(history[0].amount_out.0, 1_800);
+        // A got more expensive, the order is filled
+        assert_eq!(contract.limit_orders.len(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Slippage error: 3800 is less than the minimum 3801")]
+    fn test_remove_liquidity_one_token_slippage() {
+        let mut contract = setup();
+        send(&mut contract, accounts(1), accounts(2), 20_000);
+        send(&mut contract, accounts(1), accounts(3), 5_000);
+        testing_env!(context(accounts(1)).build());
+        contract.add_liquidity(vec![U128(20_000), U128(5_000)], U128(0));
+        contract.remove_liquidity_one_token(U128(1_000), accounts(2), U128(3_801));
+    }
+
+    #[test]
+    fn test_owner_transfers_are_pending() {
+        let mut contract = setup();
+        for (sell, buy, amount) in [
+            (accounts(2), accounts(3), 20_000),
+            (accounts(3), accounts(2), 5_000),
+        ] {
+            testing_env!(context(sell.clone()).build());
+            contract.ft_on_transfer(accounts(1), U128(amount), buy.to_string());
+        }
+        assert_eq!(contract.main_pool_reserves(), vec![0, 0]);
+        testing_env!(context(accounts(1)).build());
+        contract.add_liquidity(vec![U128(20_000), U128(5_000)], U128(0));
+        send(&mut contract, accounts(4), accounts(2), 4_000);
+        send(&mut contract, accounts(4), accounts(3), 1_000);
+        testing_env!(context(accounts(4)).build());
+        contract.add_liquidity(vec![U128(4_000), U128(1_000)], U128(0));
+        assert_eq!(contract.get_main_pool_shares(accounts(1)), U128(9_000));
+        assert_eq!(contract.get_main_pool_shares(accounts(4)), U128(2_000));
+    }
+
+    #[test]
+    fn test_swap_against_one_sided_seed() {
+        let mut contract = setup();
+        testing_env!(context(accounts(2)).build());
+        contract.ft_on_transfer(accounts(1), U128(20_000), accounts(3).to_string());
+        testing_env!(context(accounts(3)).build());
+        let unused = match contract.ft_on_transfer(accounts(4), U128(1), accounts(2).to_string()) {
+            PromiseOrValue::Value(unused) => unused.0,
+            PromiseOrValue::Promise(_) => unreachable!(),
+        };
+        assert_eq!(unused, 1);
+        assert_eq!(
+            get_logs().last().unwrap(),
+            "1 danny are refunded: E_INSUFFICIENT_LIQUIDITY: Not enough liquidity in the pool"
+        );
+        assert_eq!(
+            contract.get_pending_liquidity(accounts(1))[&accounts(2)].0,
+            20_000
+        );
+    }
+
+    #[test]
+    fn test_one_sided_seed_becomes_pending() {
+        let mut contract = setup();
+        testing_env!(context(accounts(2)).build());
+        contract.ft_on_transfer(accounts(1), U128(20_000), accounts(3).to_string());
+        send(&mut contract, accounts(1), accounts(3), 5_000);
+        testing_env!(context(accounts(1)).build());
+        contract.add_liquidity(vec![U128(20_000), U128(5_000)], U128(0));
+        assert_eq!(contract.get_main_pool_shares(accounts(1)), U128(9_000));
+        assert!(contract.get_pending_liquidity(accounts(1)).is_empty());
+    }
+
+    #[test]
+    fn test_legacy_transfer_after_shares_is_pending() {
+        let mut contract = setup();
+        send(&mut contract, accounts(4), accounts(2), 20_000);
+        send(&mut contract, accounts(4), accounts(3), 5_000);
+        testing_env!(context(accounts(4)).build());
+        contract.add_liquidity(vec![U128(20_000), U128(5_000)], U128(0));
+
+        testing_env!(context(accounts(2)).build());
+        contract.ft_on_transfer(accounts(1), U128(1_000), accounts(3).to_string());
+        assert_eq!(contract.tokens.get(&accounts(2)).unwrap().balance, 20_000);
+        assert_eq!(
+            contract.get_pending_liquidity(accounts(1))[&accounts(2)],
+            U128(1_000)
+        );
+    }
+
+    #[test]
+    fn test_add_liquidity_single() {
+        let mut contract = setup();
+        let (x, y) = (10u128.pow(12), 4 * 10u128.pow(12));
+        send(&mut contract, accounts(1), accounts(2), x);
+        send(&mut contract, accounts(1), accounts(3), y);
+        testing_env!(context(accounts(1)).build());
+        contract.add_liquidity(vec![U128(x), U128(y)], U128(0));
+        let total_supply = contract.get_main_pool_total_shares().0;
+
+        let amount = 10u128.pow(10);
+        testing_env!(context(accounts(2)).build());
+        contract.ft_on_transfer(
+            accounts(4),
+            U128(amount),
+            r#"{"AddLiquiditySingle": {"min_shares": "0"}}"#.to_string(),
+        );
+        let shares = contract.get_main_pool_shares(accounts(4)).0;
+        // about half of the deposit's value, minus the fee on the swapped half and the price impact
+        let fair = total_supply * amount / (2 * x);
+        assert!(shares < fair && shares > fair * 996 / 1000);
+        assert_eq!(balance(&contract, &accounts(2)), x + amount);
+        assert_eq!(balance(&contract, &accounts(3)), y);
+    }
+
+    #[test]
+    fn test_zap_swap_amount() {
+        // the rest of the input and the output are in the ratio of the pool after the swap
+        let (x, y, amount) = (10u128.pow(24), 3 * 10u128.pow(24), 5 * 10u128.pow(22));
+        let swapped = zap_swap_amount(x, amount);
+        let (x, y, swapped) = (x as f64, y as f64, swapped as f64);
+        let in_after_fee = swapped * 0.997;
+        let out = in_after_fee * y / (x + in_after_fee);
+        let ratio_rest = (amount as f64 - swapped) / out;
+        let ratio_pool = (x + swapped) / (y - out);
+        assert!((ratio_rest - ratio_pool).abs() / ratio_pool < 1e-12);
+    }
+
+    #[test]
+    fn test_add_liquidity_needs_registration() {
+        let mut contract = setup();
+        for msg in [
+            "\"AddLiquidity\"",
+            r#"{"AddLiquiditySingle": {"min_shares": "0"}}"#,
+        ] {
+            testing_env!(context(accounts(2)).build());
+            let unused = match contract.ft_on_transfer(accounts(0), U128(1_000), msg.to_string()) {
+                PromiseOrValue::Value(unused) => unused,
+                PromiseOrValue::Promise(_) => unreachable!(),
+            };
+            assert_eq!(unused, U128(1_000));
+            assert_eq!(
+                get_logs(),
+                vec!["1000 charlie are refunded: E_NOT_REGISTERED: @alice is not registered, pay for its storage with storage_deposit"]
+            );
+        }
+        assert!(contract.get_pending_liquidity(accounts(0)).is_empty());
+    }
+
+    #[test]
+    fn test_add_liquidity_single_to_empty_pool() {
+        let mut contract = setup();
+        testing_env!(context(accounts(2)).build());
+        let unused = match contract.ft_on_transfer(
+            accounts(4),
+            U128(1_000),
+            r#"{"AddLiquiditySingle": {"min_shares": "0"}}"#.to_string(),
+        ) {
+            PromiseOrValue::Value(unused) => unused,
+            PromiseOrValue::Promise(_) => unreachable!(),
+        };
+        assert_eq!(unused, U128(1_000));
+        assert_eq!(
+            get_logs(),
+            vec!["1000 charlie are refunded: E_INSUFFICIENT_LIQUIDITY: Not enough liquidity in the pool"]
+        );
+        assert_eq!(contract.get_main_pool_shares(accounts(4)), U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Slippage error: 2000 shares is less than the minimum 2001")]
+    fn test_add_liquidity_slippage() {
+        let mut contract = setup();
+        send(&mut contract, accounts(1), accounts(2), 20_000);
+        send(&mut contract, accounts(1), accounts(3), 5_000);
+        testing_env!(context(accounts(1)).build());
+        contract.add_liquidity(vec![U128(20_000), U128(5_000)], U128(0));
+
+        send(&mut contract, accounts(4), accounts(2), 4_000);
+        send(&mut contract, accounts(4), accounts(3), 1_000);
+        testing_env!(context(accounts(4)).build());
+        contract.add_liquidity(vec![U128(4_000), U128(1_000)], U128(2_001));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough charlie pending")]
+    fn test_add_liquidity_without_pending_tokens() {
+        let mut contract = setup();
+        send(&mut contract, accounts(4), accounts(3), 1_000);
+        testing_env!(context(accounts(4)).build());
+        contract.add_liquidity(vec![U128(4_000), U128(1_000)], U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "The first deposit must mint more than 1000 shares, got 1000")]
+    fn test_first_deposit_below_minimum_liquidity() {
+        let mut contract = setup();
+        send(&mut contract, accounts(4), accounts(2), 1_000);
+        send(&mut contract, accounts(4), accounts(3), 1_000);
+        testing_env!(context(accounts(4)).build());
+        contract.add_liquidity(vec![U128(1_000), U128(1_000)], U128(0));
+    }
+
+    /// The first depositor mints a single share and donates to the pool to inflate its price,
+    /// so that later deposits round down to nothing. The donation is credited to the reserves
+    /// directly here, the way the owner's `sell_token:buy_token` transfers do.
+    fn inflate_share_price(contract: &mut AMM, donation: Balance) {
+        send(contract, accounts(5), accounts(2), 1_001);
+        send(contract, accounts(5), accounts(3), 1_001);
+        testing_env!(context(accounts(5)).build());
+        let shares = contract.add_liquidity(vec![U128(1_001), U128(1_001)], U128(0));
+        assert_eq!(shares, U128(1));
+        for token_id in [accounts(2), accounts(3)] {
+            let mut token_info = contract.tokens.get(&token_id).unwrap();
+            token_info.balance += donation;
+            contract.tokens.insert(&token_id, &token_info);
+        }
+    }
+
+    #[test]
+    fn test_inflation_attack_costs_the_attacker() {
+        let mut contract = setup();
+        let donation = 1_000_000;
+        inflate_share_price(&mut contract, donation);
+
+        send(&mut contract, accounts(4), accounts(2), 100_000);
+        send(&mut contract, accounts(4), accounts(3), 100_000);
+        testing_env!(context(accounts(4)).build());
+        let shares = contract.add_liquidity(vec![U128(100_000), U128(100_000)], U128(0));
+        assert_eq!(shares, U128(99));
+        // the victim loses at most the rounding of one share, the unused part was refunded
+        let amounts = contract.remove_liquidity(shares, vec![U128(0), U128(0)]);
+        assert_eq!(amounts, vec![U128(99_000), U128(99_000)]);
+
+        // most of the donation went to the locked shares
+        testing_env!(context(accounts(5)).build());
+        let amounts = contract.remove_liquidity(U128(1), vec![U128(0), U128(0)]);
+        assert_eq!(amounts, vec![U128(1_000), U128(1_000)]);
+        assert!(amounts[0].0 < (1_001 + donation) / 1_000);
+        assert_eq!(
+            contract.get_main_pool_total_shares(),
+            U128(MINIMUM_LIQUIDITY)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The deposit is too small to mint any shares")]
+    fn test_deposit_rounding_to_zero_shares() {
+        let mut contract = setup();
+        inflate_share_price(&mut contract, 1_000_000);
+
+        send(&mut contract, accounts(4), accounts(2), 500);
+        send(&mut contract, accounts(4), accounts(3), 500);
+        testing_env!(context(accounts(4)).build());
+        contract.add_liquidity(vec![U128(500), U128(500)], U128(0));
+    }
+}
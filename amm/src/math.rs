@@ -0,0 +1,175 @@
+//! Checked integer math for the main pool's constant-product pricing.
+//!
+//! Products go through a 256-bit intermediate, so balances with 24 decimals don't overflow.
+//! Every division rounds explicitly in the pool's favour: amounts paid out round down, amounts
+//! paid in round up. A result that doesn't fit a `u128` comes back as a [`MathError`] instead
+//! of a panic, so a swap can refund the tokens it received.
+
+use std::fmt;
+
+use near_sdk::Balance;
+
+use crate::fixed_point::U256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MathError {
+    Overflow,
+    DivisionByZero,
+    /// The pool doesn't hold the requested output.
+    InsufficientLiquidity,
+}
+
+impl fmt::Display for MathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MathError::Overflow => write!(f, "Math overflow"),
+            MathError::DivisionByZero => write!(f, "Division by zero"),
+            MathError::InsufficientLiquidity => write!(f, "Not enough liquidity in the pool"),
+        }
+    }
+}
+
+pub type MathResult<T> = Result<T, MathError>;
+
+pub fn to_u128(value: U256) -> MathResult<u128> {
+    if value > U256::from(u128::MAX) {
+        Err(MathError::Overflow)
+    } else {
+        Ok(value.as_u128())
+    }
+}
+
+/// `a * b / c`, rounded down.
+pub fn mul_div_down(a: u128, b: u128, c: u128) -> MathResult<u128> {
+    if c == 0 {
+        return Err(MathError::DivisionByZero);
+    }
+    to_u128(U256::from(a) * U256::from(b) / U256::from(c))
+}
+
+/// `a * b / c`, rounded up.
+pub fn mul_div_up(a: u128, b: u128, c: u128) -> MathResult<u128> {
+    if c == 0 {
+        return Err(MathError::DivisionByZero);
+    }
+    let c = U256::from(c);
+    to_u128((U256::from(a) * U256::from(b) + c - 1) / c)
+}
+
+pub fn checked_add(a: u128, b: u128) -> MathResult<u128> {
+    a.checked_add(b).ok_or(MathError::Overflow)
+}
+
+/// Output of selling `amount_in` to a constant-product pool, rounded down:
+/// `(x + a)(y - b) >= xy`, so `b = ya / (x + a)`.
+pub fn get_amount_out(
+    amount_in: Balance,
+    reserve_in: Balance,
+    reserve_out: Balance,
+) -> MathResult<Balance> {
+    mul_div_down(amount_in, reserve_out, checked_add(reserve_in, amount_in)?)
+}
+
+/// Input a constant-product pool needs to pay out exactly `amount_out`, rounded up: the
+/// smallest `a` with `ya / (x + a) >= b` is `xb / (y - b)`.
+pub fn get_amount_in(
+    amount_out: Balance,
+    reserve_in: Balance,
+    reserve_out: Balance,
+) -> MathResult<Balance> {
+    if amount_out >= reserve_out {
+        return Err(MathError::InsufficientLiquidity);
+    }
+    mul_div_up(reserve_in, amount_out, reserve_out - amount_out)
+}
+
+/// `k`, the product of the reserves. Two `u128` reserves always fit.
+pub fn invariant(reserves: &[Balance]) -> U256 {
+    reserves.iter().fold(U256::one(), |k, reserve| {
+        k.saturating_mul(U256::from(*reserve))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const E24: u128 = 1_000_000_000_000_000_000_000_000;
+
+    #[test]
+    fn test_large_balances() {
+        // (amount * y) overflows u128 here
+        let (x, y) = (1_000_000 * E24, 3_000_000 * E24);
+        assert_eq!(
+            get_amount_out(1_000 * E24, x, y),
+            Ok(2_997_002_997_002_997_002_997_002_997)
+        );
+        assert_eq!(
+            get_amount_in(3_000 * E24, x, y),
+            Ok(1_001_001_001_001_001_001_001_001_002)
+        );
+    }
+
+    #[test]
+    fn test_rounding() {
+        assert_eq!(mul_div_down(7, 3, 2), Ok(10));
+        assert_eq!(mul_div_up(7, 3, 2), Ok(11));
+        assert_eq!(mul_div_up(6, 3, 2), Ok(9));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(mul_div_down(1, 1, 0), Err(MathError::DivisionByZero));
+        assert_eq!(mul_div_up(u128::MAX, 2, 1), Err(MathError::Overflow));
+        assert_eq!(get_amount_out(u128::MAX, 1, 1), Err(MathError::Overflow));
+        assert_eq!(get_amount_out(0, 0, 1), Err(MathError::DivisionByZero));
+        assert_eq!(
+            get_amount_in(100, 1_000, 100),
+            Err(MathError::InsufficientLiquidity)
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_swap_never_decreases_k(
+            x in 1..u128::MAX / 2,
+            y in 1..u128::MAX,
+            amount_in in 0..u128::MAX / 2,
+        ) {
+            let amount_out = get_amount_out(amount_in, x, y).unwrap();
+            prop_assert!(amount_out < y);
+            prop_assert!(invariant(&[x + amount_in, y - amount_out]) >= invariant(&[x, y]));
+        }
+
+        #[test]
+        fn prop_exact_out_swap_never_decreases_k(
+            x in 1..u128::MAX / 2,
+            y in 2..u128::MAX,
+            amount_out in 1..u128::MAX,
+        ) {
+            let amount_out = amount_out % y;
+            match get_amount_in(amount_out, x, y) {
+                Ok(amount_in) => {
+                    // inputs that would overflow the reserve can't be sent by any token
+                    prop_assume!(x.checked_add(amount_in).is_some());
+                    prop_assert!(invariant(&[x + amount_in, y - amount_out]) >= invariant(&[x, y]));
+                    // it's the smallest input that pays out `amount_out`
+                    prop_assert!(amount_in == 0 || get_amount_out(amount_in - 1, x, y).unwrap() < amount_out);
+                }
+                Err(err) => prop_assert_eq!(err, MathError::Overflow),
+            }
+        }
+
+        #[test]
+        fn prop_round_trip_favours_the_pool(
+            x in 1..u128::MAX / 2,
+            y in 1..u128::MAX / 2,
+            amount_in in 0..u128::MAX / 2,
+        ) {
+            let amount_out = get_amount_out(amount_in, x, y).unwrap();
+            let amount_back = get_amount_out(amount_out, y - amount_out, x + amount_in).unwrap();
+            prop_assert!(amount_back <= amount_in);
+        }
+    }
+}
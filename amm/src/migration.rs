@@ -0,0 +1,146 @@
+//! Migration of the contract state from older layouts.
+//!
+//! The state version is kept under its own storage key, outside the `AMM` struct, so that
+//! `migrate` knows which layout to read before deserializing it. The first deployment didn't
+//! write it, its state is version 0.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::UnorderedMap;
+use near_sdk::{env, log, near_bindgen, AccountId};
+
+use crate::*;
+
+/// The version of the state layout this code reads.
+pub const STATE_VERSION: u32 = 1;
+const STATE_VERSION_KEY: &[u8] = b"state_version";
+
+/// The state before the full token metadata and everything after the main pool.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct AmmV0 {
+    owner_id: AccountId,
+    tokens: UnorderedMap<AccountId, TokenInfoV0>,
+    k: u128,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct TokenInfoV0 {
+    name: String,
+    decimals: u8,
+    balance: u128,
+    ticker: TickerInfo,
+}
+
+pub(crate) fn write_state_version() {
+    env::storage_write(STATE_VERSION_KEY, &STATE_VERSION.to_le_bytes());
+}
+
+fn read_state_version() -> u32 {
+    env::storage_read(STATE_VERSION_KEY).map_or(0, |bytes| {
+        u32::from_le_bytes(bytes.try_into().expect("Invalid state version"))
+    })
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Rewrites the state of an older version in the current layout, to call with the deploy
+    /// of new code. The tokens keep their reserves; their metadata is fetched again, as the old
+    /// state only kept the name and the decimals.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let version = read_state_version();
+        let mut this = match version {
+            0 => Self::from_v0(env::state_read().expect("No state to migrate")),
+            _ => panic!("The state is already at version {}", version),
+        };
+        write_state_version();
+        log!("The state is migrated from version {}", version);
+        this.get_metadata();
+        this
+    }
+
+    pub fn get_state_version(&self) -> u32 {
+        read_state_version()
+    }
+}
+
+impl AMM {
+    fn from_v0(mut old: AmmV0) -> Self {
+        let old_tokens: Vec<(AccountId, TokenInfoV0)> = old.tokens.iter().collect();
+        // the entries are written again under the same prefix in the new layout
+        old.tokens.clear();
+        let mut tokens = UnorderedMap::new(b"t");
+        for (token_id, token_info) in &old_tokens {
+            tokens.insert(
+                token_id,
+                &TokenInfo {
+                    balance: token_info.balance,
+                    ..Default::default()
+                },
+            );
+        }
+        let mut this = Self::with_tokens(old.owner_id, tokens);
+        // the AMM holds the reserves, the reconciliation starts from them
+        for (token_id, token_info) in old_tokens {
+            this.tracked_balances.insert(&token_id, &token_info.balance);
+        }
+        this
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    /// The state of the first deployment, with a main pool of 1_000_000 of `accounts(2)` and
+    /// 2_000_000 of `accounts(3)`.
+    fn write_v0_state() {
+        let mut tokens = UnorderedMap::new(b"t");
+        for (token_id, balance) in [(accounts(2), 1_000_000), (accounts(3), 2_000_000)] {
+            tokens.insert(
+                &token_id,
+                &TokenInfoV0 {
+                    name: token_id.to_string(),
+                    decimals: 24,
+                    balance,
+                    ticker: TickerInfo::default(),
+                },
+            );
+        }
+        env::state_write(&AmmV0 {
+            owner_id: accounts(1),
+            tokens,
+            k: 2_000_000_000_000,
+        });
+    }
+
+    #[test]
+    fn test_migrate_from_v0() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        write_v0_state();
+        let contract = AMM::migrate();
+        assert_eq!(get_logs(), vec!["The state is migrated from version 0"]);
+        assert_eq!(contract.get_state_version(), STATE_VERSION);
+        assert_eq!(contract.owner_id, accounts(1));
+        assert_eq!(contract.main_pool_reserves(), vec![1_000_000, 2_000_000]);
+        assert_eq!(contract.k, 2_000_000_000_000);
+        assert_eq!(contract.get_tracked_balance(accounts(3)).0, 2_000_000);
+        let token = contract.get_token(accounts(2)).unwrap();
+        assert!(token.metadata.is_none());
+        assert_eq!(token.metadata_version, 0);
+        assert_eq!(contract.get_virtual_order_pools()[0].sale_rate.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "The state is already at version 1")]
+    fn test_migrate_current_state() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let contract = AMM::new(accounts(1), accounts(2), accounts(3));
+        env::state_write(&contract);
+        AMM::migrate();
+    }
+}
@@ -0,0 +1,309 @@
+use near_contract_standards::storage_management::StorageBalance;
+use near_sdk::json_types::U128;
+use near_sdk::{
+    env, is_promise_success, log, near_bindgen, AccountId, Balance, Promise, PromiseError,
+};
+
+use crate::*;
+
+#[near_bindgen]
+impl AMM {
+    /// Pays the swap output once the receiver is known to be registered on the token contract,
+    /// otherwise refunds it to the sender.
+    #[private]
+    pub fn payout_storage_callback(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        msg: Option<String>,
+        #[callback_result] storage_balance: Result<Option<StorageBalance>, PromiseError>,
+    ) -> Promise {
+        if let Ok(Some(_)) = storage_balance {
+            self.transfer_output(sender_id, receiver_id, token_id, amount.0, msg)
+        } else {
+            log!(
+                "@{} is not registered on {}, {} are refunded to @{}",
+                receiver_id,
+                token_id,
+                amount.0,
+                sender_id
+            );
+            self.internal_refund(sender_id, token_id, amount.0)
+        }
+    }
+
+    /// Refunds the swap output to the sender if the transfer to the receiver failed.
+    #[private]
+    pub fn payout_callback(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) {
+        if !is_promise_success() {
+            log!(
+                "Failed to pay {} {} to @{}, they are refunded to @{}",
+                amount.0,
+                token_id,
+                receiver_id,
+                sender_id
+            );
+            self.track_received(&token_id, amount.0);
+            self.internal_refund(sender_id, token_id, amount.0);
+        }
+    }
+
+    /// Refunds to the sender the part of the swap output that the receiver of `ft_transfer_call`
+    /// didn't use, or all of it if the call failed.
+    #[private]
+    pub fn forward_callback(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        #[callback_result] used: Result<U128, PromiseError>,
+    ) {
+        let unused = match used {
+            Ok(used) => amount.0.saturating_sub(used.0),
+            Err(_) => amount.0,
+        };
+        if unused > 0 {
+            log!(
+                "@{} didn't use {} {}, they are refunded to @{}",
+                receiver_id,
+                unused,
+                token_id,
+                sender_id
+            );
+            self.track_received(&token_id, unused);
+            self.internal_refund(sender_id, token_id, unused);
+        }
+    }
+}
+
+impl AMM {
+    /// Transfers the output of a swap, with `ft_transfer_call` if there is a `msg` to forward.
+    /// A receiver other than the sender is checked for storage registration first, and whatever
+    /// can't be paid to it goes back to the sender.
+    pub(crate) fn internal_payout(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        token_id: AccountId,
+        amount: Balance,
+        msg: Option<String>,
+    ) -> Promise {
+        if receiver_id == sender_id {
+            return match msg {
+                Some(msg) => {
+                    self.transfer_output(sender_id, receiver_id, token_id, amount, Some(msg))
+                }
+                // the sender gets its tokens credited to the deposits if the transfer fails
+                None => {
+                    self.track_sent(&token_id, amount);
+                    ext_ft::ext(token_id.clone())
+                        .with_attached_deposit(1)
+                        .ft_transfer(receiver_id, U128(amount), None)
+                        .then(Self::ext(env::current_account_id()).withdraw_callback(
+                            sender_id,
+                            token_id,
+                            U128(amount),
+                        ))
+                }
+            };
+        }
+        ext_ft::ext(token_id.clone())
+            .storage_balance_of(receiver_id.clone())
+            .then(
+                Self::ext(env::current_account_id()).payout_storage_callback(
+                    sender_id,
+                    receiver_id,
+                    token_id,
+                    U128(amount),
+                    msg,
+                ),
+            )
+    }
+
+    fn transfer_output(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        token_id: AccountId,
+        amount: Balance,
+        msg: Option<String>,
+    ) -> Promise {
+        self.track_sent(&token_id, amount);
+        let callback = Self::ext(env::current_account_id());
+        let transfer = ext_ft::ext(token_id.clone()).with_attached_deposit(1);
+        match msg {
+            Some(msg) => transfer
+                .ft_transfer_call(receiver_id.clone(), U128(amount), None, msg)
+                .then(callback.forward_callback(sender_id, receiver_id, token_id, U128(amount))),
+            None => transfer
+                .ft_transfer(receiver_id.clone(), U128(amount), None)
+                .then(callback.payout_callback(sender_id, receiver_id, token_id, U128(amount))),
+        }
+    }
+
+    /// Transfers tokens back to the sender, they are credited to its deposits if that fails too.
+    pub(crate) fn internal_refund(
+        &mut self,
+        sender_id: AccountId,
+        token_id: AccountId,
+        amount: Balance,
+    ) -> Promise {
+        self.track_sent(&token_id, amount);
+        ext_ft::ext(token_id.clone())
+            .with_attached_deposit(1)
+            .ft_transfer(sender_id.clone(), U128(amount), None)
+            .then(Self::ext(env::current_account_id()).withdraw_callback(
+                sender_id,
+                token_id,
+                U128(amount),
+            ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_sdk::{serde_json, testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    fn setup() -> AMM {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+        AMM::new(accounts(1), accounts(2), accounts(3))
+    }
+
+    /// Receivers and amounts of the `ft_transfer` and `ft_transfer_call` calls made so far.
+    fn transfers(method: &str) -> Vec<(AccountId, u128)> {
+        get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } if function_name == method => {
+                    let args: serde_json::Value = serde_json::from_slice(&args).unwrap();
+                    Some((
+                        args["receiver_id"].as_str().unwrap().parse().unwrap(),
+                        args["amount"].as_str().unwrap().parse().unwrap(),
+                    ))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_payout_to_registered_receiver() {
+        let mut contract = setup();
+        contract.payout_storage_callback(
+            accounts(4),
+            accounts(5),
+            accounts(2),
+            U128(100),
+            None,
+            Ok(Some(StorageBalance {
+                total: U128(1),
+                available: U128(0),
+            })),
+        );
+        assert_eq!(transfers("ft_transfer"), vec![(accounts(5), 100)]);
+    }
+
+    #[test]
+    fn test_unregistered_receiver_is_refunded() {
+        let mut contract = setup();
+        contract.payout_storage_callback(
+            accounts(4),
+            accounts(5),
+            accounts(2),
+            U128(100),
+            Some("stake".to_string()),
+            Ok(None),
+        );
+        assert_eq!(transfers("ft_transfer"), vec![(accounts(4), 100)]);
+        assert!(transfers("ft_transfer_call").is_empty());
+    }
+
+    #[test]
+    fn test_failed_payout_is_refunded() {
+        let mut contract = setup();
+        testing_env!(
+            VMContextBuilder::new()
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.payout_callback(accounts(4), accounts(5), accounts(2), U128(100));
+        assert_eq!(transfers("ft_transfer"), vec![(accounts(4), 100)]);
+    }
+
+    #[test]
+    fn test_forward_output() {
+        let mut contract = setup();
+        contract.internal_payout(
+            accounts(4),
+            accounts(4),
+            accounts(2),
+            100,
+            Some("stake".to_string()),
+        );
+        assert_eq!(transfers("ft_transfer_call"), vec![(accounts(4), 100)]);
+    }
+
+    #[test]
+    fn test_unused_forwarded_output_is_refunded() {
+        let mut contract = setup();
+        contract.forward_callback(
+            accounts(4),
+            accounts(5),
+            accounts(2),
+            U128(100),
+            Ok(U128(30)),
+        );
+        assert_eq!(transfers("ft_transfer"), vec![(accounts(4), 70)]);
+    }
+
+    #[test]
+    fn test_failed_forward_is_refunded() {
+        let mut contract = setup();
+        contract.forward_callback(
+            accounts(4),
+            accounts(5),
+            accounts(2),
+            U128(100),
+            Err(PromiseError::Failed),
+        );
+        assert_eq!(transfers("ft_transfer"), vec![(accounts(4), 100)]);
+    }
+
+    #[test]
+    fn test_used_forwarded_output() {
+        let mut contract = setup();
+        contract.forward_callback(
+            accounts(4),
+            accounts(5),
+            accounts(2),
+            U128(100),
+            Ok(U128(100)),
+        );
+        assert!(transfers("ft_transfer").is_empty());
+    }
+}
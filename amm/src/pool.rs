@@ -0,0 +1,348 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::Serialize;
+use near_sdk::{env, near_bindgen, AccountId, Balance};
+
+use crate::concentrated_pool::{ConcentratedPool, ConcentratedPoolView, PositionView};
+use crate::error::{AmmResult, OrPanic};
+use crate::weighted_pool::{WeightedPool, WeightedPoolView};
+use crate::*;
+
+/// Pools that live next to the main constant-product pool of the contract.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub enum Pool {
+    Weighted(WeightedPool),
+    Concentrated(ConcentratedPool),
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PoolView {
+    Weighted(WeightedPoolView),
+    Concentrated(ConcentratedPoolView),
+}
+
+impl Pool {
+    pub fn view(&self) -> PoolView {
+        match self {
+            Pool::Weighted(pool) => PoolView::Weighted(pool.view()),
+            Pool::Concentrated(pool) => PoolView::Concentrated(pool.view()),
+        }
+    }
+
+    pub fn token_ids(&self) -> &[AccountId] {
+        match self {
+            Pool::Weighted(pool) => &pool.token_ids,
+            Pool::Concentrated(pool) => &pool.token_ids,
+        }
+    }
+
+    pub fn as_weighted(&mut self) -> &mut WeightedPool {
+        match self {
+            Pool::Weighted(pool) => pool,
+            _ => panic!("The pool is not a weighted pool"),
+        }
+    }
+
+    /// Like [`Pool::as_weighted`], for the transfers that are refunded on failure.
+    pub fn weighted(&mut self) -> AmmResult<&mut WeightedPool> {
+        match self {
+            Pool::Weighted(pool) => Ok(pool),
+            _ => Err(AmmError::BadMsg(
+                "the pool is not a weighted pool".to_string(),
+            )),
+        }
+    }
+
+    pub fn as_concentrated(&mut self) -> &mut ConcentratedPool {
+        match self {
+            Pool::Concentrated(pool) => pool,
+            _ => panic!("The pool is not a concentrated liquidity pool"),
+        }
+    }
+
+    pub fn get_return(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+    ) -> AmmResult<Balance> {
+        match self {
+            Pool::Weighted(pool) => pool.get_return(token_in, amount_in, token_out),
+            Pool::Concentrated(pool) => pool.get_return(token_in, amount_in, token_out),
+        }
+    }
+
+    pub fn swap(
+        &mut self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> AmmResult<Balance> {
+        match self {
+            Pool::Weighted(pool) => pool.swap(token_in, amount_in, token_out, min_amount_out),
+            Pool::Concentrated(pool) => pool.swap(token_in, amount_in, token_out, min_amount_out),
+        }
+    }
+
+    pub fn get_amount_in(
+        &self,
+        token_in: &AccountId,
+        amount_out: Balance,
+        token_out: &AccountId,
+    ) -> AmmResult<Balance> {
+        match self {
+            Pool::Weighted(pool) => pool.get_amount_in(token_in, amount_out, token_out),
+            Pool::Concentrated(pool) => pool.get_amount_in(token_in, amount_out, token_out),
+        }
+    }
+
+    pub fn swap_exact_out(
+        &mut self,
+        token_in: &AccountId,
+        max_amount_in: Balance,
+        amount_out: Balance,
+        token_out: &AccountId,
+    ) -> AmmResult<Balance> {
+        match self {
+            Pool::Weighted(pool) => {
+                pool.swap_exact_out(token_in, max_amount_in, amount_out, token_out)
+            }
+            Pool::Concentrated(pool) => {
+                pool.swap_exact_out(token_in, max_amount_in, amount_out, token_out)
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Creates a weighted pool of 2 to 8 tokens, e.g. 80/20. `weights` are relative and get
+    /// normalized, `swap_fee` is in basis points. The owner seeds the pool by sending each of its
+    /// tokens with a `JoinPool` msg, the initial shares are minted when the last one arrives.
+    pub fn create_weighted_pool(
+        &mut self,
+        token_ids: Vec<AccountId>,
+        weights: Vec<u32>,
+        swap_fee: u32,
+    ) -> u64 {
+        self.assert_owner();
+        let pool_id = self.pools.len();
+        let pool = WeightedPool::new(
+            StorageKey::PoolShares { pool_id },
+            token_ids,
+            weights,
+            swap_fee,
+        );
+        self.add_pool(Pool::Weighted(pool))
+    }
+
+    /// Creates a concentrated liquidity pool of two tokens. The price is `token1 / token0`,
+    /// it starts at `1.0001^initial_tick`. Positions can only use ticks that are multiples of
+    /// `tick_spacing`.
+    pub fn create_concentrated_pool(
+        &mut self,
+        token_ids: Vec<AccountId>,
+        swap_fee: u32,
+        tick_spacing: u32,
+        initial_tick: i32,
+    ) -> u64 {
+        self.assert_owner();
+        let pool_id = self.pools.len();
+        let pool = ConcentratedPool::new(pool_id, token_ids, swap_fee, tick_spacing, initial_tick);
+        self.add_pool(Pool::Concentrated(pool))
+    }
+
+    /// Opens a liquidity position in `[tick_lower, tick_upper]` of a concentrated liquidity pool.
+    /// The tokens are taken from the caller's deposits: as much as the current price allows,
+    /// but not more than `amounts` and not less than `min_amounts`.
+    pub fn open_position(
+        &mut self,
+        pool_id: u64,
+        tick_lower: i32,
+        tick_upper: i32,
+        amounts: Vec<U128>,
+        min_amounts: Vec<U128>,
+    ) -> u64 {
+        assert!(
+            amounts.len() == 2 && min_amounts.len() == 2,
+            "Expected amounts of 2 tokens"
+        );
+        let account_id = env::predecessor_account_id();
+        let mut pool = self.get_pool_or_panic(pool_id);
+        let concentrated = pool.as_concentrated();
+        let (position_id, used) = concentrated.open_position(
+            &account_id,
+            tick_lower,
+            tick_upper,
+            [amounts[0].0, amounts[1].0],
+        );
+        for i in 0..2 {
+            if used[i] < min_amounts[i].0 {
+                panic!("{}", AmmError::below_minimum(used[i], min_amounts[i].0));
+            }
+            self.internal_withdraw(&account_id, &concentrated.token_ids[i], used[i]);
+        }
+        self.pools.replace(pool_id, &pool);
+        position_id
+    }
+
+    /// Removes `liquidity` from a position and credits the tokens, together with the earned fees,
+    /// to the caller's deposits. Zero `liquidity` only collects the fees.
+    pub fn decrease_position(
+        &mut self,
+        pool_id: u64,
+        position_id: u64,
+        liquidity: U128,
+    ) -> Vec<U128> {
+        let account_id = env::predecessor_account_id();
+        self.check_registered(&account_id).or_panic();
+        let mut pool = self.get_pool_or_panic(pool_id);
+        let concentrated = pool.as_concentrated();
+        let amounts = concentrated.decrease_position(&account_id, position_id, liquidity.0);
+        for (token_id, amount) in concentrated.token_ids.iter().zip(amounts) {
+            if amount > 0 {
+                self.internal_deposit(&account_id, token_id, amount);
+            }
+        }
+        self.pools.replace(pool_id, &pool);
+        amounts.iter().map(|amount| U128(*amount)).collect()
+    }
+
+    pub fn get_position(&self, pool_id: u64, position_id: u64) -> PositionView {
+        self.get_pool_or_panic(pool_id)
+            .as_concentrated()
+            .position_view(position_id)
+    }
+
+    /// Burns `shares` of a weighted pool and withdraws their value in `token_out` only.
+    pub fn exit_pool(
+        &mut self,
+        pool_id: u64,
+        shares: U128,
+        token_out: AccountId,
+        min_amount_out: U128,
+    ) -> U128 {
+        let account_id = env::predecessor_account_id();
+        let mut pool = self.get_pool_or_panic(pool_id);
+        let amount_out =
+            pool.as_weighted()
+                .exit(&account_id, shares.0, &token_out, min_amount_out.0);
+        self.pools.replace(pool_id, &pool);
+
+        self.internal_refund(account_id, token_out, amount_out);
+        U128(amount_out)
+    }
+
+    pub fn get_number_of_pools(&self) -> u64 {
+        self.pools.len()
+    }
+
+    pub fn get_pool(&self, pool_id: u64) -> PoolView {
+        self.get_pool_or_panic(pool_id).view()
+    }
+
+    pub fn get_pool_shares(&self, pool_id: u64, account_id: AccountId) -> U128 {
+        U128(
+            self.get_pool_or_panic(pool_id)
+                .as_weighted()
+                .share_balance(&account_id),
+        )
+    }
+
+    /// Quotes a swap. `pool_id` is `None` for the main pool.
+    pub fn get_return(
+        &self,
+        pool_id: Option<u64>,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: AccountId,
+    ) -> U128 {
+        let amount_out = match pool_id {
+            Some(pool_id) => self
+                .get_pool_or_panic(pool_id)
+                .get_return(&token_in, amount_in.0, &token_out)
+                .or_panic(),
+            None => self
+                .get_main_return(&token_in, amount_in.0, &token_out)
+                .or_panic(),
+        };
+        U128(amount_out)
+    }
+
+    /// Quotes the input of an exact-output swap. `pool_id` is `None` for the main pool.
+    pub fn get_amount_in(
+        &self,
+        pool_id: Option<u64>,
+        token_in: AccountId,
+        amount_out: U128,
+        token_out: AccountId,
+    ) -> U128 {
+        let amount_in = match pool_id {
+            Some(pool_id) => self
+                .get_pool_or_panic(pool_id)
+                .get_amount_in(&token_in, amount_out.0, &token_out)
+                .or_panic(),
+            None => self
+                .get_main_amount_in(&token_in, amount_out.0, &token_out)
+                .or_panic(),
+        };
+        U128(amount_in)
+    }
+
+    /// Spot price of `token_out` in `token_in` of a weighted pool, scaled by 10^18.
+    pub fn get_spot_price(&self, pool_id: u64, token_in: AccountId, token_out: AccountId) -> U128 {
+        U128(
+            self.get_pool_or_panic(pool_id)
+                .as_weighted()
+                .spot_price(&token_in, &token_out),
+        )
+    }
+
+    /// Quotes the shares minted for a single-token join.
+    pub fn get_join_shares(&self, pool_id: u64, token_in: AccountId, amount_in: U128) -> U128 {
+        U128(
+            self.get_pool_or_panic(pool_id)
+                .as_weighted()
+                .calc_join_shares(&token_in, amount_in.0)
+                .or_panic(),
+        )
+    }
+
+    /// Quotes the amount of `token_out` withdrawn for `shares` on a single-token exit.
+    pub fn get_exit_amount(&self, pool_id: u64, shares: U128, token_out: AccountId) -> U128 {
+        U128(
+            self.get_pool_or_panic(pool_id)
+                .as_weighted()
+                .calc_exit_amount(shares.0, &token_out),
+        )
+    }
+}
+
+impl AMM {
+    fn add_pool(&mut self, pool: Pool) -> u64 {
+        for token_id in pool.token_ids() {
+            self.assert_token_accepted(token_id);
+            // create the AMM's wallet for the token, registration is refunded if it exists
+            ext_ft::ext(token_id.clone())
+                .with_attached_deposit(MIN_STORAGE)
+                .storage_deposit(env::current_account_id(), false);
+        }
+        self.pools.push(&pool);
+        self.pools.len() - 1
+    }
+
+    pub(crate) fn get_pool_or_panic(&self, pool_id: u64) -> Pool {
+        self.pools
+            .get(pool_id)
+            .unwrap_or_else(|| panic!("The pool {} doesn't exist", pool_id))
+    }
+
+    /// Like [`AMM::get_pool_or_panic`], for the transfers that are refunded on failure.
+    pub(crate) fn pool_by_id(&self, pool_id: u64) -> AmmResult<Pool> {
+        self.pools
+            .get(pool_id)
+            .ok_or_else(|| AmmError::BadMsg(format!("the pool {} doesn't exist", pool_id)))
+    }
+}
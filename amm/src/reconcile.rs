@@ -0,0 +1,343 @@
+//! Tokens the AMM should hold, reconciled with what the token contracts report.
+//!
+//! Every token received by `ft_on_transfer` and transferred out is tracked per token. A token
+//! with a transfer tax delivers less than it notifies: with the tax set by `set_transfer_fee`,
+//! only the amount actually received is used and tracked. A rebasing token, or a tax the AMM
+//! doesn't know about, makes the tracked balance drift from the real balance, and
+//! `reconcile_balance` fetches the real balance and settles the difference.
+
+use near_sdk::json_types::U128;
+use near_sdk::{env, log, near_bindgen, AccountId, Balance, Promise, PromiseError};
+
+use crate::error::OrPanic;
+use crate::math;
+use crate::*;
+
+/// Transfer fees are set in basis points.
+const MAX_TRANSFER_FEE: u16 = 10_000;
+
+#[near_bindgen]
+impl AMM {
+    /// The balance of `token_id` the AMM should hold by its own accounting.
+    pub fn get_tracked_balance(&self, token_id: AccountId) -> U128 {
+        U128(self.tracked_balances.get(&token_id).unwrap_or(0))
+    }
+
+    /// The part of the tracked balance of `token_id` that is missing and wasn't taken from the
+    /// main pool. Later surpluses of the token cover it first.
+    pub fn get_shortfall(&self, token_id: AccountId) -> U128 {
+        U128(self.shortfalls.get(&token_id).unwrap_or(0))
+    }
+
+    /// Sets the tax in basis points that `token_id` takes from the AMM's incoming transfers.
+    /// Only the owner can call it.
+    pub fn set_transfer_fee(&mut self, token_id: AccountId, transfer_fee: u16) {
+        self.assert_owner();
+        assert!(
+            transfer_fee <= MAX_TRANSFER_FEE,
+            "The transfer fee can't be more than {} basis points",
+            MAX_TRANSFER_FEE
+        );
+        if transfer_fee == 0 {
+            self.transfer_fees.remove(&token_id);
+        } else {
+            self.transfer_fees.insert(&token_id, &transfer_fee);
+        }
+    }
+
+    pub fn get_transfer_fee(&self, token_id: AccountId) -> u16 {
+        self.transfer_fees.get(&token_id).unwrap_or(0)
+    }
+
+    /// Compares the tracked balance of `token_id` with its `ft_balance_of`. A surplus first
+    /// covers an earlier shortfall. The main pool gets its share of the rest, in proportion to
+    /// the part of the tracked balance that is its reserve, so the liquidity providers get their
+    /// part of the rebases and pay their part of the losses. The rest of a surplus is credited
+    /// to the owner's deposits, the rest of a loss is kept as a shortfall, see `get_shortfall`.
+    /// Transfers still in flight show up as a difference too, so only the owner can call it.
+    pub fn reconcile_balance(&mut self, token_id: AccountId) -> Promise {
+        self.assert_owner();
+        self.assert_no_flash_loan();
+        ext_ft::ext(token_id.clone())
+            .ft_balance_of(env::current_account_id())
+            .then(Self::ext(env::current_account_id()).reconcile_callback(token_id))
+    }
+
+    #[private]
+    pub fn reconcile_callback(
+        &mut self,
+        token_id: AccountId,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) {
+        let balance = match balance {
+            Ok(balance) => balance.0,
+            Err(_) => {
+                log!("Failed to fetch the balance of {}", token_id);
+                return;
+            }
+        };
+        if self.flash_loan.is_some() {
+            log!("{}", AmmError::Paused);
+            return;
+        }
+        self.settle_virtual_orders();
+        let tracked = self.tracked_balances.get(&token_id).unwrap_or(0);
+        let shortfall = self.shortfalls.get(&token_id).unwrap_or(0);
+        let expected = tracked.saturating_sub(shortfall);
+        if balance == expected {
+            return;
+        }
+
+        if balance > expected {
+            let covered = (balance - expected).min(shortfall);
+            if covered > 0 {
+                log!("{} {} of the shortfall are covered", covered, token_id);
+                self.set_shortfall(&token_id, shortfall - covered);
+            }
+            let surplus = balance - expected - covered;
+            if surplus == 0 {
+                return;
+            }
+            let to_pool = self.main_pool_share(&token_id, surplus, tracked);
+            if to_pool > 0 {
+                let mut token_info = self.tokens.get(&token_id).unwrap();
+                token_info.balance = math::checked_add(token_info.balance, to_pool).or_panic();
+                self.tokens.insert(&token_id, &token_info);
+                self.k = self.main_pool_k();
+                log!("{} {} are added to the main pool", to_pool, token_id);
+            }
+            if surplus > to_pool {
+                log!(
+                    "{} {} are credited to the owner",
+                    surplus - to_pool,
+                    token_id
+                );
+                let owner_id = self.owner_id.clone();
+                self.internal_deposit(&owner_id, &token_id, surplus - to_pool);
+            }
+            self.track_received(&token_id, surplus);
+        } else {
+            let loss = expected - balance;
+            let from_pool = self.main_pool_share(&token_id, loss, tracked);
+            if from_pool > 0 {
+                let mut token_info = self.tokens.get(&token_id).unwrap();
+                token_info.balance -= from_pool;
+                self.tokens.insert(&token_id, &token_info);
+                self.k = self.main_pool_k();
+                self.track_sent(&token_id, from_pool);
+                log!("{} {} are taken from the main pool", from_pool, token_id);
+            }
+            if loss > from_pool {
+                log!("The AMM is short of {} {}", loss - from_pool, token_id);
+                self.set_shortfall(&token_id, shortfall + loss - from_pool);
+            }
+        }
+    }
+}
+
+impl AMM {
+    pub(crate) fn track_received(&mut self, token_id: &AccountId, amount: Balance) {
+        if amount > 0 {
+            let tracked = self.tracked_balances.get(token_id).unwrap_or(0);
+            self.tracked_balances
+                .insert(token_id, &tracked.saturating_add(amount));
+        }
+    }
+
+    pub(crate) fn track_sent(&mut self, token_id: &AccountId, amount: Balance) {
+        if amount > 0 {
+            let tracked = self.tracked_balances.get(token_id).unwrap_or(0);
+            self.tracked_balances
+                .insert(token_id, &tracked.saturating_sub(amount));
+        }
+    }
+
+    /// `amount` of `token_id` notified by `ft_on_transfer`, less the token's transfer fee.
+    pub(crate) fn amount_received(&self, token_id: &AccountId, amount: Balance) -> Balance {
+        let (fee_bps, max_bps) = match self.transfer_fees.get(token_id) {
+            Some(fee_bps) => (fee_bps as Balance, MAX_TRANSFER_FEE as Balance),
+            None => return amount,
+        };
+        // split so that `amount * fee_bps` can't overflow, the fee is rounded down like the
+        // tokens do
+        amount - (amount / max_bps * fee_bps + amount % max_bps * fee_bps / max_bps)
+    }
+
+    /// The part of `amount` that belongs to the main pool: the share of its reserve of
+    /// `token_id` in the `tracked` balance, or nothing for other tokens.
+    fn main_pool_share(&self, token_id: &AccountId, amount: Balance, tracked: Balance) -> Balance {
+        match self.tokens.get(token_id) {
+            Some(token_info) if tracked > 0 => {
+                let reserve = token_info.balance.min(tracked);
+                math::mul_div_down(amount, reserve, tracked)
+                    .or_panic()
+                    .min(token_info.balance)
+            }
+            _ => 0,
+        }
+    }
+
+    fn set_shortfall(&mut self, token_id: &AccountId, shortfall: Balance) {
+        if shortfall == 0 {
+            self.shortfalls.remove(token_id);
+        } else {
+            self.shortfalls.insert(token_id, &shortfall);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseOrValue};
+
+    fn setup() -> (VMContextBuilder, AMM) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let mut contract = AMM::new(accounts(1), accounts(2), accounts(3));
+        test_utils::register(&mut context, &mut contract, accounts(1));
+        for (token_id, other, amount) in [
+            (accounts(2), accounts(3), 10_000),
+            (accounts(3), accounts(2), 20_000),
+        ] {
+            testing_env!(context.predecessor_account_id(token_id.clone()).build());
+            contract.ft_on_transfer(accounts(1), U128(amount), format!("{}:{}", token_id, other));
+        }
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.add_liquidity(vec![U128(10_000), U128(20_000)], U128(0));
+        testing_env!(context
+            .predecessor_account_id(env::current_account_id())
+            .build());
+        (context, contract)
+    }
+
+    #[test]
+    fn test_swap_is_tracked() {
+        let (mut context, mut contract) = setup();
+        assert_eq!(contract.get_tracked_balance(accounts(2)).0, 10_000);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        let unused = match contract.ft_on_transfer(
+            accounts(4),
+            U128(10_000),
+            format!("{}:{}", accounts(2), accounts(3)),
+        ) {
+            PromiseOrValue::Value(unused) => unused.0,
+            PromiseOrValue::Promise(_) => unreachable!(),
+        };
+        assert_eq!(unused, 0);
+        assert_eq!(contract.get_tracked_balance(accounts(2)).0, 20_000);
+        assert_eq!(contract.get_tracked_balance(accounts(3)).0, 10_000);
+    }
+
+    #[test]
+    fn test_taxed_deposit_is_taken_from_the_reserve() {
+        let (_context, mut contract) = setup();
+        // a 1% tax on the owner's deposit of 10_000 the AMM doesn't know about
+        contract.reconcile_callback(accounts(2), Ok(U128(9_900)));
+        assert_eq!(contract.main_token(&accounts(2)).unwrap().balance, 9_900);
+        assert_eq!(contract.get_tracked_balance(accounts(2)).0, 9_900);
+        assert_eq!(contract.k, 9_900 * 20_000);
+        assert_eq!(
+            get_logs().last().unwrap(),
+            "100 charlie are taken from the main pool"
+        );
+    }
+
+    #[test]
+    fn test_rebase_is_added_to_the_reserve() {
+        let (_context, mut contract) = setup();
+        contract.reconcile_callback(accounts(3), Ok(U128(25_000)));
+        assert_eq!(contract.main_token(&accounts(3)).unwrap().balance, 25_000);
+        assert_eq!(contract.k, 10_000 * 25_000);
+        // nothing changes the second time
+        contract.reconcile_callback(accounts(3), Ok(U128(25_000)));
+        assert_eq!(contract.main_token(&accounts(3)).unwrap().balance, 25_000);
+    }
+
+    #[test]
+    fn test_surplus_of_other_token_goes_to_the_owner() {
+        let (_context, mut contract) = setup();
+        contract.reconcile_callback(accounts(4), Ok(U128(500)));
+        assert_eq!(contract.get_deposits(accounts(1))[&accounts(4)].0, 500);
+        assert_eq!(contract.get_tracked_balance(accounts(4)).0, 500);
+    }
+
+    fn deposit(context: &mut VMContextBuilder, contract: &mut AMM, token_id: AccountId) {
+        test_utils::register(context, contract, accounts(4));
+        testing_env!(context.predecessor_account_id(token_id).build());
+        contract.ft_on_transfer(accounts(4), U128(10_000), "\"Deposit\"".to_string());
+        testing_env!(context
+            .predecessor_account_id(env::current_account_id())
+            .build());
+    }
+
+    #[test]
+    fn test_transfer_fee_is_applied_on_arrival() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_transfer_fee(accounts(2), 100);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.ft_on_transfer(
+            accounts(4),
+            U128(10_000),
+            format!("{}:{}", accounts(2), accounts(3)),
+        );
+        // only the 9_900 received are swapped and tracked
+        assert_eq!(contract.main_token(&accounts(2)).unwrap().balance, 19_900);
+        assert_eq!(contract.get_tracked_balance(accounts(2)).0, 19_900);
+
+        // a failed transfer refunds what was received
+        let unused = match contract.ft_on_transfer(
+            accounts(4),
+            U128(10_000),
+            format!("{}:{}", accounts(2), accounts(5)),
+        ) {
+            PromiseOrValue::Value(unused) => unused.0,
+            PromiseOrValue::Promise(_) => unreachable!(),
+        };
+        assert_eq!(unused, 9_900);
+        assert_eq!(contract.get_tracked_balance(accounts(2)).0, 19_900);
+    }
+
+    #[test]
+    fn test_loss_is_shared_with_deposits() {
+        let (mut context, mut contract) = setup();
+        deposit(&mut context, &mut contract, accounts(2));
+        assert_eq!(contract.get_tracked_balance(accounts(2)).0, 20_000);
+
+        // half of the balance is the reserve, it pays half of the loss
+        contract.reconcile_callback(accounts(2), Ok(U128(19_000)));
+        assert_eq!(contract.main_token(&accounts(2)).unwrap().balance, 9_500);
+        assert_eq!(contract.get_tracked_balance(accounts(2)).0, 19_500);
+        assert_eq!(contract.get_shortfall(accounts(2)).0, 500);
+        assert_eq!(
+            get_logs(),
+            vec![
+                "500 charlie are taken from the main pool",
+                "The AMM is short of 500 charlie"
+            ]
+        );
+
+        // the shortfall isn't taken twice
+        contract.reconcile_callback(accounts(2), Ok(U128(19_000)));
+        assert_eq!(contract.main_token(&accounts(2)).unwrap().balance, 9_500);
+        assert_eq!(contract.get_shortfall(accounts(2)).0, 500);
+
+        // a surplus covers it first
+        contract.reconcile_callback(accounts(2), Ok(U128(19_800)));
+        assert_eq!(contract.get_shortfall(accounts(2)).0, 0);
+        assert_eq!(contract.main_token(&accounts(2)).unwrap().balance, 9_646);
+        assert_eq!(contract.get_deposits(accounts(1))[&accounts(2)].0, 154);
+        assert_eq!(contract.get_tracked_balance(accounts(2)).0, 19_800);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_reconcile_not_owner() {
+        let (mut context, mut contract) = setup();
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.reconcile_balance(accounts(2));
+    }
+}
@@ -0,0 +1,222 @@
+//! Storage registration of the accounts, after NEP-145.
+//!
+//! Deposits, pending liquidity and shares of the main pool are kept by account, so an account
+//! pays for that storage with `storage_deposit` before it can hold any of them. The balance is
+//! fixed at [`ACCOUNT_STORAGE`] bytes, nothing of it is available to withdraw, and
+//! `storage_unregister` returns it once the account holds nothing.
+
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
+use near_sdk::json_types::U128;
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, AccountId, Balance, StorageUsage};
+
+use crate::*;
+
+/// Storage of the deposits, pending liquidity and shares of an account holding a few tokens.
+pub const ACCOUNT_STORAGE: StorageUsage = 1_000;
+
+#[near_bindgen]
+impl StorageManagement for AMM {
+    /// Registers `account_id`, or the caller, for the deposit of `storage_balance_bounds`. The
+    /// balance is fixed, so the excess is refunded whatever `registration_only` is, and all of
+    /// it if the account is already registered.
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let _ = registration_only;
+        let payer_id = env::predecessor_account_id();
+        let account_id = account_id.unwrap_or_else(|| payer_id.clone());
+        let deposit = env::attached_deposit();
+        let refund = if self.storage_deposits.contains_key(&account_id) {
+            log!("@{} is already registered", account_id);
+            deposit
+        } else {
+            let storage_deposit = self.storage_balance_bounds().min.0;
+            assert!(
+                deposit >= storage_deposit,
+                "The registration needs a deposit of {} yoctoNEAR, attached {}",
+                storage_deposit,
+                deposit
+            );
+            self.storage_deposits.insert(&account_id, &storage_deposit);
+            log!("@{} is registered", account_id);
+            deposit - storage_deposit
+        };
+        if refund > 0 {
+            Promise::new(payer_id).transfer(refund);
+        }
+        self.storage_balance_of(account_id).unwrap()
+    }
+
+    /// Nothing of the balance is available, only `amount` 0 or `None` is accepted.
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let balance = self
+            .storage_balance_of(account_id.clone())
+            .unwrap_or_else(|| panic!("{}", AmmError::NotRegistered(account_id)));
+        let amount = amount.map_or(0, |amount| amount.0);
+        assert!(
+            amount <= balance.available.0,
+            "Not enough storage balance available: {} < {}",
+            balance.available.0,
+            amount
+        );
+        balance
+    }
+
+    /// Returns the caller's storage deposit once it has no deposits, pending liquidity or
+    /// shares. `force` isn't supported: the tokens must be withdrawn first.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        assert!(
+            !force.unwrap_or(false),
+            "Unregistering with force is not supported, withdraw the tokens first"
+        );
+        let account_id = env::predecessor_account_id();
+        let storage_deposit = match self.storage_deposits.get(&account_id) {
+            Some(storage_deposit) => storage_deposit,
+            None => return false,
+        };
+        assert!(
+            self.deposits.get(&account_id).is_none()
+                && self.pending_liquidity.get(&account_id).is_none()
+                && self.main_shares.get(&account_id).unwrap_or(0) == 0,
+            "@{} still holds deposits, pending liquidity or shares",
+            account_id
+        );
+        self.main_shares.remove(&account_id);
+        self.storage_deposits.remove(&account_id);
+        log!("@{} is unregistered", account_id);
+        Promise::new(account_id).transfer(storage_deposit);
+        true
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        let storage_deposit = U128(ACCOUNT_STORAGE as Balance * env::storage_byte_cost());
+        StorageBalanceBounds {
+            min: storage_deposit,
+            max: Some(storage_deposit),
+        }
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_deposits
+            .get(&account_id)
+            .map(|storage_deposit| StorageBalance {
+                total: U128(storage_deposit),
+                available: U128(0),
+            })
+    }
+}
+
+impl AMM {
+    pub(crate) fn is_registered(&self, account_id: &AccountId) -> bool {
+        self.storage_deposits.contains_key(account_id)
+    }
+
+    pub(crate) fn check_registered(&self, account_id: &AccountId) -> AmmResult<()> {
+        if self.is_registered(account_id) {
+            Ok(())
+        } else {
+            Err(AmmError::NotRegistered(account_id.clone()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{register, setup_main_pool, transfer};
+    use near_sdk::test_utils::{accounts, get_logs};
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_deposit_needs_registration() {
+        let (mut context, mut contract) = setup_main_pool();
+        let msg = "\"Deposit\"".to_string();
+        let unused = transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            100,
+            msg.clone(),
+        );
+        assert_eq!(unused, 100);
+        assert_eq!(
+            get_logs(),
+            vec!["100 charlie are refunded: E_NOT_REGISTERED: @eugene is not registered, pay for its storage with storage_deposit"]
+        );
+        assert!(contract.get_deposits(accounts(4)).is_empty());
+
+        register(&mut context, &mut contract, accounts(4));
+        let unused = transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            100,
+            msg,
+        );
+        assert_eq!(unused, 0);
+        assert_eq!(contract.get_deposits(accounts(4))[&accounts(2)].0, 100);
+    }
+
+    #[test]
+    fn test_register_and_unregister() {
+        let (mut context, mut contract) = setup_main_pool();
+        assert!(contract.storage_balance_of(accounts(4)).is_none());
+        register(&mut context, &mut contract, accounts(4));
+        let deposit = contract.storage_balance_bounds().min;
+        let balance = contract.storage_balance_of(accounts(4)).unwrap();
+        assert_eq!(balance.total, deposit);
+        assert_eq!(balance.available.0, 0);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+        assert!(contract.storage_unregister(None));
+        assert!(contract.storage_balance_of(accounts(4)).is_none());
+        assert!(!contract.storage_unregister(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "@eugene still holds deposits, pending liquidity or shares")]
+    fn test_unregister_with_deposits() {
+        let (mut context, mut contract) = setup_main_pool();
+        register(&mut context, &mut contract, accounts(4));
+        let msg = "\"Deposit\"".to_string();
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            100,
+            msg,
+        );
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The registration needs a deposit of")]
+    fn test_registration_needs_deposit() {
+        let (mut context, mut contract) = setup_main_pool();
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+        contract.storage_deposit(None, None);
+    }
+}
@@ -0,0 +1,335 @@
+//! Recent swaps of every account and the volume of every pool, so frontends can show them
+//! without an indexer.
+//!
+//! An account that opted in with `enable_swap_history` keeps its last [`SWAP_HISTORY_LEN`]
+//! swaps in a ring buffer, the storage is paid with a deposit returned by
+//! `disable_swap_history`. The volume of a token in a pool counts it both bought and sold, in
+//! total and in hourly buckets for the last 24 hours.
+
+use std::collections::HashMap;
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::Serialize;
+use near_sdk::{env, log, near_bindgen, AccountId, Balance, Promise, StorageUsage};
+
+use crate::*;
+
+pub const SWAP_HISTORY_LEN: usize = 50;
+/// Storage of a full history: a record takes up to 185 bytes with the longest account ids.
+const SWAP_HISTORY_STORAGE: StorageUsage = 10_000;
+const HOUR: u64 = 3_600_000_000_000;
+const DAY_HOURS: u64 = 24;
+const DEFAULT_LIMIT: u64 = 20;
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapRecord {
+    /// `None` for the main pool.
+    pub pool_id: Option<u64>,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub amount_in: U128,
+    pub amount_out: U128,
+    /// Block timestamp in nanoseconds.
+    pub timestamp: U64,
+}
+
+/// Ring buffer of the last swaps of an account.
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+pub struct SwapHistory {
+    records: Vec<SwapRecord>,
+    /// Where the next record goes once the buffer is full, the oldest record is there.
+    next: usize,
+    /// Paid for the storage of the history, returned when it's disabled.
+    storage_deposit: Balance,
+}
+
+impl SwapHistory {
+    fn push(&mut self, record: SwapRecord) {
+        if self.records.len() < SWAP_HISTORY_LEN {
+            self.records.push(record);
+        } else {
+            self.records[self.next] = record;
+            self.next = (self.next + 1) % SWAP_HISTORY_LEN;
+        }
+    }
+
+    /// The records from the newest to the oldest.
+    fn newest_first(&self) -> impl Iterator<Item = &SwapRecord> {
+        let (older, newer) = self.records.split_at(self.next);
+        older.iter().rev().chain(newer.iter().rev())
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+pub struct PoolVolume {
+    total: HashMap<AccountId, Balance>,
+    /// Volume by hour since the epoch, for the last 24 hours.
+    hourly: Vec<(u64, HashMap<AccountId, Balance>)>,
+}
+
+impl PoolVolume {
+    fn add(&mut self, token_id: &AccountId, amount: Balance, now: u64) {
+        let total = self.total.entry(token_id.clone()).or_default();
+        *total = total.saturating_add(amount);
+
+        let hour = now / HOUR;
+        self.hourly.retain(|(bucket, _)| *bucket + DAY_HOURS > hour);
+        let volumes = match self.hourly.iter().position(|(bucket, _)| *bucket == hour) {
+            Some(i) => &mut self.hourly[i].1,
+            None => {
+                self.hourly.push((hour, HashMap::new()));
+                &mut self.hourly.last_mut().unwrap().1
+            }
+        };
+        let volume = volumes.entry(token_id.clone()).or_default();
+        *volume = volume.saturating_add(amount);
+    }
+
+    fn last_day(&self, now: u64) -> HashMap<AccountId, Balance> {
+        let hour = now / HOUR;
+        let mut volumes = HashMap::<AccountId, Balance>::new();
+        for (_, bucket) in self
+            .hourly
+            .iter()
+            .filter(|(bucket, _)| *bucket + DAY_HOURS > hour)
+        {
+            for (token_id, amount) in bucket {
+                let volume = volumes.entry(token_id.clone()).or_default();
+                *volume = volume.saturating_add(*amount);
+            }
+        }
+        volumes
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VolumeView {
+    pub total: HashMap<AccountId, U128>,
+    pub last_24h: HashMap<AccountId, U128>,
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Starts recording the caller's swaps. The deposit of `get_swap_history_deposit` pays
+    /// for the storage, the excess is refunded.
+    #[payable]
+    pub fn enable_swap_history(&mut self) {
+        let account_id = env::predecessor_account_id();
+        assert!(
+            self.swap_history.get(&account_id).is_none(),
+            "The swap history of @{} is already enabled",
+            account_id
+        );
+        let storage_deposit = self.get_swap_history_deposit().0;
+        let deposit = env::attached_deposit();
+        assert!(
+            deposit >= storage_deposit,
+            "The swap history needs a deposit of {} yoctoNEAR, attached {}",
+            storage_deposit,
+            deposit
+        );
+        self.swap_history.insert(
+            &account_id,
+            &SwapHistory {
+                storage_deposit,
+                ..Default::default()
+            },
+        );
+        log!("@{} enabled the swap history", account_id);
+        if deposit > storage_deposit {
+            Promise::new(account_id).transfer(deposit - storage_deposit);
+        }
+    }
+
+    /// Deletes the caller's swap history and returns its storage deposit.
+    pub fn disable_swap_history(&mut self) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let history = self
+            .swap_history
+            .remove(&account_id)
+            .unwrap_or_else(|| panic!("The swap history of @{} isn't enabled", account_id));
+        log!("@{} disabled the swap history", account_id);
+        Promise::new(account_id).transfer(history.storage_deposit)
+    }
+
+    /// The deposit `enable_swap_history` needs, in yoctoNEAR.
+    pub fn get_swap_history_deposit(&self) -> U128 {
+        U128(SWAP_HISTORY_STORAGE as Balance * env::storage_byte_cost())
+    }
+
+    /// Swaps of `account_id`, from the newest. At most [`SWAP_HISTORY_LEN`] are kept, and only
+    /// while the history is enabled.
+    pub fn get_swap_history(
+        &self,
+        account_id: AccountId,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<SwapRecord> {
+        self.swap_history
+            .get(&account_id)
+            .unwrap_or_default()
+            .newest_first()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(DEFAULT_LIMIT) as usize)
+            .cloned()
+            .collect()
+    }
+
+    /// Volume of each token of the pool, or of the main pool if `pool_id` is `None`.
+    pub fn get_pool_volume(&self, pool_id: Option<u64>) -> VolumeView {
+        let volume = self.pool_volumes.get(&pool_id).unwrap_or_default();
+        let to_view = |volumes: HashMap<AccountId, Balance>| {
+            volumes
+                .into_iter()
+                .map(|(token_id, amount)| (token_id, U128(amount)))
+                .collect()
+        };
+        VolumeView {
+            last_24h: to_view(volume.last_day(env::block_timestamp())),
+            total: to_view(volume.total),
+        }
+    }
+}
+
+impl AMM {
+    pub(crate) fn record_swap(
+        &mut self,
+        account_id: &AccountId,
+        pool_id: Option<u64>,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+        amount_out: Balance,
+    ) {
+        let now = env::block_timestamp();
+        if let Some(mut history) = self.swap_history.get(account_id) {
+            history.push(SwapRecord {
+                pool_id,
+                token_in: token_in.clone(),
+                token_out: token_out.clone(),
+                amount_in: U128(amount_in),
+                amount_out: U128(amount_out),
+                timestamp: U64(now),
+            });
+            self.swap_history.insert(account_id, &history);
+        }
+
+        let mut volume = self.pool_volumes.get(&pool_id).unwrap_or_default();
+        volume.add(token_in, amount_in, now);
+        volume.add(token_out, amount_out, now);
+        self.pool_volumes.insert(&pool_id, &volume);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, VMContext};
+
+    fn setup() -> (VMContextBuilder, AMM) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let contract = AMM::new(accounts(1), accounts(2), accounts(3));
+        (context, contract)
+    }
+
+    fn with_deposit(
+        context: &mut VMContextBuilder,
+        account_id: AccountId,
+        deposit: Balance,
+    ) -> VMContext {
+        context
+            .predecessor_account_id(account_id)
+            .attached_deposit(deposit)
+            .build()
+    }
+
+    #[test]
+    fn test_history_keeps_the_last_swaps() {
+        let (mut context, mut contract) = setup();
+        let deposit = contract.get_swap_history_deposit().0;
+        testing_env!(with_deposit(&mut context, accounts(4), deposit));
+        contract.enable_swap_history();
+        for amount in 0..SWAP_HISTORY_LEN as u128 + 5 {
+            contract.record_swap(
+                &accounts(4),
+                None,
+                &accounts(2),
+                amount,
+                &accounts(3),
+                amount,
+            );
+        }
+        let history = contract.get_swap_history(accounts(4), None, Some(100));
+        assert_eq!(history.len(), SWAP_HISTORY_LEN);
+        assert_eq!(history[0].amount_in.0, SWAP_HISTORY_LEN as u128 + 4);
+        assert_eq!(history.last().unwrap().amount_in.0, 5);
+
+        let page = contract.get_swap_history(accounts(4), Some(2), Some(3));
+        let amounts: Vec<_> = page.iter().map(|record| record.amount_in.0).collect();
+        assert_eq!(amounts, vec![52, 51, 50]);
+        assert!(contract
+            .get_swap_history(accounts(5), None, None)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_history_is_opt_in() {
+        let (mut context, mut contract) = setup();
+        contract.record_swap(&accounts(4), None, &accounts(2), 100, &accounts(3), 50);
+        assert!(contract
+            .get_swap_history(accounts(4), None, None)
+            .is_empty());
+        // the volume is counted anyway
+        assert_eq!(contract.get_pool_volume(None).total[&accounts(2)].0, 100);
+
+        let deposit = contract.get_swap_history_deposit().0;
+        testing_env!(with_deposit(&mut context, accounts(4), deposit + 1));
+        contract.enable_swap_history();
+        // the excess is refunded
+        assert_eq!(get_created_receipts().len(), 1);
+        contract.record_swap(&accounts(4), None, &accounts(2), 100, &accounts(3), 50);
+        assert_eq!(contract.get_swap_history(accounts(4), None, None).len(), 1);
+
+        testing_env!(with_deposit(&mut context, accounts(4), 0));
+        drop(contract.disable_swap_history());
+        assert_eq!(get_created_receipts().len(), 1);
+        contract.record_swap(&accounts(4), None, &accounts(2), 100, &accounts(3), 50);
+        assert!(contract
+            .get_swap_history(accounts(4), None, None)
+            .is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "The swap history needs a deposit of")]
+    fn test_history_without_deposit() {
+        let (mut context, mut contract) = setup();
+        let deposit = contract.get_swap_history_deposit().0;
+        testing_env!(with_deposit(&mut context, accounts(4), deposit - 1));
+        contract.enable_swap_history();
+    }
+
+    #[test]
+    fn test_volume() {
+        let (mut context, mut contract) = setup();
+        contract.record_swap(&accounts(4), Some(0), &accounts(2), 100, &accounts(3), 50);
+        testing_env!(context.block_timestamp(20 * HOUR).build());
+        contract.record_swap(&accounts(5), Some(0), &accounts(3), 10, &accounts(2), 20);
+
+        let volume = contract.get_pool_volume(Some(0));
+        assert_eq!(volume.total[&accounts(2)].0, 120);
+        assert_eq!(volume.total[&accounts(3)].0, 60);
+        assert_eq!(volume.last_24h[&accounts(2)].0, 120);
+
+        testing_env!(context.block_timestamp(30 * HOUR).build());
+        let volume = contract.get_pool_volume(Some(0));
+        assert_eq!(volume.total[&accounts(2)].0, 120);
+        assert_eq!(volume.last_24h[&accounts(2)].0, 20);
+        assert_eq!(volume.last_24h[&accounts(3)].0, 10);
+        assert!(contract.get_pool_volume(None).total.is_empty());
+    }
+}
@@ -0,0 +1,78 @@
+//! Helpers shared by the unit tests of the main pool features.
+
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use near_sdk::test_utils::{accounts, VMContextBuilder};
+use near_sdk::{testing_env, AccountId, Balance, PromiseOrValue};
+
+use crate::AMM;
+
+/// Calls `ft_on_transfer` as the `token` contract and returns the unused amount.
+pub fn transfer(
+    context: &mut VMContextBuilder,
+    contract: &mut AMM,
+    token: AccountId,
+    sender: AccountId,
+    amount: Balance,
+    msg: String,
+) -> Balance {
+    testing_env!(context.predecessor_account_id(token).build());
+    match contract.ft_on_transfer(sender, U128(amount), msg) {
+        PromiseOrValue::Value(unused) => unused.0,
+        PromiseOrValue::Promise(_) => unreachable!(),
+    }
+}
+
+/// Seeds the main pool with `amounts` of `accounts(2)` and `accounts(3)` from the owner
+/// `accounts(1)`: the owner registers, sends them and adds them as liquidity.
+pub fn seed_main_pool(context: &mut VMContextBuilder, contract: &mut AMM, amounts: [Balance; 2]) {
+    if !contract.is_registered(&accounts(1)) {
+        register(context, contract, accounts(1));
+    }
+    for ((token, other), amount) in [(accounts(2), accounts(3)), (accounts(3), accounts(2))]
+        .into_iter()
+        .zip(amounts)
+    {
+        let msg = format!("{}:{}", token, other);
+        transfer(context, contract, token, accounts(1), amount, msg);
+    }
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    contract.add_liquidity(vec![U128(amounts[0]), U128(amounts[1])], U128(0));
+}
+
+/// A main pool of 1_000_000 of `accounts(2)` and 1_000_000 of `accounts(3)`, seeded by the
+/// owner `accounts(1)`.
+pub fn setup_main_pool() -> (VMContextBuilder, AMM) {
+    let mut context = VMContextBuilder::new();
+    testing_env!(context.predecessor_account_id(accounts(1)).build());
+    let mut contract = AMM::new(accounts(1), accounts(2), accounts(3));
+    seed_main_pool(&mut context, &mut contract, [1_000_000, 1_000_000]);
+    (context, contract)
+}
+
+/// Opts `account_id` in to the swap history.
+pub fn enable_swap_history(
+    context: &mut VMContextBuilder,
+    contract: &mut AMM,
+    account_id: AccountId,
+) {
+    let deposit = contract.get_swap_history_deposit().0;
+    testing_env!(context
+        .predecessor_account_id(account_id)
+        .attached_deposit(deposit)
+        .build());
+    contract.enable_swap_history();
+    testing_env!(context.attached_deposit(0).build());
+}
+
+/// Registers `account_id` with the storage deposit of `storage_deposit`.
+pub fn register(context: &mut VMContextBuilder, contract: &mut AMM, account_id: AccountId) {
+    let deposit = contract.storage_balance_bounds().min.0;
+    testing_env!(context
+        .predecessor_account_id(account_id)
+        .attached_deposit(deposit)
+        .build());
+    contract.storage_deposit(None, None);
+    testing_env!(context.attached_deposit(0).build());
+}
@@ -0,0 +1,329 @@
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::Deserialize;
+use near_sdk::{env, log, near_bindgen, serde_json, AccountId, PromiseOrValue};
+
+use crate::*;
+
+/// JSON messages accepted by `ft_on_transfer`. A msg that isn't JSON is read in the original
+/// `sell_token:buy_token` format and goes to the main pool.
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum TokenReceiverMessage {
+    /// Swaps the received tokens for `token_out`. `pool_id` is `None` for the main pool.
+    /// With `amount_out` the swap buys exactly that amount and the rest of the received tokens
+    /// is returned. The tokens are refunded if the swap executes after `deadline`, a block
+    /// timestamp in nanoseconds. The output goes to `receiver_id`, the sender by default, with
+    /// `ft_transfer_call` and `forward_msg` if it's set.
+    Swap {
+        pool_id: Option<u64>,
+        token_out: AccountId,
+        min_amount_out: Option<U128>,
+        amount_out: Option<U128>,
+        deadline: Option<U64>,
+        receiver_id: Option<AccountId>,
+        forward_msg: Option<String>,
+    },
+    /// Adds the received tokens to a weighted pool. While the pool isn't seeded only the owner
+    /// can deposit, afterwards it's a single-token join that mints shares.
+    JoinPool { pool_id: u64, min_shares: U128 },
+    /// Credits the received tokens to the sender's deposits, e.g. to open a concentrated
+    /// liquidity position with them later.
+    Deposit,
+    /// Holds the received tokens until `add_liquidity` adds them to the main pool together with
+    /// the other token.
+    AddLiquidity,
+    /// Adds the received tokens to the main pool on their own: the right part of them is
+    /// swapped for the other token first.
+    AddLiquiditySingle { min_shares: U128 },
+    /// Repays the flash loan in progress, see [`AMM::flash_loan`].
+    FlashLoanRepayment,
+    /// Escrows the received tokens in a limit order on the main pool. It's filled once the
+    /// whole amount swaps for at least `min_amount_out` of `token_out`, or cancelled with
+    /// `cancel_order`.
+    PlaceLimitOrder {
+        token_out: AccountId,
+        min_amount_out: U128,
+    },
+    /// Swaps the received tokens for `token_out` on the main pool in `swaps` equal parts, one
+    /// every `interval` nanoseconds, see [`AMM::execute_due_orders`].
+    PlaceDcaOrder(DcaParams),
+    /// Sells the received tokens for `token_out` on the main pool evenly over at least `blocks`
+    /// blocks, see [`AMM::execute_virtual_orders`]. The part that doesn't divide evenly between
+    /// the blocks is refunded.
+    PlaceLongTermOrder { token_out: AccountId, blocks: U64 },
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for AMM {
+    /// Transfers that fail with an [`AmmError`] are refunded, the error is logged as the reason.
+    /// Only the amount left after the token's transfer fee is used and refunded.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let token_in = env::predecessor_account_id();
+        let amount = self.amount_received(&token_in, amount.0);
+        match self.internal_on_transfer(sender_id, &token_in, amount, &msg) {
+            Ok(unused) => {
+                self.track_received(&token_in, amount - unused);
+                PromiseOrValue::Value(U128(unused))
+            }
+            Err(err) => {
+                log!("{} {} are refunded: {}", amount, token_in, err);
+                PromiseOrValue::Value(U128(amount))
+            }
+        }
+    }
+}
+
+impl AMM {
+    /// Handles a transfer and returns the amount to refund.
+    fn internal_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        token_in: &AccountId,
+        amount: Balance,
+        msg: &str,
+    ) -> AmmResult<Balance> {
+        let message = match serde_json::from_str::<TokenReceiverMessage>(msg) {
+            Ok(message) => message,
+            Err(_) => return self.on_legacy_transfer(sender_id, amount, msg),
+        };
+        self.check_token_accepted(token_in)?;
+        match message {
+            TokenReceiverMessage::Swap {
+                pool_id,
+                token_out,
+                min_amount_out,
+                amount_out,
+                deadline,
+                receiver_id,
+                forward_msg,
+            } => {
+                if let Some(deadline) = deadline {
+                    if env::block_timestamp() > deadline.0 {
+                        log!(
+                            "The swap expired at {}, {} {} are refunded",
+                            deadline.0,
+                            amount,
+                            token_in
+                        );
+                        return Ok(amount);
+                    }
+                }
+                let (amount_in, amount_out) = match amount_out {
+                    Some(amount_out) => {
+                        if min_amount_out.is_some() {
+                            return Err(AmmError::BadMsg(
+                                "min_amount_out can't be used together with amount_out".to_string(),
+                            ));
+                        }
+                        let amount_in = self.internal_swap_exact_out(
+                            pool_id,
+                            token_in,
+                            amount,
+                            amount_out.0,
+                            &token_out,
+                        )?;
+                        (amount_in, amount_out.0)
+                    }
+                    None => {
+                        let min_amount_out = min_amount_out.map(u128::from).unwrap_or(0);
+                        let amount_out = self.internal_swap(
+                            pool_id,
+                            token_in,
+                            amount,
+                            &token_out,
+                            min_amount_out,
+                        )?;
+                        (amount, amount_out)
+                    }
+                };
+                log!(
+                    "Swapped {} {} for {} {}",
+                    amount_in,
+                    token_in,
+                    amount_out,
+                    token_out
+                );
+                self.record_swap(
+                    &sender_id, pool_id, token_in, amount_in, &token_out, amount_out,
+                );
+                if pool_id.is_none() {
+                    self.fill_limit_orders(&sender_id, &token_out);
+                }
+
+                let receiver_id = receiver_id.unwrap_or_else(|| sender_id.clone());
+                self.internal_payout(sender_id, receiver_id, token_out, amount_out, forward_msg);
+                return Ok(amount - amount_in);
+            }
+            TokenReceiverMessage::JoinPool {
+                pool_id,
+                min_shares,
+            } => {
+                let mut pool = self.pool_by_id(pool_id)?;
+                let weighted = pool.weighted()?;
+                if weighted.is_seeded() {
+                    let shares = weighted.join(&sender_id, token_in, amount, min_shares.0)?;
+                    log!(
+                        "@{} joined the pool {} for {} shares",
+                        sender_id,
+                        pool_id,
+                        shares
+                    );
+                } else {
+                    if sender_id != self.owner_id {
+                        return Err(AmmError::Unauthorized(
+                            "The pool is not seeded yet".to_string(),
+                        ));
+                    }
+                    weighted.seed(&sender_id, token_in, amount)?;
+                }
+                self.pools.replace(pool_id, &pool);
+            }
+            TokenReceiverMessage::Deposit => {
+                self.check_registered(&sender_id)?;
+                self.internal_deposit(&sender_id, token_in, amount);
+            }
+            TokenReceiverMessage::AddLiquidity => {
+                self.check_registered(&sender_id)?;
+                self.add_pending_liquidity(&sender_id, token_in, amount)?;
+            }
+            TokenReceiverMessage::AddLiquiditySingle { min_shares } => {
+                self.check_registered(&sender_id)?;
+                self.add_liquidity_single(&sender_id, token_in, amount, min_shares.0)?;
+            }
+            TokenReceiverMessage::FlashLoanRepayment => {
+                self.repay_flash_loan(&sender_id, token_in, amount)?;
+            }
+            TokenReceiverMessage::PlaceLimitOrder {
+                token_out,
+                min_amount_out,
+            } => {
+                self.place_limit_order(sender_id, token_in, amount, token_out, min_amount_out.0)?;
+            }
+            TokenReceiverMessage::PlaceDcaOrder(params) => {
+                self.place_dca_order(sender_id, token_in, amount, params)?;
+            }
+            TokenReceiverMessage::PlaceLongTermOrder { token_out, blocks } => {
+                return self
+                    .place_long_term_order(sender_id, token_in, amount, token_out, blocks.0);
+            }
+        }
+        Ok(0)
+    }
+
+    /// Swaps in a pool, or in the main pool if `pool_id` is `None`, and returns the output.
+    /// Errors are returned, the tokens can be refunded then.
+    fn internal_swap(
+        &mut self,
+        pool_id: Option<u64>,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> AmmResult<Balance> {
+        match pool_id {
+            Some(pool_id) => {
+                let mut pool = self.pool_by_id(pool_id)?;
+                let amount_out = pool.swap(token_in, amount_in, token_out, min_amount_out)?;
+                self.pools.replace(pool_id, &pool);
+                Ok(amount_out)
+            }
+            None => {
+                self.check_no_flash_loan()?;
+                self.settle_virtual_orders();
+                let amount_out = self.get_main_return(token_in, amount_in, token_out)?;
+                if amount_out < min_amount_out {
+                    return Err(AmmError::below_minimum(amount_out, min_amount_out));
+                }
+                self.swap_main(token_in, amount_in, token_out)
+            }
+        }
+    }
+
+    /// Swaps for exactly `amount_out` and returns the input used.
+    fn internal_swap_exact_out(
+        &mut self,
+        pool_id: Option<u64>,
+        token_in: &AccountId,
+        max_amount_in: Balance,
+        amount_out: Balance,
+        token_out: &AccountId,
+    ) -> AmmResult<Balance> {
+        match pool_id {
+            Some(pool_id) => {
+                let mut pool = self.pool_by_id(pool_id)?;
+                let amount_in =
+                    pool.swap_exact_out(token_in, max_amount_in, amount_out, token_out)?;
+                self.pools.replace(pool_id, &pool);
+                Ok(amount_in)
+            }
+            None => self.swap_main_exact_out(token_in, max_amount_in, amount_out, token_out),
+        }
+    }
+
+    /// Handles the `sell_token:buy_token` (or just `buy_token`) msg: the owner's transfers are
+    /// credited to the main pool until it has shares and become pending liquidity afterwards,
+    /// everyone else's are swapped. The sell token is always the
+    /// calling token contract, a sell token in the msg must match it. Returns the amount to
+    /// refund.
+    fn on_legacy_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: u128,
+        msg: &str,
+    ) -> AmmResult<Balance> {
+        let sell_token = &env::predecessor_account_id();
+        self.main_token(sell_token)?;
+
+        // Get tokens' accounts.
+        let accounts = msg
+            .split(':')
+            .map(|x| x.parse::<AccountId>())
+            .collect::<Result<Vec<AccountId>, _>>()
+            .map_err(|_| AmmError::BadMsg(msg.to_string()))?;
+        let buy_token = match accounts.as_slice() {
+            [buy_token] => buy_token,
+            [msg_sell_token, buy_token] => {
+                if msg_sell_token != sell_token {
+                    return Err(AmmError::BadMsg(format!(
+                        "the sell token {} doesn't match the calling token contract",
+                        msg_sell_token
+                    )));
+                }
+                buy_token
+            }
+            _ => return Err(AmmError::BadMsg(msg.to_string())),
+        };
+        self.main_token(buy_token)?;
+
+        if sender_id == self.owner_id {
+            // The owner adds liquidity like anyone, a one-sided seed can't be swapped against.
+            self.check_registered(&sender_id)?;
+            self.add_pending_liquidity(&sender_id, sell_token, amount)?;
+            log!(
+                "{} {} are pending for @{}, add them to the main pool with add_liquidity",
+                amount,
+                sell_token,
+                sender_id
+            );
+        } else {
+            let b = self.swap_main(sell_token, amount, buy_token)?;
+            self.record_swap(&sender_id, None, sell_token, amount, buy_token, b);
+            self.fill_limit_orders(&sender_id, buy_token);
+
+            log!("amount to transfer: {}", b);
+
+            // transfer buy_token to initializer of swap operation
+            self.track_sent(buy_token, b);
+            ext_ft::ext(buy_token.clone())
+                .with_attached_deposit(1)
+                .ft_transfer(sender_id, U128::from(b), None);
+        }
+        Ok(0)
+    }
+}
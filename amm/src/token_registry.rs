@@ -0,0 +1,396 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::{env, is_promise_success, log, near_bindgen, AccountId, Balance, Promise};
+
+use crate::error::OrPanic;
+use crate::*;
+
+/// The storage bond paid for a registered token.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct TokenBond {
+    pub account_id: AccountId,
+    pub bond: Balance,
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Lets the owner accept tokens without a storage bond.
+    pub fn add_whitelisted_tokens(&mut self, token_ids: Vec<AccountId>) {
+        self.assert_owner();
+        for token_id in token_ids {
+            self.whitelisted_tokens.insert(&token_id);
+        }
+    }
+
+    pub fn remove_whitelisted_token(&mut self, token_id: AccountId) {
+        self.assert_owner();
+        self.whitelisted_tokens.remove(&token_id);
+    }
+
+    pub fn get_whitelisted_tokens(&self) -> Vec<AccountId> {
+        self.whitelisted_tokens.to_vec()
+    }
+
+    /// Lists a token for anyone who pays the storage bond: the AMM's registration on the token
+    /// contract plus the storage of the listing. The rest of the attached deposit is refunded,
+    /// and all of it if the registration on the token contract fails.
+    #[payable]
+    pub fn register_token(&mut self, token_id: AccountId) {
+        assert!(
+            !self.is_token_accepted(&token_id),
+            "The token {} is already accepted",
+            token_id
+        );
+        let account_id = env::predecessor_account_id();
+        let storage_usage = env::storage_usage();
+        let mut token_bond = TokenBond {
+            account_id: account_id.clone(),
+            bond: 0,
+        };
+        self.registered_tokens.insert(&token_id, &token_bond);
+        let storage_cost =
+            Balance::from(env::storage_usage() - storage_usage) * env::storage_byte_cost();
+
+        let bond = MIN_STORAGE + storage_cost;
+        let deposit = env::attached_deposit();
+        assert!(
+            deposit >= bond,
+            "The storage bond is {} yoctoNEAR, attached {}",
+            bond,
+            deposit
+        );
+        token_bond.bond = bond;
+        self.registered_tokens.insert(&token_id, &token_bond);
+        log!("@{} registered the token {}", account_id, token_id);
+
+        ext_ft::ext(token_id.clone())
+            .with_attached_deposit(MIN_STORAGE)
+            .storage_deposit(env::current_account_id(), false)
+            .then(Self::ext(env::current_account_id()).register_token_callback(token_id));
+        if deposit > bond {
+            Promise::new(account_id).transfer(deposit - bond);
+        }
+    }
+
+    /// Undoes the registration and refunds the bond if the AMM couldn't register on the token
+    /// contract. Returns whether the token is registered.
+    #[private]
+    pub fn register_token_callback(&mut self, token_id: AccountId) -> bool {
+        if is_promise_success() {
+            return true;
+        }
+        if let Some(token_bond) = self.registered_tokens.remove(&token_id) {
+            log!(
+                "Failed to register on {}, {} yoctoNEAR are refunded to @{}",
+                token_id,
+                token_bond.bond,
+                token_bond.account_id
+            );
+            Promise::new(token_bond.account_id).transfer(token_bond.bond);
+        }
+        false
+    }
+
+    /// Delists a token registered by the caller, once no pool uses it, and returns the bond. The
+    /// AMM's storage on the token contract is released first, which fails while the AMM still
+    /// holds some of the token. A token whitelisted since keeps the AMM's storage and the bond
+    /// is returned right away.
+    pub fn unregister_token(&mut self, token_id: AccountId) -> Promise {
+        let token_bond = self
+            .registered_tokens
+            .get(&token_id)
+            .unwrap_or_else(|| panic!("The token {} is not registered", token_id));
+        if token_bond.account_id != env::predecessor_account_id() {
+            panic!(
+                "{}",
+                AmmError::Unauthorized(
+                    "Only the account that paid the bond can unregister the token".to_string()
+                )
+            );
+        }
+        assert!(
+            !self
+                .pools
+                .iter()
+                .any(|pool| pool.token_ids().contains(&token_id)),
+            "The token {} is used by a pool",
+            token_id
+        );
+        self.registered_tokens.remove(&token_id);
+        log!(
+            "@{} unregistered the token {}",
+            token_bond.account_id,
+            token_id
+        );
+        if self.whitelisted_tokens.contains(&token_id) {
+            return Promise::new(token_bond.account_id).transfer(token_bond.bond);
+        }
+        ext_ft::ext(token_id.clone())
+            .with_attached_deposit(1)
+            .storage_unregister(Some(false))
+            .then(
+                Self::ext(env::current_account_id()).unregister_token_callback(
+                    token_id,
+                    token_bond.account_id,
+                    U128(token_bond.bond),
+                ),
+            )
+    }
+
+    /// Returns the bond once the AMM's storage on the token contract is released, otherwise the
+    /// token is registered again.
+    #[private]
+    pub fn unregister_token_callback(
+        &mut self,
+        token_id: AccountId,
+        account_id: AccountId,
+        bond: U128,
+    ) -> bool {
+        if is_promise_success() || self.registered_tokens.get(&token_id).is_some() {
+            Promise::new(account_id).transfer(bond.0);
+            return true;
+        }
+        log!(
+            "Failed to release the storage on {}, the token stays registered",
+            token_id
+        );
+        self.registered_tokens.insert(
+            &token_id,
+            &TokenBond {
+                account_id,
+                bond: bond.0,
+            },
+        );
+        false
+    }
+
+    /// Tokens registered with a storage bond and the accounts that paid it.
+    pub fn get_registered_tokens(&self) -> Vec<(AccountId, AccountId)> {
+        self.registered_tokens
+            .iter()
+            .map(|(token_id, token_bond)| (token_id, token_bond.account_id))
+            .collect()
+    }
+}
+
+impl AMM {
+    /// Whitelisted tokens, tokens registered with a bond and the tokens of the main pool.
+    pub(crate) fn is_token_accepted(&self, token_id: &AccountId) -> bool {
+        self.whitelisted_tokens.contains(token_id)
+            || self.registered_tokens.get(token_id).is_some()
+            || self.tokens.get(token_id).is_some()
+    }
+
+    pub(crate) fn assert_token_accepted(&self, token_id: &AccountId) {
+        self.check_token_accepted(token_id).or_panic();
+    }
+
+    pub(crate) fn check_token_accepted(&self, token_id: &AccountId) -> AmmResult<()> {
+        if self.is_token_accepted(token_id) {
+            Ok(())
+        } else {
+            Err(AmmError::UnknownToken(token_id.clone()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_sdk::json_types::U128;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{accounts, get_created_receipts, get_logs, VMContextBuilder};
+    use near_sdk::{
+        testing_env, PromiseOrValue, PromiseResult, RuntimeFeesConfig, VMConfig, ONE_NEAR,
+    };
+
+    fn context(predecessor: AccountId, deposit: Balance) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .attached_deposit(deposit);
+        builder
+    }
+
+    fn setup() -> AMM {
+        testing_env!(context(accounts(1), 0).build());
+        AMM::new(accounts(1), accounts(2), accounts(3))
+    }
+
+    fn token(name: &str) -> AccountId {
+        name.parse().unwrap()
+    }
+
+    #[test]
+    fn test_whitelisted_token_in_pool() {
+        let mut contract = setup();
+        contract.add_whitelisted_tokens(vec![token("c.near")]);
+        assert_eq!(contract.get_whitelisted_tokens(), vec![token("c.near")]);
+        contract.create_weighted_pool(vec![accounts(2), token("c.near")], vec![50, 50], 30);
+    }
+
+    #[test]
+    #[should_panic(expected = "E_UNKNOWN_TOKEN: The token c.near is not supported")]
+    fn test_pool_with_unknown_token() {
+        let mut contract = setup();
+        contract.create_weighted_pool(vec![accounts(2), token("c.near")], vec![50, 50], 30);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_whitelist_not_owner() {
+        let mut contract = setup();
+        testing_env!(context(accounts(4), 0).build());
+        contract.add_whitelisted_tokens(vec![token("c.near")]);
+    }
+
+    #[test]
+    fn test_register_token() {
+        let mut contract = setup();
+        testing_env!(context(accounts(4), 2 * ONE_NEAR).build());
+        contract.register_token(token("c.near"));
+        assert_eq!(
+            contract.get_registered_tokens(),
+            vec![(token("c.near"), accounts(4))]
+        );
+        assert!(contract.is_token_accepted(&token("c.near")));
+    }
+
+    fn with_result(result: PromiseResult) {
+        testing_env!(
+            context(accounts(0), 0).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+    }
+
+    /// NEAR transferred to `account_id` so far.
+    fn refunds(account_id: &AccountId) -> Vec<Balance> {
+        get_created_receipts()
+            .into_iter()
+            .filter(|receipt| &receipt.receiver_id == account_id)
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::Transfer { deposit } => Some(deposit),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// `accounts(4)` registers `c.near` and returns the bond.
+    fn register(contract: &mut AMM) -> Balance {
+        testing_env!(context(accounts(4), 2 * ONE_NEAR).build());
+        contract.register_token(token("c.near"));
+        contract
+            .registered_tokens
+            .get(&token("c.near"))
+            .unwrap()
+            .bond
+    }
+
+    #[test]
+    fn test_failed_registration_is_refunded() {
+        let mut contract = setup();
+        let bond = register(&mut contract);
+        with_result(PromiseResult::Failed);
+        assert!(!contract.register_token_callback(token("c.near")));
+        assert!(!contract.is_token_accepted(&token("c.near")));
+        assert_eq!(refunds(&accounts(4)), vec![bond]);
+    }
+
+    #[test]
+    fn test_unregister_token() {
+        let mut contract = setup();
+        let bond = register(&mut contract);
+        testing_env!(context(accounts(4), 0).build());
+        drop(contract.unregister_token(token("c.near")));
+        assert!(!contract.is_token_accepted(&token("c.near")));
+
+        with_result(PromiseResult::Successful(b"true".to_vec()));
+        assert!(contract.unregister_token_callback(token("c.near"), accounts(4), U128(bond)));
+        assert_eq!(refunds(&accounts(4)), vec![bond]);
+    }
+
+    #[test]
+    fn test_failed_unregistration_keeps_the_token() {
+        let mut contract = setup();
+        let bond = register(&mut contract);
+        testing_env!(context(accounts(4), 0).build());
+        drop(contract.unregister_token(token("c.near")));
+        with_result(PromiseResult::Failed);
+        assert!(!contract.unregister_token_callback(token("c.near"), accounts(4), U128(bond)));
+        assert!(contract.is_token_accepted(&token("c.near")));
+        assert!(refunds(&accounts(4)).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "The token c.near is used by a pool")]
+    fn test_unregister_token_in_pool() {
+        let mut contract = setup();
+        register(&mut contract);
+        testing_env!(context(accounts(1), 0).build());
+        contract.create_weighted_pool(vec![accounts(2), token("c.near")], vec![50, 50], 30);
+        testing_env!(context(accounts(4), 0).build());
+        contract.unregister_token(token("c.near"));
+    }
+
+    #[test]
+    #[should_panic(expected = "E_UNAUTHORIZED: Only the account that paid the bond")]
+    fn test_unregister_token_not_payer() {
+        let mut contract = setup();
+        register(&mut contract);
+        testing_env!(context(accounts(5), 0).build());
+        contract.unregister_token(token("c.near"));
+    }
+
+    #[test]
+    #[should_panic(expected = "The storage bond is")]
+    fn test_register_token_without_bond() {
+        let mut contract = setup();
+        testing_env!(context(accounts(4), ONE_NEAR).build());
+        contract.register_token(token("c.near"));
+    }
+
+    fn refunded(result: PromiseOrValue<U128>) -> Balance {
+        match result {
+            PromiseOrValue::Value(unused) => unused.0,
+            PromiseOrValue::Promise(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_deposit_unknown_token() {
+        let mut contract = setup();
+        testing_env!(context(token("c.near"), 0).build());
+        let result = contract.ft_on_transfer(accounts(4), U128(100), "\"Deposit\"".to_string());
+        assert_eq!(refunded(result), 100);
+        assert_eq!(
+            get_logs(),
+            vec!["100 c.near are refunded: E_UNKNOWN_TOKEN: The token c.near is not supported"]
+        );
+        assert!(contract.get_deposits(accounts(4)).is_empty());
+    }
+
+    #[test]
+    fn test_legacy_msg_names_another_token() {
+        let mut contract = setup();
+        testing_env!(context(accounts(3), 0).build());
+        let result = contract.ft_on_transfer(
+            accounts(4),
+            U128(100),
+            format!("{}:{}", accounts(2), accounts(3)),
+        );
+        assert_eq!(refunded(result), 100);
+        assert_eq!(
+            get_logs(),
+            vec![
+                "100 danny are refunded: E_BAD_MSG: Invalid msg: the sell token charlie doesn't \
+                match the calling token contract"
+            ]
+        );
+    }
+}
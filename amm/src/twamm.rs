@@ -0,0 +1,930 @@
+//! TWAMM-style long-term orders on the main pool.
+//!
+//! A long-term order sells its tokens evenly over a range of blocks. The orders selling the same
+//! token make up a virtual order pool with a sale rate per block. Nothing runs per block: every
+//! interaction with the main pool first settles the blocks since the last one. For each stretch
+//! of blocks between two order expiries, the two virtual pools are matched against each other at
+//! the pool price and only the rest is swapped through the pool, so opposite orders don't move
+//! the price. The proceeds of a virtual pool are tracked per unit of sale rate, an order gets its
+//! sale rate times their growth while it was selling.
+//!
+//! Orders end at a multiple of [`ORDER_BLOCK_INTERVAL`], which bounds the stretches to settle.
+//! An account can have [`MAX_LONG_TERM_ORDERS_PER_ACCOUNT`] orders, each selling at least
+//! 1/[`MIN_ORDER_DIVISOR`] of the pool's reserve of the token.
+//! A stretch that can't be settled stops the settlement before it, its orders stay unsold and
+//! can be cancelled.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, TreeMap, UnorderedMap};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Serialize, Serializer};
+use near_sdk::{env, log, near_bindgen, AccountId, Balance};
+
+use crate::error::OrPanic;
+use crate::fixed_point::{self, U256};
+use crate::limit_orders::MIN_ORDER_DIVISOR;
+use crate::math::{self, MathError, MathResult};
+use crate::*;
+
+/// Long-term orders end at a multiple of this many blocks.
+pub const ORDER_BLOCK_INTERVAL: u64 = 100;
+pub const MAX_LONG_TERM_ORDERS_PER_ACCOUNT: u32 = 10;
+/// Scale of the proceeds per unit of sale rate.
+const PROCEEDS_SCALE: u128 = fixed_point::ONE;
+
+/// Proceeds per unit of sale rate, scaled by `PROCEEDS_SCALE`. It only grows, so it's kept in
+/// 256 bits; JSON shows it as a decimal string.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct ProceedsPerRate([u64; 4]);
+
+impl From<U256> for ProceedsPerRate {
+    fn from(value: U256) -> Self {
+        Self(value.0)
+    }
+}
+
+impl From<ProceedsPerRate> for U256 {
+    fn from(value: ProceedsPerRate) -> Self {
+        U256(value.0)
+    }
+}
+
+impl Serialize for ProceedsPerRate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&U256::from(*self).to_string())
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Twamm {
+    /// The block the virtual orders are settled up to.
+    last_block: u64,
+    /// Tokens sold per block by each virtual pool, in the order of `get_main_pool_tokens`.
+    sale_rates: [Balance; 2],
+    /// Proceeds per unit of sale rate of each virtual pool so far.
+    proceeds_per_rate: [ProceedsPerRate; 2],
+    /// Orders that end at a block and haven't reached it yet.
+    expirations: TreeMap<u64, Expiration>,
+    /// Orders that ended at a block and aren't closed yet.
+    proceeds_at_expiry: LookupMap<u64, ExpiredOrders>,
+    orders: UnorderedMap<u64, LongTermOrder>,
+    /// Orders of each account.
+    order_counts: LookupMap<AccountId, u32>,
+}
+
+impl Twamm {
+    pub(crate) fn new() -> Self {
+        Self {
+            last_block: env::block_height(),
+            sale_rates: [0; 2],
+            proceeds_per_rate: Default::default(),
+            expirations: TreeMap::new(StorageKey::TwammExpirations),
+            proceeds_at_expiry: LookupMap::new(StorageKey::TwammProceeds),
+            orders: UnorderedMap::new(StorageKey::TwammOrders),
+            order_counts: LookupMap::new(StorageKey::TwammOrderCounts),
+        }
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+struct Expiration {
+    /// Sale rates of the orders that end, by virtual pool.
+    sale_rates: [Balance; 2],
+    orders: u32,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct ExpiredOrders {
+    /// `proceeds_per_rate` at the block.
+    proceeds_per_rate: [ProceedsPerRate; 2],
+    /// The orders left to close, the entry is removed with the last one.
+    orders: u32,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LongTermOrder {
+    pub order_id: u64,
+    pub owner_id: AccountId,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    /// Tokens sold per block.
+    pub sale_rate: U128,
+    pub end_block: U64,
+    /// Proceeds per unit of sale rate of the virtual pool when the order last withdrew.
+    pub proceeds_per_rate_start: ProceedsPerRate,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LongTermOrderView {
+    #[serde(flatten)]
+    pub order: LongTermOrder,
+    /// Proceeds that can be withdrawn, as of the last settlement.
+    pub proceeds: U128,
+    /// Tokens not sold yet.
+    pub unsold: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VirtualOrderPoolView {
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub sale_rate: U128,
+    /// Proceeds per unit of sale rate so far, scaled by 10^18.
+    pub proceeds_per_rate: ProceedsPerRate,
+    /// The block the virtual orders are settled up to.
+    pub last_block: U64,
+}
+
+/// The state a settlement changes. Views settle a copy to show the pool as of the current block.
+pub(crate) struct Settlement {
+    pub last_block: u64,
+    pub sale_rates: [Balance; 2],
+    proceeds_per_rate: [U256; 2],
+    /// The main pool reserves.
+    pub reserves: [Balance; 2],
+    /// The expiry blocks passed, with `proceeds_per_rate` at each.
+    expired: Vec<(u64, [U256; 2])>,
+}
+
+impl Settlement {
+    /// Trades `blocks` blocks of the virtual orders against the reserves.
+    fn settle_blocks(&mut self, blocks: u64) -> MathResult<()> {
+        let rates = self.sale_rates;
+        if blocks == 0 || rates == [0; 2] {
+            return Ok(());
+        }
+        let blocks = blocks as Balance;
+        let sold = [
+            rates[0].checked_mul(blocks).ok_or(MathError::Overflow)?,
+            rates[1].checked_mul(blocks).ok_or(MathError::Overflow)?,
+        ];
+        let (proceeds, reserves) = virtual_trade(sold, self.reserves)?;
+        let mut proceeds_per_rate = self.proceeds_per_rate;
+        for side in 0..2 {
+            if rates[side] > 0 {
+                let growth = U256::from(proceeds[side]) * U256::from(PROCEEDS_SCALE)
+                    / U256::from(rates[side]);
+                proceeds_per_rate[side] = proceeds_per_rate[side]
+                    .checked_add(growth)
+                    .ok_or(MathError::Overflow)?;
+            }
+        }
+        self.proceeds_per_rate = proceeds_per_rate;
+        self.reserves = reserves;
+        Ok(())
+    }
+}
+
+#[near_bindgen]
+impl AMM {
+    /// Settles the virtual orders up to the current block, anyone can call it.
+    pub fn execute_virtual_orders(&mut self) {
+        self.assert_no_flash_loan();
+        self.settle_virtual_orders();
+    }
+
+    /// The virtual order pools of the main pool, one for each token sold, settled up to the
+    /// current block.
+    pub fn get_virtual_order_pools(&self) -> Vec<VirtualOrderPoolView> {
+        let token_ids = self.get_main_pool_tokens();
+        let settlement = self.settled_virtual_orders();
+        (0..2)
+            .map(|side| VirtualOrderPoolView {
+                token_in: token_ids[side].clone(),
+                token_out: token_ids[1 - side].clone(),
+                sale_rate: U128(settlement.sale_rates[side]),
+                proceeds_per_rate: settlement.proceeds_per_rate[side].into(),
+                last_block: U64(settlement.last_block),
+            })
+            .collect()
+    }
+
+    pub fn get_long_term_orders(&self, account_id: AccountId) -> Vec<LongTermOrderView> {
+        self.twamm
+            .orders
+            .values()
+            .filter(|order| order.owner_id == account_id)
+            .map(|order| LongTermOrderView {
+                proceeds: U128(self.order_proceeds(&order).or_panic()),
+                unsold: U128(self.order_unsold(&order)),
+                order,
+            })
+            .collect()
+    }
+
+    /// Transfers the proceeds of the order so far. An order that ended is closed.
+    pub fn withdraw_long_term_order(&mut self, order_id: u64) -> U128 {
+        self.assert_no_flash_loan();
+        self.settle_virtual_orders();
+        let mut order = self.owned_long_term_order(order_id);
+        let proceeds = self.order_proceeds(&order).or_panic();
+        if order.end_block.0 <= self.twamm.last_block {
+            self.close_long_term_order(&order);
+            log!("Long-term order {} is closed", order_id);
+        } else {
+            let side = self.side(&order.token_in);
+            order.proceeds_per_rate_start = self.twamm.proceeds_per_rate[side];
+            self.twamm.orders.insert(&order_id, &order);
+        }
+        if proceeds > 0 {
+            self.internal_payout(
+                order.owner_id.clone(),
+                order.owner_id,
+                order.token_out,
+                proceeds,
+                None,
+            );
+        }
+        U128(proceeds)
+    }
+
+    /// Stops the order, transfers its proceeds and refunds what isn't sold yet.
+    pub fn cancel_long_term_order(&mut self, order_id: u64) {
+        self.assert_no_flash_loan();
+        self.settle_virtual_orders();
+        let order = self.owned_long_term_order(order_id);
+        let proceeds = self.order_proceeds(&order).or_panic();
+        let unsold = self.order_unsold(&order);
+        self.close_long_term_order(&order);
+        log!("Long-term order {} is cancelled", order_id);
+
+        let owner_id = order.owner_id;
+        if proceeds > 0 {
+            self.internal_payout(
+                owner_id.clone(),
+                owner_id.clone(),
+                order.token_out,
+                proceeds,
+                None,
+            );
+        }
+        if unsold > 0 {
+            self.internal_payout(owner_id.clone(), owner_id, order.token_in, unsold, None);
+        }
+    }
+}
+
+impl AMM {
+    /// Places an order selling `amount` over at least `blocks` blocks, up to the next multiple
+    /// of [`ORDER_BLOCK_INTERVAL`]. Returns the part of `amount` that doesn't divide evenly
+    /// between the blocks, to refund.
+    pub(crate) fn place_long_term_order(
+        &mut self,
+        owner_id: AccountId,
+        token_in: &AccountId,
+        amount: Balance,
+        token_out: AccountId,
+        blocks: u64,
+    ) -> AmmResult<Balance> {
+        let reserve = self.main_token(token_in)?.balance;
+        self.main_token(&token_out)?;
+        if token_in == &token_out {
+            return Err(AmmError::BadMsg(
+                "the order must sell one main pool token for the other".to_string(),
+            ));
+        }
+        if blocks == 0 {
+            return Err(AmmError::BadMsg(
+                "the order must last at least one block".to_string(),
+            ));
+        }
+        if amount == 0 || U256::from(amount) * U256::from(MIN_ORDER_DIVISOR) < U256::from(reserve) {
+            return Err(AmmError::BadMsg(format!(
+                "the order must sell at least {} {}",
+                math::mul_div_up(reserve, 1, MIN_ORDER_DIVISOR)?,
+                token_in
+            )));
+        }
+        let count = self.twamm.order_counts.get(&owner_id).unwrap_or(0);
+        if count >= MAX_LONG_TERM_ORDERS_PER_ACCOUNT {
+            return Err(AmmError::BadMsg(format!(
+                "an account can't have more than {} long-term orders",
+                MAX_LONG_TERM_ORDERS_PER_ACCOUNT
+            )));
+        }
+        self.check_no_flash_loan()?;
+        if self.main_pool_reserves().contains(&0) {
+            return Err(AmmError::InsufficientLiquidity);
+        }
+        let now = env::block_height();
+        let end_block = now
+            .checked_add(blocks)
+            .ok_or(AmmError::MathOverflow)?
+            .div_ceil(ORDER_BLOCK_INTERVAL)
+            * ORDER_BLOCK_INTERVAL;
+        let sale_rate = amount / (end_block - now) as Balance;
+        if sale_rate == 0 {
+            return Err(AmmError::BadMsg(format!(
+                "{} is less than a token per block until {}",
+                amount, end_block
+            )));
+        }
+        self.settle_virtual_orders();
+        // the new order would be sold in the blocks left behind
+        if self.twamm.last_block < now {
+            return Err(AmmError::BadMsg(format!(
+                "the virtual orders can't be settled after block {}",
+                self.twamm.last_block
+            )));
+        }
+        // the settlement ends the expired orders, the new one adds to the rates left
+        let side = self.side(token_in);
+        self.twamm.sale_rates[side] = math::checked_add(self.twamm.sale_rates[side], sale_rate)?;
+        let mut expiration = self.twamm.expirations.get(&end_block).unwrap_or_default();
+        expiration.sale_rates[side] += sale_rate;
+        expiration.orders += 1;
+        self.twamm.expirations.insert(&end_block, &expiration);
+
+        let order_id = self.next_order_id;
+        self.next_order_id += 1;
+        self.twamm.order_counts.insert(&owner_id, &(count + 1));
+        self.twamm.orders.insert(
+            &order_id,
+            &LongTermOrder {
+                order_id,
+                owner_id,
+                token_in: token_in.clone(),
+                token_out,
+                sale_rate: U128(sale_rate),
+                end_block: U64(end_block),
+                proceeds_per_rate_start: self.twamm.proceeds_per_rate[side],
+            },
+        );
+        log!(
+            "Long-term order {} sells {} {} per block until {}",
+            order_id,
+            sale_rate,
+            token_in,
+            end_block
+        );
+        Ok(amount - sale_rate * (end_block - now) as Balance)
+    }
+
+    /// Executes the virtual orders up to the current block, or up to a stretch that can't be
+    /// settled. Callers check the flash loan first.
+    pub(crate) fn settle_virtual_orders(&mut self) {
+        let mut settlement = self.settlement();
+        let reserves = settlement.reserves;
+        self.settle(&mut settlement, env::block_height());
+
+        for (block, proceeds_per_rate) in settlement.expired {
+            let expiration = self.twamm.expirations.remove(&block).unwrap();
+            self.twamm.proceeds_at_expiry.insert(
+                &block,
+                &ExpiredOrders {
+                    proceeds_per_rate: proceeds_per_rate.map(ProceedsPerRate::from),
+                    orders: expiration.orders,
+                },
+            );
+        }
+        self.twamm.last_block = settlement.last_block;
+        self.twamm.sale_rates = settlement.sale_rates;
+        self.twamm.proceeds_per_rate = settlement.proceeds_per_rate.map(ProceedsPerRate::from);
+        if settlement.reserves != reserves {
+            for (token_id, balance) in self.get_main_pool_tokens().iter().zip(settlement.reserves) {
+                let mut token_info = self.tokens.get(token_id).unwrap();
+                token_info.balance = balance;
+                self.tokens.insert(token_id, &token_info);
+            }
+            self.k = self.main_pool_k();
+        }
+    }
+
+    /// The virtual orders and the main pool reserves as they would be after settling up to the
+    /// current block.
+    pub(crate) fn settled_virtual_orders(&self) -> Settlement {
+        let mut settlement = self.settlement();
+        self.settle(&mut settlement, env::block_height());
+        settlement
+    }
+
+    fn settlement(&self) -> Settlement {
+        let reserves = self.main_pool_reserves();
+        Settlement {
+            last_block: self.twamm.last_block,
+            sale_rates: self.twamm.sale_rates,
+            proceeds_per_rate: self.twamm.proceeds_per_rate.map(U256::from),
+            reserves: [reserves[0], reserves[1]],
+            expired: vec![],
+        }
+    }
+
+    fn settle(&self, settlement: &mut Settlement, now: u64) {
+        while settlement.last_block < now {
+            let expiry = self
+                .twamm
+                .expirations
+                .higher(&settlement.last_block)
+                .filter(|block| *block <= now);
+            let block = expiry.unwrap_or(now);
+            if let Err(err) = settlement.settle_blocks(block - settlement.last_block) {
+                log!(
+                    "The virtual orders can't be executed after block {}: {}",
+                    settlement.last_block,
+                    err
+                );
+                return;
+            }
+            settlement.last_block = block;
+
+            if let Some(block) = expiry {
+                let expiration = self.twamm.expirations.get(&block).unwrap();
+                for side in 0..2 {
+                    settlement.sale_rates[side] -= expiration.sale_rates[side];
+                }
+                settlement
+                    .expired
+                    .push((block, settlement.proceeds_per_rate));
+            }
+        }
+    }
+
+    /// Removes the order and its sale rate, or its claim on the proceeds at its end block if
+    /// it ended.
+    fn close_long_term_order(&mut self, order: &LongTermOrder) {
+        let end_block = order.end_block.0;
+        if end_block <= self.twamm.last_block {
+            let mut expired = self.twamm.proceeds_at_expiry.get(&end_block).unwrap();
+            expired.orders -= 1;
+            if expired.orders == 0 {
+                self.twamm.proceeds_at_expiry.remove(&end_block);
+            } else {
+                self.twamm.proceeds_at_expiry.insert(&end_block, &expired);
+            }
+        } else {
+            let side = self.side(&order.token_in);
+            self.twamm.sale_rates[side] -= order.sale_rate.0;
+            let mut expiration = self.twamm.expirations.get(&end_block).unwrap();
+            expiration.sale_rates[side] -= order.sale_rate.0;
+            expiration.orders -= 1;
+            if expiration.orders == 0 {
+                self.twamm.expirations.remove(&end_block);
+            } else {
+                self.twamm.expirations.insert(&end_block, &expiration);
+            }
+        }
+        self.twamm.orders.remove(&order.order_id);
+        let count = self.twamm.order_counts.get(&order.owner_id).unwrap_or(1);
+        if count <= 1 {
+            self.twamm.order_counts.remove(&order.owner_id);
+        } else {
+            self.twamm
+                .order_counts
+                .insert(&order.owner_id, &(count - 1));
+        }
+    }
+
+    /// Index of the virtual pool selling `token_id`.
+    fn side(&self, token_id: &AccountId) -> usize {
+        self.get_main_pool_tokens()
+            .iter()
+            .position(|id| id == token_id)
+            .unwrap()
+    }
+
+    fn owned_long_term_order(&self, order_id: u64) -> LongTermOrder {
+        let order = self
+            .twamm
+            .orders
+            .get(&order_id)
+            .unwrap_or_else(|| panic!("No long-term order {}", order_id));
+        if order.owner_id != env::predecessor_account_id() {
+            panic!(
+                "{}",
+                AmmError::Unauthorized("Only the owner of the order can do this".to_string())
+            );
+        }
+        order
+    }
+
+    fn order_proceeds(&self, order: &LongTermOrder) -> MathResult<Balance> {
+        let side = self.side(&order.token_in);
+        let proceeds_per_rate = if order.end_block.0 <= self.twamm.last_block {
+            self.twamm
+                .proceeds_at_expiry
+                .get(&order.end_block.0)
+                .unwrap()
+                .proceeds_per_rate[side]
+        } else {
+            self.twamm.proceeds_per_rate[side]
+        };
+        let growth = U256::from(proceeds_per_rate) - U256::from(order.proceeds_per_rate_start);
+        let proceeds = growth
+            .checked_mul(U256::from(order.sale_rate.0))
+            .ok_or(MathError::Overflow)?;
+        math::to_u128(proceeds / U256::from(PROCEEDS_SCALE))
+    }
+
+    fn order_unsold(&self, order: &LongTermOrder) -> Balance {
+        order.sale_rate.0 * order.end_block.0.saturating_sub(self.twamm.last_block) as Balance
+    }
+}
+
+/// Trades `sold` of the two virtual pools against the main pool with `reserves`. The smaller
+/// side, in value at the pool price, is matched with the other one at that price, the rest of
+/// the other one is swapped through the pool. Returns what each side receives and the new
+/// reserves.
+fn virtual_trade(
+    sold: [Balance; 2],
+    reserves: [Balance; 2],
+) -> MathResult<([Balance; 2], [Balance; 2])> {
+    let [x, y] = reserves;
+    let value0 = math::mul_div_down(sold[0], y, x)?;
+    if value0 >= sold[1] {
+        // side 1 is matched in full
+        let matched0 = math::mul_div_down(sold[1], x, y)?;
+        let rest0 = sold[0] - matched0;
+        let swapped = math::get_amount_out(rest0, x, y)?;
+        Ok((
+            [math::checked_add(sold[1], swapped)?, matched0],
+            [math::checked_add(x, rest0)?, y - swapped],
+        ))
+    } else {
+        let rest1 = sold[1] - value0;
+        let swapped = math::get_amount_out(rest1, y, x)?;
+        Ok((
+            [value0, math::checked_add(sold[0], swapped)?],
+            [x - swapped, math::checked_add(y, rest1)?],
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{enable_swap_history, seed_main_pool, setup_main_pool, transfer};
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    fn place_msg(token_out: AccountId, blocks: u64) -> String {
+        format!(
+            r#"{{"PlaceLongTermOrder": {{"token_out": "{}", "blocks": "{}"}}}}"#,
+            token_out, blocks
+        )
+    }
+
+    fn reserves(contract: &AMM) -> Vec<Balance> {
+        contract.main_pool_reserves()
+    }
+
+    #[test]
+    fn test_virtual_trade() {
+        // side 1 is worth 100 at the pool price, it's matched with 50 of side 0
+        let (proceeds, reserves) = virtual_trade([1_050, 100], [1_000, 2_000]).unwrap();
+        assert_eq!(proceeds[1], 50);
+        assert_eq!(
+            proceeds[0],
+            100 + math::get_amount_out(1_000, 1_000, 2_000).unwrap()
+        );
+        assert_eq!(reserves, [2_000, 2_000 - (proceeds[0] - 100)]);
+
+        // opposite sides of the same value don't touch the pool
+        let (proceeds, reserves) = virtual_trade([100, 200], [1_000, 2_000]).unwrap();
+        assert_eq!(proceeds, [200, 100]);
+        assert_eq!(reserves, [1_000, 2_000]);
+    }
+
+    #[test]
+    fn test_order_sells_over_blocks() {
+        let (mut context, mut contract) = setup_main_pool();
+        // 10_050 over 100 blocks, 50 are refunded
+        let unused = transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            10_050,
+            place_msg(accounts(3), 100),
+        );
+        assert_eq!(unused, 50);
+        assert_eq!(contract.get_virtual_order_pools()[0].sale_rate.0, 100);
+
+        testing_env!(context
+            .block_index(50)
+            .predecessor_account_id(accounts(5))
+            .build());
+        contract.execute_virtual_orders();
+        let order = &contract.get_long_term_orders(accounts(4))[0];
+        assert_eq!(order.unsold.0, 5_000);
+        assert_eq!(reserves(&contract)[0], 1_005_000);
+        // all the proceeds but the rounding are the order's
+        assert!(1_000_000 - reserves(&contract)[1] - order.proceeds.0 <= 1);
+
+        // the order stops selling at its end block
+        testing_env!(context.block_index(300).build());
+        contract.execute_virtual_orders();
+        assert_eq!(reserves(&contract)[0], 1_010_000);
+        assert_eq!(contract.get_virtual_order_pools()[0].sale_rate.0, 0);
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        let proceeds = contract.withdraw_long_term_order(0).0;
+        assert!(proceeds > 9_800 && proceeds < 10_000);
+        assert!(contract.get_long_term_orders(accounts(4)).is_empty());
+    }
+
+    #[test]
+    fn test_swap_settles_virtual_orders() {
+        let (mut context, mut contract) = setup_main_pool();
+        enable_swap_history(&mut context, &mut contract, accounts(5));
+        let msg = place_msg(accounts(3), 100);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            10_000,
+            msg,
+        );
+        testing_env!(context.block_index(100).build());
+        let msg = format!(r#"{{"Swap": {{"token_out": "{}"}}}}"#, accounts(2));
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(5),
+            100,
+            msg,
+        );
+        assert_eq!(contract.get_virtual_order_pools()[0].last_block.0, 100);
+        assert_eq!(
+            reserves(&contract)[0],
+            1_010_000
+                - contract.get_swap_history(accounts(5), None, None)[0]
+                    .amount_out
+                    .0
+        );
+    }
+
+    #[test]
+    fn test_opposite_orders_are_matched() {
+        let (mut context, mut contract) = setup_main_pool();
+        let msg = place_msg(accounts(3), 100);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            10_000,
+            msg,
+        );
+        let msg = place_msg(accounts(2), 100);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(3),
+            accounts(5),
+            10_000,
+            msg,
+        );
+        testing_env!(context.block_index(100).build());
+        contract.execute_virtual_orders();
+        assert_eq!(reserves(&contract), vec![1_000_000, 1_000_000]);
+        assert_eq!(
+            contract.get_long_term_orders(accounts(4))[0].proceeds.0,
+            10_000
+        );
+    }
+
+    #[test]
+    fn test_cancel_long_term_order() {
+        let (mut context, mut contract) = setup_main_pool();
+        let msg = place_msg(accounts(3), 100);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            10_000,
+            msg,
+        );
+        testing_env!(context
+            .block_index(25)
+            .predecessor_account_id(accounts(4))
+            .build());
+        contract.cancel_long_term_order(0);
+        assert_eq!(reserves(&contract)[0], 1_002_500);
+        assert_eq!(contract.get_virtual_order_pools()[0].sale_rate.0, 0);
+
+        // nothing is sold after the cancellation
+        testing_env!(context.block_index(100).build());
+        contract.execute_virtual_orders();
+        assert_eq!(reserves(&contract)[0], 1_002_500);
+    }
+
+    #[test]
+    #[should_panic(expected = "E_UNAUTHORIZED: Only the owner of the order can do this")]
+    fn test_cancel_long_term_order_not_owner() {
+        let (mut context, mut contract) = setup_main_pool();
+        let msg = place_msg(accounts(3), 100);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            10_000,
+            msg,
+        );
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.cancel_long_term_order(0);
+    }
+
+    #[test]
+    fn test_order_placed_after_unsettled_expiry() {
+        let (mut context, mut contract) = setup_main_pool();
+        let msg = place_msg(accounts(3), 100);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            10_000,
+            msg,
+        );
+        // order 0 ended at block 100, the placement settles it
+        testing_env!(context.block_index(150).build());
+        let msg = place_msg(accounts(3), 50);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(5),
+            5_000,
+            msg,
+        );
+        assert_eq!(contract.get_virtual_order_pools()[0].sale_rate.0, 100);
+
+        testing_env!(context.block_index(1_000).build());
+        contract.execute_virtual_orders();
+        assert_eq!(contract.get_virtual_order_pools()[0].sale_rate.0, 0);
+        assert_eq!(reserves(&contract)[0], 1_015_000);
+    }
+
+    #[test]
+    fn test_views_show_settled_pool() {
+        let (mut context, mut contract) = setup_main_pool();
+        let msg = place_msg(accounts(3), 100);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            10_000,
+            msg,
+        );
+        testing_env!(context.block_index(50).build());
+        assert_eq!(reserves(&contract)[0], 1_000_000);
+        let tokens = contract.get_tokens();
+        assert_eq!(tokens[0].balance.0, 1_005_000);
+        assert_eq!(tokens[0].virtual_sale_rate.0, 100);
+        assert_eq!(tokens[1].virtual_sale_rate.0, 0);
+        assert!(contract
+            .info()
+            .contains("Balance: 1005000. Virtual orders sell: 100 per block"));
+        let pools = contract.get_virtual_order_pools();
+        assert_eq!(pools[0].last_block.0, 50);
+        // quotes use the settled reserves too
+        assert_eq!(
+            contract
+                .get_return(None, accounts(3), U128(1_000), accounts(2))
+                .0,
+            math::get_amount_out(1_000, tokens[1].balance.0, tokens[0].balance.0).unwrap()
+        );
+        assert_eq!(
+            contract
+                .get_amount_in(None, accounts(3), U128(1_000), accounts(2))
+                .0,
+            math::get_amount_in(1_000, tokens[1].balance.0, tokens[0].balance.0).unwrap()
+        );
+
+        contract.execute_virtual_orders();
+        assert_eq!(reserves(&contract)[0], tokens[0].balance.0);
+        assert_eq!(reserves(&contract)[1], tokens[1].balance.0);
+    }
+
+    #[test]
+    fn test_expiry_is_removed_with_its_last_order() {
+        let (mut context, mut contract) = setup_main_pool();
+        for account_id in [accounts(4), accounts(5)] {
+            let msg = place_msg(accounts(3), 100);
+            transfer(
+                &mut context,
+                &mut contract,
+                accounts(2),
+                account_id,
+                10_000,
+                msg,
+            );
+        }
+        testing_env!(context
+            .block_index(100)
+            .predecessor_account_id(accounts(4))
+            .build());
+        contract.withdraw_long_term_order(0);
+        assert!(contract.twamm.expirations.is_empty());
+        assert_eq!(
+            contract.twamm.proceeds_at_expiry.get(&100).unwrap().orders,
+            1
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.cancel_long_term_order(1);
+        assert!(contract.twamm.proceeds_at_expiry.get(&100).is_none());
+    }
+
+    #[test]
+    fn test_failed_settlement_keeps_orders_unsold() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let mut contract = AMM::new(accounts(1), accounts(2), accounts(3));
+        seed_main_pool(&mut context, &mut contract, [1_000_000, u128::MAX / 2]);
+        // the value of the sold tokens at the pool price overflows
+        let amount = 10u128.pow(30);
+        let msg = place_msg(accounts(3), 100);
+        transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            amount,
+            msg,
+        );
+
+        testing_env!(context.block_index(50).build());
+        contract.execute_virtual_orders();
+        assert_eq!(
+            get_logs(),
+            vec!["The virtual orders can't be executed after block 0: Math overflow"]
+        );
+        assert_eq!(contract.get_virtual_order_pools()[0].last_block.0, 0);
+        assert_eq!(reserves(&contract)[0], 1_000_000);
+
+        // a new order would be sold in the blocks that aren't settled
+        let msg = place_msg(accounts(3), 100);
+        let unused = transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(5),
+            10_000,
+            msg,
+        );
+        assert_eq!(unused, 10_000);
+
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        let order = &contract.get_long_term_orders(accounts(4))[0];
+        assert_eq!(order.unsold.0, amount);
+        contract.cancel_long_term_order(0);
+        assert_eq!(contract.get_virtual_order_pools()[0].sale_rate.0, 0);
+    }
+
+    #[test]
+    fn test_order_limits() {
+        let (mut context, mut contract) = setup_main_pool();
+        // 99 is below 0.01% of the reserve
+        let unused = transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            99,
+            place_msg(accounts(3), 1),
+        );
+        assert_eq!(unused, 99);
+        assert_eq!(
+            get_logs(),
+            vec!["99 charlie are refunded: E_BAD_MSG: Invalid msg: the order must sell at least 100 charlie"]
+        );
+
+        for _ in 0..MAX_LONG_TERM_ORDERS_PER_ACCOUNT {
+            let unused = transfer(
+                &mut context,
+                &mut contract,
+                accounts(2),
+                accounts(4),
+                100,
+                place_msg(accounts(3), 100),
+            );
+            assert_eq!(unused, 0);
+        }
+        let unused = transfer(
+            &mut context,
+            &mut contract,
+            accounts(2),
+            accounts(4),
+            100,
+            place_msg(accounts(3), 100),
+        );
+        assert_eq!(unused, 100);
+
+        // a closed order frees its place
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.cancel_long_term_order(0);
+        assert_eq!(
+            contract.twamm.order_counts.get(&accounts(4)),
+            Some(MAX_LONG_TERM_ORDERS_PER_ACCOUNT - 1)
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+
+use near_contract_standards::storage_management::StorageBalance;
+use near_sdk::json_types::U128;
+use near_sdk::serde::de::DeserializeOwned;
+use near_sdk::{
+    env, log, near_bindgen, serde_json, AccountId, Balance, Promise, PromiseOrValue, PromiseResult,
+};
+
+use crate::*;
+
+#[near_bindgen]
+impl AMM {
+    /// Registers `account_id` on every token of the main pool and the other pools, so it can
+    /// receive them. The attached deposit is split evenly between the tokens it isn't registered
+    /// on yet and the unused part is refunded to the caller. Returns whether `account_id` is
+    /// registered on each token afterwards.
+    #[payable]
+    pub fn register_user_on_tokens(&mut self, account_id: AccountId) -> Promise {
+        let deposit = env::attached_deposit();
+        assert!(deposit > 0, "Attach a deposit for the storage");
+        let token_ids = self.pool_token_ids();
+        token_ids
+            .iter()
+            .map(|token_id| ext_ft::ext(token_id.clone()).storage_balance_of(account_id.clone()))
+            .reduce(|balances, balance| balances.and(balance))
+            .unwrap()
+            .then(
+                Self::ext(env::current_account_id()).register_user_balances_callback(
+                    env::predecessor_account_id(),
+                    account_id,
+                    token_ids,
+                    U128(deposit),
+                ),
+            )
+    }
+
+    /// Registers the account on the tokens where it has no storage balance.
+    #[private]
+    pub fn register_user_balances_callback(
+        &mut self,
+        payer_id: AccountId,
+        account_id: AccountId,
+        token_ids: Vec<AccountId>,
+        deposit: U128,
+    ) -> PromiseOrValue<HashMap<AccountId, bool>> {
+        let (registered, unregistered): (Vec<_>, Vec<_>) =
+            token_ids.into_iter().enumerate().partition(|(i, _)| {
+                promise_result_json::<Option<StorageBalance>>(*i)
+                    .flatten()
+                    .is_some()
+            });
+        let registered: Vec<AccountId> = registered.into_iter().map(|(_, id)| id).collect();
+        let unregistered: Vec<AccountId> = unregistered.into_iter().map(|(_, id)| id).collect();
+        if unregistered.is_empty() {
+            Promise::new(payer_id).transfer(deposit.0);
+            return PromiseOrValue::Value(registrations(registered, vec![]));
+        }
+
+        let deposit_per_token = deposit.0 / unregistered.len() as Balance;
+        unregistered
+            .iter()
+            .map(|token_id| {
+                ext_ft::ext(token_id.clone())
+                    .with_attached_deposit(deposit_per_token)
+                    .storage_deposit(account_id.clone(), true)
+            })
+            .reduce(|deposits, deposit| deposits.and(deposit))
+            .unwrap()
+            .then(
+                Self::ext(env::current_account_id()).register_user_deposits_callback(
+                    payer_id,
+                    account_id,
+                    registered,
+                    unregistered,
+                    deposit,
+                ),
+            )
+            .into()
+    }
+
+    /// Refunds the part of the deposit that the new registrations didn't use. A registration
+    /// that failed, e.g. because its share of the deposit is below the token's minimum storage
+    /// balance, is refunded in full.
+    #[private]
+    pub fn register_user_deposits_callback(
+        &mut self,
+        payer_id: AccountId,
+        account_id: AccountId,
+        registered: Vec<AccountId>,
+        token_ids: Vec<AccountId>,
+        deposit: U128,
+    ) -> HashMap<AccountId, bool> {
+        let mut used = 0;
+        let mut succeeded = vec![];
+        let mut failed = vec![];
+        for (i, token_id) in token_ids.into_iter().enumerate() {
+            match promise_result_json::<StorageBalance>(i) {
+                Some(storage_balance) => {
+                    used += storage_balance.total.0;
+                    succeeded.push(token_id);
+                }
+                None => {
+                    log!("Failed to register @{} on {}", account_id, token_id);
+                    failed.push(token_id);
+                }
+            }
+        }
+        let unused = deposit.0.saturating_sub(used);
+        if unused > 0 {
+            Promise::new(payer_id).transfer(unused);
+        }
+        registrations(registered.into_iter().chain(succeeded).collect(), failed)
+    }
+}
+
+impl AMM {
+    /// The tokens of the main pool and of all the pools, each once.
+    fn pool_token_ids(&self) -> Vec<AccountId> {
+        let mut token_ids: Vec<AccountId> = self.tokens.keys().collect();
+        for pool in self.pools.iter() {
+            for token_id in pool.token_ids() {
+                if !token_ids.contains(token_id) {
+                    token_ids.push(token_id.clone());
+                }
+            }
+        }
+        token_ids
+    }
+}
+
+fn promise_result_json<T: DeserializeOwned>(index: usize) -> Option<T> {
+    match env::promise_result(index as u64) {
+        PromiseResult::Successful(data) => serde_json::from_slice(&data).ok(),
+        _ => None,
+    }
+}
+
+fn registrations(registered: Vec<AccountId>, failed: Vec<AccountId>) -> HashMap<AccountId, bool> {
+    registered
+        .into_iter()
+        .map(|token_id| (token_id, true))
+        .chain(failed.into_iter().map(|token_id| (token_id, false)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig, ONE_NEAR};
+
+    fn setup() -> AMM {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .build());
+        AMM::new(accounts(1), accounts(2), accounts(3))
+    }
+
+    fn with_results(results: Vec<PromiseResult>) {
+        testing_env!(
+            VMContextBuilder::new()
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            results,
+        );
+    }
+
+    fn storage_balance(total: Balance) -> PromiseResult {
+        PromiseResult::Successful(
+            serde_json::to_vec(&StorageBalance {
+                total: U128(total),
+                available: U128(0),
+            })
+            .unwrap(),
+        )
+    }
+
+    /// Deposits of the `storage_deposit` calls and the NEAR transfers made so far.
+    fn deposits() -> (Vec<Balance>, Vec<Balance>) {
+        let mut storage_deposits = vec![];
+        let mut transfers = vec![];
+        for action in get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+        {
+            match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    deposit,
+                    ..
+                } if function_name == "storage_deposit" => storage_deposits.push(deposit),
+                VmAction::Transfer { deposit } => transfers.push(deposit),
+                _ => {}
+            }
+        }
+        (storage_deposits, transfers)
+    }
+
+    #[test]
+    fn test_pool_token_ids() {
+        let mut contract = setup();
+        let token_c: AccountId = "c.near".parse().unwrap();
+        contract.add_whitelisted_tokens(vec![token_c.clone()]);
+        contract.create_weighted_pool(vec![accounts(2), token_c.clone()], vec![50, 50], 30);
+        assert_eq!(
+            contract.pool_token_ids(),
+            vec![accounts(2), accounts(3), token_c]
+        );
+    }
+
+    #[test]
+    fn test_deposit_is_split_between_unregistered_tokens() {
+        let mut contract = setup();
+        with_results(vec![
+            PromiseResult::Successful(b"null".to_vec()),
+            storage_balance(ONE_NEAR / 100),
+        ]);
+        let result = contract.register_user_balances_callback(
+            accounts(4),
+            accounts(5),
+            vec![accounts(2), accounts(3)],
+            U128(ONE_NEAR),
+        );
+        // the calls are scheduled once the promise is dropped
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        drop(result);
+        assert_eq!(deposits(), (vec![ONE_NEAR], vec![]));
+    }
+
+    #[test]
+    fn test_already_registered_is_refunded() {
+        let mut contract = setup();
+        with_results(vec![storage_balance(1), storage_balance(1)]);
+        let result = contract.register_user_balances_callback(
+            accounts(4),
+            accounts(5),
+            vec![accounts(2), accounts(3)],
+            U128(ONE_NEAR),
+        );
+        match result {
+            PromiseOrValue::Value(registered) => {
+                assert!(registered[&accounts(2)]);
+                assert!(registered[&accounts(3)]);
+            }
+            PromiseOrValue::Promise(_) => panic!("Nothing to register"),
+        }
+        assert_eq!(deposits(), (vec![], vec![ONE_NEAR]));
+    }
+
+    #[test]
+    fn test_unused_deposit_is_refunded() {
+        let mut contract = setup();
+        with_results(vec![storage_balance(ONE_NEAR / 100), PromiseResult::Failed]);
+        let registered = contract.register_user_deposits_callback(
+            accounts(4),
+            accounts(5),
+            vec![],
+            vec![accounts(2), accounts(3)],
+            U128(ONE_NEAR),
+        );
+        assert!(registered[&accounts(2)]);
+        assert!(!registered[&accounts(3)]);
+        assert_eq!(deposits(), (vec![], vec![ONE_NEAR - ONE_NEAR / 100]));
+    }
+}
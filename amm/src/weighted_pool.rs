@@ -0,0 +1,543 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::serde::Serialize;
+use near_sdk::{AccountId, Balance, IntoStorageKey};
+
+use crate::error::{AmmError, AmmResult, OrPanic};
+use crate::fixed_point::{complement, div_down, div_up, mul_down, mul_up, pow_down, pow_up, ONE};
+
+pub const MIN_TOKENS: usize = 2;
+pub const MAX_TOKENS: usize = 8;
+/// The smallest normalized weight a token can have, 1%.
+pub const MIN_WEIGHT: u128 = ONE / 100;
+/// Swap fees are set in basis points.
+pub const FEE_DIVISOR: u32 = 10_000;
+pub const MAX_SWAP_FEE: u32 = 1_000;
+/// A single swap, join or exit can't move more than 30% of a token's balance.
+/// It keeps `pow` arguments close to 1 where the approximation is precise.
+pub const MAX_IN_RATIO: u128 = ONE * 3 / 10;
+pub const MAX_OUT_RATIO: u128 = ONE * 3 / 10;
+/// Shares minted to the owner once every token of the pool is seeded.
+pub const INIT_SHARES_SUPPLY: Balance = 100 * ONE;
+
+/// Balancer-style pool of 2 to 8 tokens, each with its own weight.
+/// The invariant is `prod(balance_i ^ weight_i)`, weights sum up to `ONE`.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct WeightedPool {
+    pub token_ids: Vec<AccountId>,
+    pub weights: Vec<u128>,
+    pub balances: Vec<Balance>,
+    pub swap_fee: u32,
+    pub shares: LookupMap<AccountId, Balance>,
+    pub shares_total_supply: Balance,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WeightedPoolView {
+    pub token_ids: Vec<AccountId>,
+    pub weights: Vec<U128>,
+    pub balances: Vec<U128>,
+    pub swap_fee: u32,
+    pub shares_total_supply: U128,
+}
+
+impl WeightedPool {
+    /// Creates an empty pool. `weights` are relative, e.g. `[80, 20]`, and get normalized.
+    pub fn new<S: IntoStorageKey>(
+        prefix: S,
+        token_ids: Vec<AccountId>,
+        weights: Vec<u32>,
+        swap_fee: u32,
+    ) -> Self {
+        assert!(
+            (MIN_TOKENS..=MAX_TOKENS).contains(&token_ids.len()),
+            "A weighted pool must have from {} to {} tokens",
+            MIN_TOKENS,
+            MAX_TOKENS
+        );
+        assert_eq!(
+            token_ids.len(),
+            weights.len(),
+            "Every token must have a weight"
+        );
+        for (i, token_id) in token_ids.iter().enumerate() {
+            assert!(
+                !token_ids[..i].contains(token_id),
+                "The token {} is listed twice",
+                token_id
+            );
+        }
+        assert!(swap_fee <= MAX_SWAP_FEE, "The swap fee is too high");
+
+        let total: u128 = weights.iter().map(|w| *w as u128).sum();
+        assert!(total > 0, "Weights can't be zero");
+        let mut normalized: Vec<u128> = weights.iter().map(|w| *w as u128 * ONE / total).collect();
+        // Give the rounding dust to the last token, so weights add up to exactly ONE.
+        let dust = ONE - normalized.iter().sum::<u128>();
+        *normalized.last_mut().unwrap() += dust;
+        assert!(
+            normalized.iter().all(|w| *w >= MIN_WEIGHT),
+            "Every weight must be at least 1% of the total"
+        );
+
+        Self {
+            balances: vec![0; token_ids.len()],
+            token_ids,
+            weights: normalized,
+            swap_fee,
+            shares: LookupMap::new(prefix),
+            shares_total_supply: 0,
+        }
+    }
+
+    pub fn view(&self) -> WeightedPoolView {
+        WeightedPoolView {
+            token_ids: self.token_ids.clone(),
+            weights: self.weights.iter().map(|w| U128(*w)).collect(),
+            balances: self.balances.iter().map(|b| U128(*b)).collect(),
+            swap_fee: self.swap_fee,
+            shares_total_supply: U128(self.shares_total_supply),
+        }
+    }
+
+    pub fn token_index(&self, token_id: &AccountId) -> usize {
+        self.index(token_id).or_panic()
+    }
+
+    fn index(&self, token_id: &AccountId) -> AmmResult<usize> {
+        self.token_ids
+            .iter()
+            .position(|t| t == token_id)
+            .ok_or_else(|| AmmError::UnknownToken(token_id.clone()))
+    }
+
+    /// The indexes of a pair of different tokens of a seeded pool.
+    fn pair(&self, token_in: &AccountId, token_out: &AccountId) -> AmmResult<(usize, usize)> {
+        if !self.is_seeded() {
+            return Err(AmmError::InsufficientLiquidity);
+        }
+        let idx_in = self.index(token_in)?;
+        let idx_out = self.index(token_out)?;
+        if idx_in == idx_out {
+            return Err(AmmError::BadMsg(
+                "can't swap a token for itself".to_string(),
+            ));
+        }
+        Ok((idx_in, idx_out))
+    }
+
+    /// The pool is seeded once each of its tokens has a balance and the initial shares are minted.
+    pub fn is_seeded(&self) -> bool {
+        self.shares_total_supply > 0
+    }
+
+    pub fn share_balance(&self, account_id: &AccountId) -> Balance {
+        self.shares.get(account_id).unwrap_or(0)
+    }
+
+    /// Credits the owner's deposit while the pool is being seeded. When the last token gets
+    /// a balance, the initial shares are minted to the owner.
+    pub fn seed(
+        &mut self,
+        owner_id: &AccountId,
+        token_id: &AccountId,
+        amount: Balance,
+    ) -> AmmResult<()> {
+        if self.is_seeded() {
+            return Err(AmmError::BadMsg("the pool is already seeded".to_string()));
+        }
+        let idx = self.index(token_id)?;
+        self.balances[idx] = self.balances[idx]
+            .checked_add(amount)
+            .ok_or(AmmError::MathOverflow)?;
+        if self.balances.iter().all(|b| *b > 0) {
+            self.mint_shares(owner_id, INIT_SHARES_SUPPLY);
+        }
+        Ok(())
+    }
+
+    pub fn get_return(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+    ) -> AmmResult<Balance> {
+        let (idx_in, idx_out) = self.pair(token_in, token_out)?;
+        calc_out_given_in(
+            self.balances[idx_in],
+            self.weights[idx_in],
+            self.balances[idx_out],
+            self.weights[idx_out],
+            amount_in,
+            self.swap_fee,
+        )
+    }
+
+    pub fn swap(
+        &mut self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> AmmResult<Balance> {
+        let amount_out = self.get_return(token_in, amount_in, token_out)?;
+        if amount_out < min_amount_out {
+            return Err(AmmError::below_minimum(amount_out, min_amount_out));
+        }
+        let (idx_in, idx_out) = self.pair(token_in, token_out)?;
+        self.balances[idx_in] += amount_in;
+        self.balances[idx_out] -= amount_out;
+        Ok(amount_out)
+    }
+
+    /// The input needed to get exactly `amount_out`.
+    pub fn get_amount_in(
+        &self,
+        token_in: &AccountId,
+        amount_out: Balance,
+        token_out: &AccountId,
+    ) -> AmmResult<Balance> {
+        let (idx_in, idx_out) = self.pair(token_in, token_out)?;
+        calc_in_given_out(
+            self.balances[idx_in],
+            self.weights[idx_in],
+            self.balances[idx_out],
+            self.weights[idx_out],
+            amount_out,
+            self.swap_fee,
+        )
+    }
+
+    /// Swaps for exactly `amount_out` and returns the input used, at most `max_amount_in`.
+    pub fn swap_exact_out(
+        &mut self,
+        token_in: &AccountId,
+        max_amount_in: Balance,
+        amount_out: Balance,
+        token_out: &AccountId,
+    ) -> AmmResult<Balance> {
+        let amount_in = self.get_amount_in(token_in, amount_out, token_out)?;
+        if amount_in > max_amount_in {
+            return Err(AmmError::above_maximum(amount_in, max_amount_in));
+        }
+        let (idx_in, idx_out) = self.pair(token_in, token_out)?;
+        self.balances[idx_in] += amount_in;
+        self.balances[idx_out] -= amount_out;
+        Ok(amount_in)
+    }
+
+    /// Spot price of `token_out` in `token_in`, scaled by `ONE`, without the swap fee.
+    pub fn spot_price(&self, token_in: &AccountId, token_out: &AccountId) -> u128 {
+        assert!(self.is_seeded(), "The pool is not seeded yet");
+        let idx_in = self.token_index(token_in);
+        let idx_out = self.token_index(token_out);
+        div_up(
+            div_up(self.balances[idx_in], self.weights[idx_in]),
+            div_down(self.balances[idx_out], self.weights[idx_out]),
+        )
+    }
+
+    pub fn calc_join_shares(&self, token_in: &AccountId, amount_in: Balance) -> AmmResult<Balance> {
+        if !self.is_seeded() {
+            return Err(AmmError::InsufficientLiquidity);
+        }
+        let idx = self.index(token_in)?;
+        calc_shares_out_given_token_in(
+            self.balances[idx],
+            self.weights[idx],
+            amount_in,
+            self.shares_total_supply,
+            self.swap_fee,
+        )
+    }
+
+    /// Adds liquidity with a single token and mints shares for it.
+    pub fn join(
+        &mut self,
+        account_id: &AccountId,
+        token_in: &AccountId,
+        amount_in: Balance,
+        min_shares: Balance,
+    ) -> AmmResult<Balance> {
+        let shares = self.calc_join_shares(token_in, amount_in)?;
+        if shares == 0 {
+            return Err(AmmError::Slippage(
+                "the deposit is too small to mint any shares".to_string(),
+            ));
+        }
+        if shares < min_shares {
+            return Err(AmmError::shares_below_minimum(shares, min_shares));
+        }
+        let idx = self.index(token_in)?;
+        self.balances[idx] += amount_in;
+        self.mint_shares(account_id, shares);
+        Ok(shares)
+    }
+
+    pub fn calc_exit_amount(&self, shares: Balance, token_out: &AccountId) -> Balance {
+        assert!(self.is_seeded(), "The pool is not seeded yet");
+        let idx = self.token_index(token_out);
+        calc_token_out_given_shares_in(
+            self.balances[idx],
+            self.weights[idx],
+            shares,
+            self.shares_total_supply,
+            self.swap_fee,
+        )
+    }
+
+    /// Burns `shares` and withdraws their value in `token_out` only.
+    pub fn exit(
+        &mut self,
+        account_id: &AccountId,
+        shares: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) -> Balance {
+        let balance = self.share_balance(account_id);
+        assert!(shares <= balance, "Not enough shares");
+        let amount_out = self.calc_exit_amount(shares, token_out);
+        if amount_out < min_amount_out {
+            panic!("{}", AmmError::below_minimum(amount_out, min_amount_out));
+        }
+        let idx = self.token_index(token_out);
+        self.balances[idx] -= amount_out;
+        self.shares.insert(account_id, &(balance - shares));
+        self.shares_total_supply -= shares;
+        amount_out
+    }
+
+    fn mint_shares(&mut self, account_id: &AccountId, shares: Balance) {
+        let balance = self.share_balance(account_id);
+        self.shares.insert(account_id, &(balance + shares));
+        self.shares_total_supply += shares;
+    }
+}
+
+fn fee_complement(swap_fee: u32) -> u128 {
+    ONE - ONE * swap_fee as u128 / FEE_DIVISOR as u128
+}
+
+/// `amount_out = balance_out * (1 - (balance_in / (balance_in + amount_in * (1 - fee))) ^ (weight_in / weight_out))`
+pub fn calc_out_given_in(
+    balance_in: Balance,
+    weight_in: u128,
+    balance_out: Balance,
+    weight_out: u128,
+    amount_in: Balance,
+    swap_fee: u32,
+) -> AmmResult<Balance> {
+    if amount_in > mul_down(balance_in, MAX_IN_RATIO) {
+        return Err(AmmError::InsufficientLiquidity);
+    }
+    let amount_in = mul_down(amount_in, fee_complement(swap_fee));
+    // Rounding the base and the power up makes the complement, and the output, smaller.
+    let base = div_up(balance_in, balance_in + amount_in);
+    let exponent = div_down(weight_in, weight_out);
+    let power = pow_up(base, exponent);
+    Ok(mul_down(balance_out, complement(power)))
+}
+
+/// `amount_in = balance_in * ((balance_out / (balance_out - amount_out)) ^ (weight_out / weight_in) - 1) / (1 - fee)`
+pub fn calc_in_given_out(
+    balance_in: Balance,
+    weight_in: u128,
+    balance_out: Balance,
+    weight_out: u128,
+    amount_out: Balance,
+    swap_fee: u32,
+) -> AmmResult<Balance> {
+    if amount_out > mul_down(balance_out, MAX_OUT_RATIO) {
+        return Err(AmmError::InsufficientLiquidity);
+    }
+    // Everything is rounded up, so the input is never too small.
+    let base = div_up(balance_out, balance_out - amount_out);
+    let exponent = div_up(weight_out, weight_in);
+    let power = pow_up(base, exponent);
+    let amount_in = mul_up(balance_in, power - ONE);
+    Ok(div_up(amount_in, fee_complement(swap_fee)))
+}
+
+/// `shares = supply * ((1 + amount_in' / balance) ^ weight - 1)`, where `amount_in'` is the
+/// deposit with the swap fee charged on the part that implicitly gets swapped to other tokens.
+pub fn calc_shares_out_given_token_in(
+    balance: Balance,
+    weight: u128,
+    amount_in: Balance,
+    total_supply: Balance,
+    swap_fee: u32,
+) -> AmmResult<Balance> {
+    if amount_in > mul_down(balance, MAX_IN_RATIO) {
+        return Err(AmmError::InsufficientLiquidity);
+    }
+    let taxable = mul_up(amount_in, complement(weight));
+    let amount_in_after_fee = amount_in - taxable + mul_down(taxable, fee_complement(swap_fee));
+    let balance_ratio = div_down(balance + amount_in_after_fee, balance);
+    let invariant_ratio = pow_down(balance_ratio, weight);
+    Ok(mul_down(total_supply, invariant_ratio.saturating_sub(ONE)))
+}
+
+/// `amount_out = balance * (1 - (1 - shares / supply) ^ (1 / weight))`, minus the swap fee on the
+/// part that implicitly gets swapped from other tokens.
+pub fn calc_token_out_given_shares_in(
+    balance: Balance,
+    weight: u128,
+    shares_in: Balance,
+    total_supply: Balance,
+    swap_fee: u32,
+) -> Balance {
+    assert!(shares_in < total_supply, "Can't burn all the pool shares");
+    let invariant_ratio = div_up(total_supply - shares_in, total_supply);
+    let balance_ratio = pow_up(invariant_ratio, div_down(ONE, weight));
+    let amount_out_without_fee = mul_down(balance, complement(balance_ratio));
+    let taxable = mul_up(amount_out_without_fee, complement(weight));
+    let amount_out = amount_out_without_fee - taxable + mul_down(taxable, fee_complement(swap_fee));
+    assert!(
+        amount_out <= mul_down(balance, MAX_OUT_RATIO),
+        "The amount exceeds 30% of the pool balance"
+    );
+    amount_out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    fn new_pool(weights: Vec<u32>, swap_fee: u32) -> WeightedPool {
+        testing_env!(VMContextBuilder::new().build());
+        let token_ids = (0..weights.len()).map(token).collect();
+        WeightedPool::new(b"w".to_vec(), token_ids, weights, swap_fee)
+    }
+
+    fn token(i: usize) -> AccountId {
+        format!("token{}.near", i).parse().unwrap()
+    }
+
+    fn seeded(weights: Vec<u32>, balances: Vec<Balance>, swap_fee: u32) -> WeightedPool {
+        let mut pool = new_pool(weights, swap_fee);
+        for (i, balance) in balances.into_iter().enumerate() {
+            pool.seed(&accounts(0), &token(i), balance).unwrap();
+        }
+        pool
+    }
+
+    #[test]
+    fn test_normalize_weights() {
+        let pool = new_pool(vec![80, 20], 0);
+        assert_eq!(pool.weights, vec![ONE * 8 / 10, ONE * 2 / 10]);
+        let pool = new_pool(vec![1, 1, 1], 0);
+        assert_eq!(pool.weights.iter().sum::<u128>(), ONE);
+    }
+
+    #[test]
+    #[should_panic(expected = "Every weight must be at least 1% of the total")]
+    fn test_weight_too_small() {
+        new_pool(vec![1000, 1], 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "A weighted pool must have from 2 to 8 tokens")]
+    fn test_too_many_tokens() {
+        new_pool(vec![1; 9], 0);
+    }
+
+    #[test]
+    fn test_seed_mints_initial_shares() {
+        let mut pool = new_pool(vec![50, 50], 0);
+        pool.seed(&accounts(0), &token(0), 1000).unwrap();
+        assert!(!pool.is_seeded());
+        pool.seed(&accounts(0), &token(1), 1000).unwrap();
+        assert!(pool.is_seeded());
+        assert_eq!(pool.share_balance(&accounts(0)), INIT_SHARES_SUPPLY);
+    }
+
+    #[test]
+    fn test_equal_weights_match_constant_product() {
+        let pool = seeded(vec![50, 50], vec![20_000, 5_000], 0);
+        // the same numbers as the constant-product swap in the integration tests
+        let out = pool.get_return(&token(1), 800, &token(0)).unwrap();
+        assert!((2_757..=2_758).contains(&out), "{}", out);
+    }
+
+    #[test]
+    fn test_weighted_swap() {
+        let mut pool = seeded(vec![80, 20], vec![10u128.pow(30), 10u128.pow(27)], 30);
+        let amount_in = 10u128.pow(28);
+        // 80/20 pool: out = b_out * (1 - (b_in / (b_in + a_in * 0.997)) ^ 4)
+        let expected = 1e27 * (1.0 - (1e30f64 / (1e30 + 1e28 * 0.997)).powi(4));
+        let out = pool.swap(&token(0), amount_in, &token(1), 0).unwrap();
+        assert!(out as f64 <= expected);
+        assert!((expected - out as f64) / expected < 1e-9);
+        assert_eq!(
+            pool.balances,
+            vec![10u128.pow(30) + amount_in, 10u128.pow(27) - out]
+        );
+    }
+
+    #[test]
+    fn test_swap_slippage() {
+        let mut pool = seeded(vec![50, 50], vec![20_000, 5_000], 0);
+        let err = pool.swap(&token(1), 800, &token(0), 2_800).unwrap_err();
+        assert_eq!(err.code(), "E_SLIPPAGE");
+        assert_eq!(pool.balances, vec![20_000, 5_000]);
+    }
+
+    #[test]
+    fn test_swap_too_large() {
+        let pool = seeded(vec![50, 50], vec![20_000, 5_000], 0);
+        assert_eq!(
+            pool.get_return(&token(1), 2_000, &token(0)),
+            Err(AmmError::InsufficientLiquidity)
+        );
+    }
+
+    #[test]
+    fn test_swap_errors() {
+        let mut pool = new_pool(vec![50, 50], 0);
+        pool.seed(&accounts(0), &token(0), 1000).unwrap();
+        assert_eq!(
+            pool.swap(&token(0), 10, &token(1), 0),
+            Err(AmmError::InsufficientLiquidity)
+        );
+        pool.seed(&accounts(0), &token(1), 1000).unwrap();
+        assert_eq!(
+            pool.swap(&token(2), 10, &token(1), 0),
+            Err(AmmError::UnknownToken(token(2)))
+        );
+        assert_eq!(
+            pool.swap(&token(1), 10, &token(1), 0).unwrap_err().code(),
+            "E_BAD_MSG"
+        );
+        assert_eq!(
+            pool.seed(&accounts(0), &token(0), 1000).unwrap_err().code(),
+            "E_BAD_MSG"
+        );
+    }
+
+    #[test]
+    fn test_join_and_exit_round_trip() {
+        let mut pool = seeded(vec![80, 20], vec![10u128.pow(24), 10u128.pow(24)], 30);
+        let shares = pool
+            .join(&accounts(3), &token(1), 10u128.pow(23), 0)
+            .unwrap();
+        assert!(shares > 0);
+        assert_eq!(pool.share_balance(&accounts(3)), shares);
+        let out = pool.exit(&accounts(3), shares, &token(1), 0);
+        // the fee is charged both ways, so the LP gets back less than deposited
+        assert!(out < 10u128.pow(23));
+        assert!(out > 10u128.pow(23) * 99 / 100);
+        assert_eq!(pool.share_balance(&accounts(3)), 0);
+        assert_eq!(pool.shares_total_supply, INIT_SHARES_SUPPLY);
+    }
+
+    #[test]
+    fn test_spot_price() {
+        let pool = seeded(vec![80, 20], vec![4_000 * ONE, 1_000 * ONE], 0);
+        // (4000 / 0.8) / (1000 / 0.2) = 1
+        assert_eq!(pool.spot_price(&token(0), &token(1)), ONE);
+    }
+}
@@ -15,22 +15,52 @@ NOTES:
   - To prevent the deployed contract from being modified or deleted, it should not have any access
     keys on its account.
 */
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
+use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
 use near_contract_standards::fungible_token::metadata::{
     FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
 };
+use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
 use near_contract_standards::fungible_token::FungibleToken;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LazyOption;
-use near_sdk::json_types::U128;
-use near_sdk::{env, log, near_bindgen, AccountId, Balance, PanicOnDefault, PromiseOrValue};
+use near_sdk::collections::{LazyOption, UnorderedSet};
+use near_sdk::json_types::{Base64VecU8, U128};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, log, near_bindgen, AccountId, Balance, PanicOnDefault, PromiseOrValue,
+};
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
     token: FungibleToken,
     metadata: LazyOption<FungibleTokenMetadata>,
+    /// Manages the minters and the metadata.
+    owner_id: AccountId,
+    /// Accounts that can mint and burn tokens.
+    minters: UnorderedSet<AccountId>,
+    /// No tokens move while the token is paused.
+    paused: bool,
+    /// Accounts that can't send or receive tokens.
+    blacklist: UnorderedSet<AccountId>,
+    modes: TokenModes,
+}
+
+/// Behaviours of real-world tokens, off by default, to test how integrations handle them.
+#[derive(
+    BorshDeserialize, BorshSerialize, Deserialize, Serialize, Clone, Default, Debug, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenModes {
+    /// Tax in basis points taken from the receiver of every transfer and paid to the owner, so
+    /// the receiver ends up with less than the transferred amount.
+    pub transfer_fee: u16,
+    /// The owner can change any balance with `rebase`.
+    pub rebasing: bool,
 }
 
+const MAX_BASIS_POINTS: u16 = 10_000;
+
 const DATA_IMAGE_SVG_NEAR_ICON: &str = "data:image/svg+xml,%3Csvg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 288 288'%3E%3Cg id='l' data-name='l'%3E%3Cpath d='M187.58,79.81l-30.1,44.69a3.2,3.2,0,0,0,4.75,4.2L191.86,103a1.2,1.2,0,0,1,2,.91v80.46a1.2,1.2,0,0,1-2.12.77L102.18,77.93A15.35,15.35,0,0,0,90.47,72.5H87.34A15.34,15.34,0,0,0,72,87.84V201.16A15.34,15.34,0,0,0,87.34,216.5h0a15.35,15.35,0,0,0,13.08-7.31l30.1-44.69a3.2,3.2,0,0,0-4.75-4.2L96.14,186a1.2,1.2,0,0,1-2-.91V104.61a1.2,1.2,0,0,1,2.12-.77l89.55,107.23a15.35,15.35,0,0,0,11.71,5.43h3.13A15.34,15.34,0,0,0,216,201.16V87.84A15.34,15.34,0,0,0,200.66,72.5h0A15.35,15.35,0,0,0,187.58,79.81Z'/%3E%3C/g%3E%3C/svg%3E";
 
 #[near_bindgen]
@@ -58,15 +88,36 @@ impl Contract {
     /// the given fungible token metadata.
     #[init]
     pub fn new(owner_id: AccountId, total_supply: U128, metadata: FungibleTokenMetadata) -> Self {
+        Self::new_with_modes(owner_id, total_supply, metadata, TokenModes::default())
+    }
+
+    /// Like `new`, with a transfer fee or rebasing turned on.
+    #[init]
+    pub fn new_with_modes(
+        owner_id: AccountId,
+        total_supply: U128,
+        metadata: FungibleTokenMetadata,
+        modes: TokenModes,
+    ) -> Self {
         assert!(!env::state_exists(), "Already initialized");
         metadata.assert_valid();
+        assert!(
+            modes.transfer_fee <= MAX_BASIS_POINTS,
+            "The transfer fee can't be more than {} basis points",
+            MAX_BASIS_POINTS
+        );
         let mut this = Self {
             token: FungibleToken::new(b"a".to_vec()),
             metadata: LazyOption::new(b"m".to_vec(), Some(&metadata)),
+            owner_id: owner_id.clone(),
+            minters: UnorderedSet::new(b"n".to_vec()),
+            paused: false,
+            blacklist: UnorderedSet::new(b"b".to_vec()),
+            modes,
         };
         this.token.internal_register_account(&owner_id);
         this.token.internal_deposit(&owner_id, total_supply.into());
-        near_contract_standards::fungible_token::events::FtMint {
+        FtMint {
             owner_id: &owner_id,
             amount: &total_supply,
             memo: Some("Initial tokens supply is minted"),
@@ -75,6 +126,192 @@ impl Contract {
         this
     }
 
+    pub fn add_minter(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.minters.insert(&account_id);
+    }
+
+    pub fn remove_minter(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.minters.remove(&account_id);
+    }
+
+    pub fn get_minters(&self) -> Vec<AccountId> {
+        self.minters.to_vec()
+    }
+
+    /// Mints `amount` new tokens to `account_id`, which must be registered. Only minters can
+    /// call it, with 1 yoctoNEAR attached.
+    #[payable]
+    pub fn mint(&mut self, account_id: AccountId, amount: U128) {
+        assert_one_yocto();
+        self.assert_minter();
+        self.assert_transfer_allowed(&[&account_id]);
+        self.token.internal_deposit(&account_id, amount.into());
+        FtMint {
+            owner_id: &account_id,
+            amount: &amount,
+            memo: None,
+        }
+        .emit();
+    }
+
+    /// Burns `amount` of the minter's own tokens, with 1 yoctoNEAR attached.
+    #[payable]
+    pub fn burn(&mut self, amount: U128) {
+        assert_one_yocto();
+        self.assert_minter();
+        let account_id = env::predecessor_account_id();
+        self.assert_transfer_allowed(&[&account_id]);
+        self.token.internal_withdraw(&account_id, amount.into());
+        FtBurn {
+            owner_id: &account_id,
+            amount: &amount,
+            memo: None,
+        }
+        .emit();
+    }
+
+    /// Stops all transfers, mints and burns until `unpause`.
+    pub fn pause(&mut self) {
+        self.assert_owner();
+        self.paused = true;
+        log!("The token is paused");
+    }
+
+    pub fn unpause(&mut self) {
+        self.assert_owner();
+        self.paused = false;
+        log!("The token is unpaused");
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Freezes the account: it can't send or receive tokens.
+    pub fn add_to_blacklist(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.blacklist.insert(&account_id);
+        log!("@{} is blacklisted", account_id);
+    }
+
+    pub fn remove_from_blacklist(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.blacklist.remove(&account_id);
+        log!("@{} is removed from the blacklist", account_id);
+    }
+
+    pub fn is_blacklisted(&self, account_id: AccountId) -> bool {
+        self.blacklist.contains(&account_id)
+    }
+
+    pub fn get_modes(&self) -> TokenModes {
+        self.modes.clone()
+    }
+
+    /// Sets the balance of `account_id` to `amount`, minting or burning the difference. Only
+    /// the owner of a rebasing token can call it.
+    pub fn rebase(&mut self, account_id: AccountId, amount: U128) {
+        self.assert_owner();
+        assert!(self.modes.rebasing, "The token isn't rebasing");
+        let balance = self.token.ft_balance_of(account_id.clone()).0;
+        let amount = amount.0;
+        if amount > balance {
+            self.token.internal_deposit(&account_id, amount - balance);
+            FtMint {
+                owner_id: &account_id,
+                amount: &U128(amount - balance),
+                memo: Some("rebase"),
+            }
+            .emit();
+        } else if amount < balance {
+            self.token.internal_withdraw(&account_id, balance - amount);
+            FtBurn {
+                owner_id: &account_id,
+                amount: &U128(balance - amount),
+                memo: Some("rebase"),
+            }
+            .emit();
+        }
+    }
+
+    pub fn set_metadata(&mut self, metadata: FungibleTokenMetadata) {
+        self.assert_owner();
+        self.update_metadata(metadata);
+    }
+
+    pub fn set_icon(&mut self, icon: Option<String>) {
+        self.assert_owner();
+        let mut metadata = self.metadata.get().unwrap();
+        metadata.icon = icon;
+        self.update_metadata(metadata);
+    }
+
+    /// Sets the reference and its hash, both or none of them.
+    pub fn set_reference(
+        &mut self,
+        reference: Option<String>,
+        reference_hash: Option<Base64VecU8>,
+    ) {
+        self.assert_owner();
+        let mut metadata = self.metadata.get().unwrap();
+        metadata.reference = reference;
+        metadata.reference_hash = reference_hash;
+        self.update_metadata(metadata);
+    }
+
+    fn update_metadata(&mut self, metadata: FungibleTokenMetadata) {
+        metadata.assert_valid();
+        self.metadata.set(&metadata);
+        log!("Metadata of the token is updated");
+    }
+
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "Only the owner can call this method"
+        );
+    }
+
+    fn assert_transfer_allowed(&self, account_ids: &[&AccountId]) {
+        assert!(!self.paused, "The token is paused");
+        for account_id in account_ids {
+            assert!(
+                !self.blacklist.contains(account_id),
+                "@{} is blacklisted",
+                account_id
+            );
+        }
+    }
+
+    /// Takes the transfer fee of `amount` from the receiver, the owner receives it tax free.
+    fn charge_transfer_fee(&mut self, receiver_id: &AccountId, amount: U128) {
+        let (amount, fee_bps, max_bps) = (
+            amount.0,
+            self.modes.transfer_fee as Balance,
+            MAX_BASIS_POINTS as Balance,
+        );
+        // split so that `amount * fee_bps` can't overflow
+        let fee = amount / max_bps * fee_bps + amount % max_bps * fee_bps / max_bps;
+        if fee > 0 && receiver_id != &self.owner_id {
+            self.token.internal_transfer(
+                receiver_id,
+                &self.owner_id,
+                fee,
+                Some("transfer fee".to_string()),
+            );
+        }
+    }
+
+    fn assert_minter(&self) {
+        assert!(
+            self.minters.contains(&env::predecessor_account_id()),
+            "Only minters can call this method"
+        );
+    }
+
     fn on_account_closed(&mut self, account_id: AccountId, balance: Balance) {
         log!("Closed @{} with {}", account_id, balance);
     }
@@ -84,7 +321,63 @@ impl Contract {
     }
 }
 
-near_contract_standards::impl_fungible_token_core!(Contract, token, on_tokens_burned);
+/// The core methods of `impl_fungible_token_core!`, transfers are checked against the pause and
+/// the blacklist first and pay the transfer fee after.
+#[near_bindgen]
+impl FungibleTokenCore for Contract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        self.assert_transfer_allowed(&[&env::predecessor_account_id(), &receiver_id]);
+        self.token.ft_transfer(receiver_id.clone(), amount, memo);
+        self.charge_transfer_fee(&receiver_id, amount);
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        self.assert_transfer_allowed(&[&env::predecessor_account_id(), &receiver_id]);
+        let promise = self
+            .token
+            .ft_transfer_call(receiver_id.clone(), amount, memo, msg);
+        // `ft_on_transfer` is still told about the full amount
+        self.charge_transfer_fee(&receiver_id, amount);
+        promise
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.token.ft_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for Contract {
+    /// Refunds of `ft_transfer_call` aren't blocked, the tokens only go back to the sender.
+    #[private]
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        let (used_amount, burned_amount) =
+            self.token
+                .internal_ft_resolve_transfer(&sender_id, receiver_id, amount);
+        if burned_amount > 0 {
+            self.on_tokens_burned(sender_id, burned_amount);
+        }
+        used_amount.into()
+    }
+}
+
 near_contract_standards::impl_fungible_token_storage!(Contract, token, on_account_closed);
 
 #[near_bindgen]
@@ -96,8 +389,7 @@ impl FungibleTokenMetadataProvider for Contract {
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::MockedBlockchain;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
     use near_sdk::{testing_env, Balance};
 
     use super::*;
@@ -117,7 +409,7 @@ mod tests {
     fn test_new() {
         let mut context = get_context(accounts(1));
         testing_env!(context.build());
-        let contract = Contract::new_default_meta(accounts(1).into(), TOTAL_SUPPLY.into());
+        let contract = Contract::new_default_meta(accounts(1), TOTAL_SUPPLY.into());
         testing_env!(context.is_view(true).build());
         assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY);
         assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY);
@@ -135,7 +427,7 @@ mod tests {
     fn test_transfer() {
         let mut context = get_context(accounts(2));
         testing_env!(context.build());
-        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        let mut contract = Contract::new_default_meta(accounts(2), TOTAL_SUPPLY.into());
         testing_env!(context
             .storage_usage(env::storage_usage())
             .attached_deposit(contract.storage_balance_bounds().min.into())
@@ -164,4 +456,230 @@ mod tests {
         );
         assert_eq!(contract.ft_balance_of(accounts(1)).0, transfer_amount);
     }
+
+    fn register(context: &mut VMContextBuilder, contract: &mut Contract, account_id: AccountId) {
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(account_id)
+            .build());
+        contract.storage_deposit(None, None);
+    }
+
+    #[test]
+    fn test_mint_and_burn() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2), TOTAL_SUPPLY.into());
+        contract.add_minter(accounts(3));
+        assert_eq!(contract.get_minters(), vec![accounts(3)]);
+        register(&mut context, &mut contract, accounts(1));
+        register(&mut context, &mut contract, accounts(3));
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(3))
+            .build());
+        contract.mint(accounts(1), 1_000.into());
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_mint","data":[{"owner_id":"bob","amount":"1000"}]}"#
+            ]
+        );
+        contract.mint(accounts(3), 500.into());
+        contract.burn(200.into());
+        assert_eq!(
+            get_logs().last().unwrap(),
+            r#"EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_burn","data":[{"owner_id":"danny","amount":"200"}]}"#
+        );
+
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 1_000);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 300);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY + 1_300);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only minters can call this method")]
+    fn test_mint_not_minter() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2), TOTAL_SUPPLY.into());
+        testing_env!(context.attached_deposit(1).build());
+        contract.mint(accounts(2), 1_000.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_add_minter_not_owner() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2), TOTAL_SUPPLY.into());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.add_minter(accounts(3));
+    }
+
+    #[test]
+    fn test_set_metadata() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2), TOTAL_SUPPLY.into());
+        let mut metadata = contract.ft_metadata();
+        metadata.symbol = "RENAMED".to_string();
+        contract.set_metadata(metadata);
+        contract.set_icon(None);
+        contract.set_reference(
+            Some("https://example.com/token.json".to_string()),
+            Some(vec![0; 32].into()),
+        );
+
+        testing_env!(context.is_view(true).build());
+        let metadata = contract.ft_metadata();
+        assert_eq!(metadata.symbol, "RENAMED");
+        assert_eq!(metadata.icon, None);
+        assert_eq!(
+            metadata.reference.as_deref(),
+            Some("https://example.com/token.json")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_set_icon_not_owner() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2), TOTAL_SUPPLY.into());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.set_icon(None);
+    }
+
+    fn transfer_setup(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2), TOTAL_SUPPLY.into());
+        register(context, &mut contract, accounts(1));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = "The token is paused")]
+    fn test_transfer_while_paused() {
+        let mut context = get_context(accounts(2));
+        let mut contract = transfer_setup(&mut context);
+        contract.pause();
+        assert!(contract.is_paused());
+        contract.ft_transfer(accounts(1), 100.into(), None);
+    }
+
+    #[test]
+    fn test_transfer_after_unpause() {
+        let mut context = get_context(accounts(2));
+        let mut contract = transfer_setup(&mut context);
+        contract.pause();
+        contract.unpause();
+        contract.ft_transfer(accounts(1), 100.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "@bob is blacklisted")]
+    fn test_transfer_to_blacklisted_account() {
+        let mut context = get_context(accounts(2));
+        let mut contract = transfer_setup(&mut context);
+        contract.add_to_blacklist(accounts(1));
+        assert!(contract.is_blacklisted(accounts(1)));
+        contract.ft_transfer_call(accounts(1), 100.into(), None, String::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "@charlie is blacklisted")]
+    fn test_transfer_from_blacklisted_account() {
+        let mut context = get_context(accounts(2));
+        let mut contract = transfer_setup(&mut context);
+        contract.add_to_blacklist(accounts(2));
+        contract.ft_transfer(accounts(1), 100.into(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_pause_not_owner() {
+        let mut context = get_context(accounts(2));
+        let mut contract = transfer_setup(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.pause();
+    }
+
+    fn new_with_modes(context: &mut VMContextBuilder, modes: TokenModes) -> Contract {
+        testing_env!(context.build());
+        let metadata = FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "Taxed".to_string(),
+            symbol: "TAX".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 24,
+        };
+        let mut contract =
+            Contract::new_with_modes(accounts(2), TOTAL_SUPPLY.into(), metadata, modes);
+        register(context, &mut contract, accounts(1));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract
+    }
+
+    #[test]
+    fn test_transfer_fee() {
+        let mut context = get_context(accounts(2));
+        let mut contract = new_with_modes(
+            &mut context,
+            TokenModes {
+                transfer_fee: 100,
+                rebasing: false,
+            },
+        );
+        contract.ft_transfer(accounts(1), 10_000.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 9_900);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, TOTAL_SUPPLY - 9_900);
+
+        // the owner receives without the fee
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_transfer(accounts(2), 900.into(), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 9_000);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY);
+    }
+
+    #[test]
+    fn test_rebase() {
+        let mut context = get_context(accounts(2));
+        let mut contract = new_with_modes(
+            &mut context,
+            TokenModes {
+                transfer_fee: 0,
+                rebasing: true,
+            },
+        );
+        contract.ft_transfer(accounts(1), 1_000.into(), None);
+        contract.rebase(accounts(1), 1_500.into());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 1_500);
+        contract.rebase(accounts(1), 200.into());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 200);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY - 800);
+    }
+
+    #[test]
+    #[should_panic(expected = "The token isn't rebasing")]
+    fn test_rebase_disabled() {
+        let mut context = get_context(accounts(2));
+        let mut contract = transfer_setup(&mut context);
+        contract.rebase(accounts(2), 0.into());
+    }
 }
@@ -1,9 +1,9 @@
+use std::collections::HashMap;
+
 use near_sdk::json_types::U128;
-use near_units::{parse_gas, parse_near};
-use serde_json::json;
-use workspaces::result::CallExecutionDetails;
+use near_units::parse_near;
+use workspaces::prelude::*;
 use workspaces::{network::Sandbox, Account, Contract, Worker};
-use workspaces::{prelude::*, sandbox, DevNetwork};
 
 async fn init(
     worker: &Worker<Sandbox>,
@@ -281,7 +281,7 @@ async fn init_amm() -> anyhow::Result<()> {
 #[tokio::test]
 async fn deposit() -> anyhow::Result<()> {
     let worker = workspaces::sandbox().await?;
-    let (owner, a_contract, b_contract, alice, bob, amm_contract) = init(&worker).await?;
+    let (owner, a_contract, b_contract, _alice, bob, amm_contract) = init(&worker).await?;
 
     let res = owner
         .call(&worker, amm_contract.id(), "new")
@@ -295,6 +295,15 @@ async fn deposit() -> anyhow::Result<()> {
         .await?;
     assert!(res.is_success());
 
+    // the owner pays for the storage of its pending liquidity
+    let res = owner
+        .call(&worker, amm_contract.id(), "storage_deposit")
+        .args_json(serde_json::json!({}))?
+        .deposit(parse_near!("0.01 N"))
+        .transact()
+        .await?;
+    assert!(res.is_success());
+
     // deposit AMM account with 10_000 "A" coins. Later check it for consistency.
     let res = owner
         .call(&worker, a_contract.id(), "ft_transfer_call")
@@ -325,7 +334,7 @@ async fn deposit() -> anyhow::Result<()> {
         .args_json(serde_json::json!({
             "receiver_id": amm_contract.id(),
             "amount": U128(5000),
-            "msg": format!("{}:{}", a_contract.id(), b_contract.id())
+            "msg": format!("{}:{}", b_contract.id(), a_contract.id())
         }))?
         .gas(300_000_000_000_000)
         .deposit(1)
@@ -369,6 +378,14 @@ async fn swap() -> anyhow::Result<()> {
         .transact()
         .await?;
 
+    // the owner pays for the storage of its pending liquidity
+    owner
+        .call(&worker, amm_contract.id(), "storage_deposit")
+        .args_json(serde_json::json!({}))?
+        .deposit(parse_near!("0.01 N"))
+        .transact()
+        .await?;
+
     // deposit AMM account with 20_000 "A" coins. Later check it for consistency.
     owner
         .call(&worker, a_contract.id(), "ft_transfer_call")
@@ -396,6 +413,17 @@ async fn swap() -> anyhow::Result<()> {
         .transact()
         .await?;
 
+    // the owner's transfers are pending until they're added as liquidity
+    owner
+        .call(&worker, amm_contract.id(), "add_liquidity")
+        .args_json(serde_json::json!({
+            "amounts": [U128(20_000), U128(5_000)],
+            "min_shares": U128(0),
+        }))?
+        .gas(300_000_000_000_000)
+        .transact()
+        .await?;
+
     // swap 800 "B" tokens for 2758 "A" tokens
     alice
         .call(&worker, b_contract.id(), "ft_transfer_call")
@@ -443,3 +471,195 @@ async fn swap() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn direct_ft_on_transfer_is_refunded() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox().await?;
+    let (owner, a_contract, b_contract, alice, _bob, amm_contract) = init(&worker).await?;
+
+    owner
+        .call(&worker, amm_contract.id(), "new")
+        .args_json(serde_json::json!({
+            "owner_id": owner.id(),
+            "a_contract": a_contract.id(),
+            "b_contract": b_contract.id(),
+        }))?
+        .gas(300_000_000_000_000)
+        .transact()
+        .await?;
+
+    // the owner pays for the storage of its pending liquidity
+    owner
+        .call(&worker, amm_contract.id(), "storage_deposit")
+        .args_json(serde_json::json!({}))?
+        .deposit(parse_near!("0.01 N"))
+        .transact()
+        .await?;
+
+    for (sell, buy, amount) in [
+        (&a_contract, &b_contract, 20_000),
+        (&b_contract, &a_contract, 5_000),
+    ] {
+        owner
+            .call(&worker, sell.id(), "ft_transfer_call")
+            .args_json(serde_json::json!({
+                "receiver_id": amm_contract.id(),
+                "amount": U128(amount),
+                "msg": format!("{}:{}", sell.id(), buy.id()),
+            }))?
+            .gas(300_000_000_000_000)
+            .deposit(1)
+            .transact()
+            .await?;
+    }
+
+    let alice_balance: U128 = alice
+        .call(&worker, a_contract.id(), "ft_balance_of")
+        .args_json(serde_json::json!({
+            "account_id": alice.id(),
+        }))?
+        .view()
+        .await?
+        .json()?;
+
+    // Alice pretends to have sent 1_000_000 "B" tokens to drain "A" from the pool
+    for msg in [
+        format!("{}:{}", b_contract.id(), a_contract.id()),
+        a_contract.id().to_string(),
+    ] {
+        let res = alice
+            .call(&worker, amm_contract.id(), "ft_on_transfer")
+            .args_json(serde_json::json!({
+                "sender_id": alice.id(),
+                "amount": U128(1_000_000),
+                "msg": msg,
+            }))?
+            .gas(300_000_000_000_000)
+            .transact()
+            .await?;
+        // Alice isn't a token, the whole amount is returned as unused
+        assert_eq!(res.json::<U128>()?, U128(1_000_000));
+    }
+
+    let res: U128 = alice
+        .call(&worker, a_contract.id(), "ft_balance_of")
+        .args_json(serde_json::json!({
+            "account_id": alice.id(),
+        }))?
+        .view()
+        .await?
+        .json()?;
+    assert_eq!(res, alice_balance);
+
+    let res: U128 = owner
+        .call(&worker, a_contract.id(), "ft_balance_of")
+        .args_json(serde_json::json!({
+            "account_id": amm_contract.id(),
+        }))?
+        .view()
+        .await?
+        .json()?;
+    assert_eq!(res, U128(20_000));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn payout_to_blacklisted_account_is_credited_to_deposits() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox().await?;
+    let (owner, a_contract, b_contract, alice, _bob, amm_contract) = init(&worker).await?;
+
+    owner
+        .call(&worker, amm_contract.id(), "new")
+        .args_json(serde_json::json!({
+            "owner_id": owner.id(),
+            "a_contract": a_contract.id(),
+            "b_contract": b_contract.id(),
+        }))?
+        .gas(300_000_000_000_000)
+        .transact()
+        .await?;
+
+    // the owner pays for the storage of its pending liquidity
+    owner
+        .call(&worker, amm_contract.id(), "storage_deposit")
+        .args_json(serde_json::json!({}))?
+        .deposit(parse_near!("0.01 N"))
+        .transact()
+        .await?;
+
+    for (sell, buy, amount) in [
+        (&a_contract, &b_contract, 20_000),
+        (&b_contract, &a_contract, 5_000),
+    ] {
+        owner
+            .call(&worker, sell.id(), "ft_transfer_call")
+            .args_json(serde_json::json!({
+                "receiver_id": amm_contract.id(),
+                "amount": U128(amount),
+                "msg": format!("{}:{}", sell.id(), buy.id()),
+            }))?
+            .gas(300_000_000_000_000)
+            .deposit(1)
+            .transact()
+            .await?;
+    }
+
+    // the owner's transfers are pending until they're added as liquidity
+    owner
+        .call(&worker, amm_contract.id(), "add_liquidity")
+        .args_json(serde_json::json!({
+            "amounts": [U128(20_000), U128(5_000)],
+            "min_shares": U128(0),
+        }))?
+        .gas(300_000_000_000_000)
+        .transact()
+        .await?;
+
+    // token "A" freezes Alice, the AMM can't pay her
+    a_contract
+        .call(&worker, "add_to_blacklist")
+        .args_json(serde_json::json!({
+            "account_id": alice.id(),
+        }))?
+        .transact()
+        .await?;
+
+    alice
+        .call(&worker, b_contract.id(), "ft_transfer_call")
+        .args_json(serde_json::json!({
+            "receiver_id": amm_contract.id(),
+            "amount": U128(800),
+            "msg": serde_json::json!({
+                "Swap": {"pool_id": null, "token_out": a_contract.id()}
+            })
+            .to_string(),
+        }))?
+        .gas(300_000_000_000_000)
+        .deposit(1)
+        .transact()
+        .await?;
+
+    let res: U128 = alice
+        .call(&worker, a_contract.id(), "ft_balance_of")
+        .args_json(serde_json::json!({
+            "account_id": alice.id(),
+        }))?
+        .view()
+        .await?
+        .json()?;
+    assert_eq!(res, U128(500_000));
+
+    // the output is kept for her in the AMM
+    let res: HashMap<String, U128> = alice
+        .call(&worker, amm_contract.id(), "get_deposits")
+        .args_json(serde_json::json!({
+            "account_id": alice.id(),
+        }))?
+        .view()
+        .await?
+        .json()?;
+    assert_eq!(res[a_contract.id().as_str()], U128(2_758));
+
+    Ok(())
+}